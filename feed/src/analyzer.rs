@@ -0,0 +1,425 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::time::{Duration, Instant};
+
+// Lowest level either view draws.
+pub const FLOOR_DB: f32 = -100.0;
+pub const FFT_SIZES: [usize; 4] = [1024, 2048, 4096, 8192];
+// Time spans the waveform view can show, in seconds.
+pub const WAVEFORM_SPANS: [f32; 4] = [0.02, 0.1, 0.5, 2.0];
+// Without fresh audio for this long the analyzer treats the output as silent.
+const STALE: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AnalyzerView {
+    #[default]
+    Off,
+    Spectrum,
+    Waveform,
+}
+
+impl AnalyzerView {
+    pub const ALL: [AnalyzerView; 3] = [
+        AnalyzerView::Off,
+        AnalyzerView::Spectrum,
+        AnalyzerView::Waveform,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            AnalyzerView::Off => "No analyzer",
+            AnalyzerView::Spectrum => "Spectrum",
+            AnalyzerView::Waveform => "Waveform",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Window {
+    Rectangular,
+    #[default]
+    Hann,
+    BlackmanHarris,
+}
+
+impl Window {
+    pub const ALL: [Window; 3] = [Window::Rectangular, Window::Hann, Window::BlackmanHarris];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Window::Rectangular => "Rectangular",
+            Window::Hann => "Hann",
+            Window::BlackmanHarris => "Blackman-Harris",
+        }
+    }
+
+    fn coefficient(self, n: usize, size: usize) -> f32 {
+        let x = 2.0 * PI * n as f32 / size as f32;
+        match self {
+            Window::Rectangular => 1.0,
+            Window::Hann => 0.5 - 0.5 * x.cos(),
+            Window::BlackmanHarris => {
+                0.35875 - 0.48829 * x.cos() + 0.14128 * (2.0 * x).cos() - 0.01168 * (3.0 * x).cos()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Averaging {
+    Off,
+    #[default]
+    Fast,
+    Slow,
+}
+
+impl Averaging {
+    pub const ALL: [Averaging; 3] = [Averaging::Off, Averaging::Fast, Averaging::Slow];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Averaging::Off => "No averaging",
+            Averaging::Fast => "Fast averaging",
+            Averaging::Slow => "Slow averaging",
+        }
+    }
+
+    // Time constant of the exponential average over successive spectra.
+    fn seconds(self) -> f32 {
+        match self {
+            Averaging::Off => 0.0,
+            Averaging::Fast => 0.25,
+            Averaging::Slow => 1.5,
+        }
+    }
+}
+
+// In-place iterative radix-2 FFT; the length must be a power of two.
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * cos - im[b] * sin;
+                let t_im = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+// Spectrum of the left/right mix of the outgoing audio. Levels are scaled so
+// a full-scale sine reads 0 dB whatever the window.
+pub struct Spectrum {
+    pub window: Window,
+    pub size: usize,
+    pub averaging: Averaging,
+    history: VecDeque<f32>,
+    // Averaged power per bin, `size / 2 + 1` of them.
+    power: Vec<f32>,
+    fresh: bool,
+    last_update: Option<Instant>,
+    last_audio: Option<Instant>,
+}
+
+impl Default for Spectrum {
+    fn default() -> Self {
+        Self {
+            window: Window::default(),
+            size: 4096,
+            averaging: Averaging::default(),
+            history: VecDeque::new(),
+            power: Vec::new(),
+            fresh: false,
+            last_update: None,
+            last_audio: None,
+        }
+    }
+}
+
+impl Spectrum {
+    pub fn push(&mut self, samples: &[f32]) {
+        let largest = FFT_SIZES[FFT_SIZES.len() - 1];
+        self.history
+            .extend(samples.chunks_exact(2).map(|f| (f[0] + f[1]) * 0.5));
+        let excess = self.history.len().saturating_sub(largest);
+        self.history.drain(..excess);
+        self.fresh = true;
+    }
+
+    pub fn update(&mut self, now: Instant) {
+        let elapsed = self
+            .last_update
+            .map_or(0.0, |at| now.duration_since(at).as_secs_f32());
+        self.last_update = Some(now);
+        if std::mem::take(&mut self.fresh) {
+            self.last_audio = Some(now);
+        } else if self
+            .last_audio
+            .is_none_or(|at| now.duration_since(at) >= STALE)
+        {
+            self.history.clear();
+        }
+
+        let size = self.size;
+        let mut re = vec![0.0; size];
+        let mut im = vec![0.0; size];
+        // The newest `size` samples, zero-padded at the front if fewer.
+        let available = self.history.len().min(size);
+        let skip = self.history.len() - available;
+        for (slot, &sample) in re[size - available..]
+            .iter_mut()
+            .zip(self.history.iter().skip(skip))
+        {
+            *slot = sample;
+        }
+        let mut window_sum = 0.0;
+        for (n, sample) in re.iter_mut().enumerate() {
+            let w = self.window.coefficient(n, size);
+            *sample *= w;
+            window_sum += w;
+        }
+        fft(&mut re, &mut im);
+
+        let bins = size / 2 + 1;
+        let scale = 2.0 / window_sum;
+        let power = (0..bins).map(|k| (re[k] * re[k] + im[k] * im[k]) * scale * scale);
+        let tau = self.averaging.seconds();
+        if self.power.len() != bins || tau == 0.0 {
+            self.power = power.collect();
+        } else {
+            let keep = (-elapsed / tau).exp();
+            for (average, new) in self.power.iter_mut().zip(power) {
+                *average = *average * keep + new * (1.0 - keep);
+            }
+        }
+    }
+
+    // Highest level in dB over `from..to` Hz, or the nearest bin's when the
+    // range falls between two.
+    pub fn level_db(&self, from: f32, to: f32, sample_rate: u32) -> f32 {
+        if self.power.is_empty() {
+            return FLOOR_DB;
+        }
+        let hz_per_bin = sample_rate as f32 / self.size as f32;
+        let last = self.power.len() - 1;
+        let first = ((from / hz_per_bin).ceil() as usize).min(last);
+        let end = ((to / hz_per_bin).floor() as usize).min(last);
+        let power = if first <= end {
+            self.power[first..=end].iter().fold(0f32, |m, &p| m.max(p))
+        } else {
+            let centre = (from + to) * 0.5 / hz_per_bin;
+            self.power[(centre.round() as usize).min(last)]
+        };
+        if power > 0.0 {
+            (10.0 * power.log10()).max(FLOOR_DB)
+        } else {
+            FLOOR_DB
+        }
+    }
+}
+
+// The most recent stretch of outgoing audio, for a scrolling view.
+pub struct Waveform {
+    pub span: f32,
+    frames: VecDeque<[f32; 2]>,
+}
+
+impl Default for Waveform {
+    fn default() -> Self {
+        Self {
+            span: 0.1,
+            frames: VecDeque::new(),
+        }
+    }
+}
+
+impl Waveform {
+    pub fn push(&mut self, samples: &[f32], sample_rate: u32) {
+        let longest = WAVEFORM_SPANS[WAVEFORM_SPANS.len() - 1];
+        let capacity = (longest * sample_rate as f32) as usize;
+        self.frames
+            .extend(samples.chunks_exact(2).map(|f| [f[0], f[1]]));
+        let excess = self.frames.len().saturating_sub(capacity);
+        self.frames.drain(..excess);
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    // Lowest and highest sample per channel in each of `count` columns
+    // covering the span, oldest first. Before a whole span has arrived the
+    // missing start reads as silence.
+    pub fn columns(&self, sample_rate: u32, count: usize) -> Vec<[(f32, f32); 2]> {
+        let span = ((self.span * sample_rate as f32) as usize).max(1);
+        let missing = span.saturating_sub(self.frames.len());
+        let start = self.frames.len().saturating_sub(span);
+        let frame = |i: usize| {
+            if i < missing {
+                [0.0; 2]
+            } else {
+                self.frames[start + i - missing]
+            }
+        };
+        (0..count)
+            .map(|column| {
+                let from = column * span / count;
+                let to = ((column + 1) * span / count).max(from + 1);
+                let mut range = [(f32::MAX, f32::MIN); 2];
+                for i in from..to {
+                    for (channel, sample) in frame(i).into_iter().enumerate() {
+                        range[channel].0 = range[channel].0.min(sample);
+                        range[channel].1 = range[channel].1.max(sample);
+                    }
+                }
+                range
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 46875;
+
+    fn sine(frequency: f32, amplitude: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|n| {
+                let s = amplitude * (2.0 * PI * frequency * n as f32 / RATE as f32).sin();
+                [s, s]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fft_matches_a_direct_dft() {
+        let input: Vec<f32> = (0..64)
+            .map(|n| ((n * 37 % 11) as f32 - 5.0) / 5.0)
+            .collect();
+        let mut re = input.clone();
+        let mut im = vec![0.0; 64];
+        fft(&mut re, &mut im);
+        for k in 0..64 {
+            let (mut dre, mut dim) = (0.0, 0.0);
+            for (n, x) in input.iter().enumerate() {
+                let angle = -2.0 * PI * (k * n) as f32 / 64.0;
+                dre += x * angle.cos();
+                dim += x * angle.sin();
+            }
+            assert!(
+                (re[k] - dre).abs() < 1e-3 && (im[k] - dim).abs() < 1e-3,
+                "bin {}",
+                k
+            );
+        }
+    }
+
+    #[test]
+    fn full_scale_sine_reads_zero_db_in_every_window() {
+        let now = Instant::now();
+        // On a bin, and halfway between two for the tapered windows.
+        let bin = RATE as f32 / 4096.0;
+        for window in Window::ALL {
+            let mut spectrum = Spectrum {
+                window,
+                averaging: Averaging::Off,
+                ..Default::default()
+            };
+            spectrum.push(&sine(100.0 * bin, 1.0, 8192));
+            spectrum.update(now);
+            let level = spectrum.level_db(99.5 * bin, 100.5 * bin, RATE);
+            assert!(level.abs() < 0.05, "{:?}: {}", window, level);
+            if window != Window::Rectangular {
+                spectrum.push(&sine(100.5 * bin, 1.0, 8192));
+                spectrum.update(now);
+                let level = spectrum.level_db(100.0 * bin, 101.0 * bin, RATE);
+                assert!(level < 0.0 && level > -1.6, "{:?}: {}", window, level);
+            }
+            // Far from the tone the window keeps leakage down.
+            let leakage = spectrum.level_db(300.0 * bin, 310.0 * bin, RATE);
+            assert!(leakage < -30.0, "{:?}: {}", window, leakage);
+        }
+    }
+
+    #[test]
+    fn averaging_smooths_changes_and_silence_clears_it() {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let tone = 87.0 * RATE as f32 / 4096.0;
+        let level = |spectrum: &Spectrum| spectrum.level_db(tone - 5.0, tone + 5.0, RATE);
+        let mut spectrum = Spectrum::default();
+        spectrum.push(&sine(tone, 0.5, 4096));
+        spectrum.update(at(0));
+        let loud = level(&spectrum);
+        assert!((loud - -6.02).abs() < 0.1, "{}", loud);
+
+        // Half of the previous power survives after about 0.17 s.
+        spectrum.push(&vec![0.0; 8192 * 2]);
+        spectrum.update(at(173));
+        let averaged = level(&spectrum);
+        assert!((averaged - (loud - 3.0)).abs() < 0.2, "{}", averaged);
+
+        spectrum.update(at(5000));
+        assert!(level(&spectrum) < -80.0);
+    }
+
+    #[test]
+    fn waveform_columns_cover_the_newest_span() {
+        let mut waveform = Waveform {
+            span: 0.02,
+            ..Default::default()
+        };
+        let frames = (0.02 * RATE as f32) as usize;
+        waveform.push(&vec![0.9; 20000], RATE);
+        let ramp: Vec<f32> = (0..frames)
+            .flat_map(|n| [n as f32 / frames as f32, -1.0])
+            .collect();
+        waveform.push(&ramp, RATE);
+
+        let columns = waveform.columns(RATE, 10);
+        assert_eq!(columns.len(), 10);
+        assert_eq!(columns[0][0].0, 0.0);
+        assert!(columns[9][0].1 > 0.99);
+        assert!(columns.windows(2).all(|w| w[1][0].0 > w[0][0].1));
+        assert!(columns.iter().all(|c| c[1] == (-1.0, -1.0)));
+
+        // More columns than frames still gives each one a sample.
+        let columns = waveform.columns(RATE, 2000);
+        assert_eq!(
+            columns[1999],
+            [(ramp[ramp.len() - 2], ramp[ramp.len() - 2]), (-1.0, -1.0)]
+        );
+
+        let mut short = Waveform::default();
+        short.push(&[0.5, -0.5], RATE);
+        let columns = short.columns(RATE, 100);
+        assert_eq!(columns[0], [(0.0, 0.0); 2]);
+        assert_eq!(columns[99], [(0.0, 0.5), (-0.5, 0.0)]);
+    }
+}
@@ -0,0 +1,1226 @@
+use crate::analyzer::{self, AnalyzerView, Averaging, Spectrum, Waveform, Window};
+use crate::cli::SinkArgs;
+use crate::config::{Config, PlaybackSettings, SessionState};
+use crate::dither::{DitherMode, NoiseShaping};
+use crate::eq::{self, Band, EqPreset, EqSettings, FilterKind};
+use crate::fade::FadeCurve;
+use crate::format::SampleEncoding;
+use crate::limiter::LimiterMode;
+use crate::link::Link;
+use crate::live::{self, InputFormat};
+use crate::loudness::{GainMode, MAX_PREAMP};
+use crate::meter::{self, Block, Meter};
+use crate::player::{
+    AudioFile, AudioPlayer, MAX_FADE, MIN_VOLUME_DB, PlayerEvent, RepeatMode, format_duration,
+};
+use crate::ports::{self, Detection, PortEvent, PortInfo, PortWatcher};
+use crate::resample::ResampleQuality;
+use crate::sink::{self, Protocol, SinkKind};
+use eframe::egui;
+use rfd::FileDialog;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const TITLE: &str = "USB audio player";
+
+// How long the clip indicator stays lit after an over.
+const CLIP_HOLD: Duration = Duration::from_secs(1);
+// Rates firmware builds are known to run at; 46875 Hz is the stock one.
+const SAMPLE_RATES: [u32; 5] = [44100, 46875, 48000, 88200, 96000];
+// Window-only state kept by eframe rather than in the shared settings.
+const ANALYZER_VIEW_KEY: &str = "analyzer_view";
+
+pub struct App {
+    player: Arc<Mutex<AudioPlayer>>,
+    events: Receiver<PlayerEvent>,
+    blocks: Receiver<Block>,
+    meter: Meter,
+    analyzer_view: AnalyzerView,
+    spectrum: Spectrum,
+    waveform: Waveform,
+    available_ports: Vec<PortInfo>,
+    port_watcher: PortWatcher,
+    // Last word on the selected port coming or going.
+    port_notice: Option<String>,
+    // The saved settings, brought up to date from the window when saving.
+    config: Config,
+    sink_kind: SinkKind,
+    selected_port: String,
+    output_path: String,
+    protocol: Protocol,
+    _file_path: String,
+    playback_thread: Option<thread::JoinHandle<()>>,
+    // Where the seek bar is being dragged to; the seek happens on release.
+    seek_preview: Option<f32>,
+    show_eq: bool,
+    // Presets saved to disk, and the name typed into the editor.
+    eq_presets: Vec<EqPreset>,
+    eq_preset_name: String,
+}
+
+impl Default for App {
+    fn default() -> Self {
+        let mut player = AudioPlayer::default();
+        let events = player.subscribe();
+        let blocks = player.meter_blocks();
+
+        let eq_presets = match eq::presets_path().map(|path| eq::load_presets(&path)) {
+            Some(Ok(presets)) => presets,
+            Some(Err(e)) => {
+                eprintln!("Failed to load EQ presets: {}", e);
+                Vec::new()
+            }
+            None => Vec::new(),
+        };
+
+        Self {
+            player: Arc::new(Mutex::new(player)),
+            events,
+            blocks,
+            meter: Meter::default(),
+            analyzer_view: AnalyzerView::Off,
+            spectrum: Spectrum::default(),
+            waveform: Waveform::default(),
+            available_ports: ports::available_ports(),
+            port_watcher: PortWatcher::spawn(ports::POLL_INTERVAL),
+            port_notice: None,
+            config: Config::default(),
+            sink_kind: SinkKind::Serial,
+            selected_port: String::new(),
+            output_path: String::new(),
+            protocol: Protocol::Raw,
+            _file_path: String::new(),
+            playback_thread: None,
+            seek_preview: None,
+            show_eq: false,
+            eq_presets,
+            eq_preset_name: String::new(),
+        }
+    }
+}
+
+impl App {
+    pub fn new(args: SinkArgs, storage: Option<&dyn eframe::Storage>) -> Self {
+        let config = Config::load();
+        let mut output = config.output.clone();
+        args.apply(&mut output);
+        let mut app = Self {
+            sink_kind: output.sink,
+            selected_port: output.port.unwrap_or_default(),
+            output_path: output.path.unwrap_or_default(),
+            protocol: output.protocol,
+            config,
+            ..Default::default()
+        };
+        if let Some(view) = storage.and_then(|s| eframe::get_value(s, ANALYZER_VIEW_KEY)) {
+            app.analyzer_view = view;
+        }
+        if let Ok(mut player) = app.player.lock() {
+            app.config.playback.apply_to(&mut player);
+            app.config.session.apply_to(&mut player);
+        }
+
+        // A remembered port that's gone is forgotten in favour of whichever
+        // port the DAC is on now.
+        let present = app
+            .available_ports
+            .iter()
+            .any(|p| p.name == app.selected_port);
+        if app.sink_kind == SinkKind::Serial && !present && args.port.is_none() {
+            app.selected_port.clear();
+        }
+        app.watch_selected_port();
+        if !app.sink_kind.needs_target() || (app.sink_kind == SinkKind::Serial && present) {
+            app.connect();
+        } else if app.sink_kind == SinkKind::Serial && app.selected_port.is_empty() {
+            app.find_dac();
+        }
+        app
+    }
+
+    // The settings as they stand in the window.
+    fn settings(&self) -> Config {
+        let mut config = self.config.clone();
+        let non_empty = |text: &str| (!text.is_empty()).then(|| text.to_string());
+        config.output.sink = self.sink_kind;
+        config.output.port = non_empty(&self.selected_port);
+        config.output.path = non_empty(&self.output_path);
+        config.output.protocol = self.protocol;
+        if let Ok(player) = self.player.lock() {
+            config.playback = PlaybackSettings::from_player(&player);
+            config.session = SessionState::from_player(&player);
+        }
+        config
+    }
+
+    fn save_settings(&mut self) {
+        self.config = self.settings();
+        if let Err(e) = self.config.save() {
+            eprintln!("Failed to save settings: {}", e);
+        }
+    }
+
+    // Puts the player and the output options back to their defaults. The
+    // port, the queue and the connection are left alone.
+    fn reset_settings(&mut self) {
+        self.config.reset();
+        self.sink_kind = self.config.output.sink;
+        self.protocol = self.config.output.protocol;
+        self.output_path.clear();
+        if let Ok(mut player) = self.player.lock() {
+            let format = player.format;
+            self.config.playback.apply_to(&mut player);
+            // The open sink was set up for the old format.
+            if player.sink.is_some() {
+                player.format = format;
+            }
+        }
+        self.analyzer_view = AnalyzerView::Off;
+        self.save_settings();
+    }
+
+    // Picks the DAC's port and connects when exactly one board is plugged in.
+    fn find_dac(&mut self) {
+        match ports::detect_dac(&self.available_ports, &self.config.dac) {
+            Detection::Found(port) => {
+                println!("Found the DAC on {}", port.name);
+                self.port_notice = Some(format!("Found the DAC on {}", port.name));
+                self.selected_port = port.name.clone();
+                self.port_watcher.watch(Some(port));
+                self.connect();
+            }
+            Detection::Ambiguous(found) => {
+                self.port_notice = Some(format!("{} DACs found, pick one", found.len()));
+            }
+            Detection::NotFound => {}
+        }
+    }
+
+    // Nothing picked or connected yet, so a newly plugged in board can be
+    // taken without getting in the user's way.
+    fn waiting_for_dac(&self) -> bool {
+        self.sink_kind == SinkKind::Serial
+            && self.selected_port.is_empty()
+            && self
+                .player
+                .lock()
+                .is_ok_and(|p| !p.is_playing && p.sink.is_none() && p.link.is_none())
+    }
+
+    fn selected_port_info(&self) -> Option<PortInfo> {
+        (!self.selected_port.is_empty()).then(|| {
+            self.available_ports
+                .iter()
+                .find(|p| p.name == self.selected_port)
+                .cloned()
+                .unwrap_or_else(|| PortInfo::named(&self.selected_port))
+        })
+    }
+
+    fn watch_selected_port(&self) {
+        self.port_watcher.watch(self.selected_port_info());
+    }
+
+    fn handle_port_event(&mut self, event: PortEvent) {
+        match event {
+            PortEvent::Changed(ports) => {
+                self.available_ports = ports;
+                if self.waiting_for_dac() {
+                    self.find_dac();
+                }
+            }
+            PortEvent::Disappeared(port) => {
+                eprintln!("{} was unplugged", port.label());
+                self.port_notice = Some(format!("{} was unplugged", port.name));
+                // While playing, the player notices on its next write and
+                // handles the reconnect itself.
+                if let Ok(mut player) = self.player.lock()
+                    && !player.is_playing
+                    && player
+                        .link
+                        .as_ref()
+                        .is_some_and(|l| l.device.same_device(&port))
+                {
+                    player.sink = None;
+                    player.link_down = Some(format!("{} was unplugged", port.name));
+                }
+            }
+            PortEvent::Reappeared(port) => {
+                eprintln!("{} is back", port.label());
+                self.port_notice = Some(format!("{} is back", port.name));
+                self.selected_port = port.name.clone();
+                self.port_watcher.watch(Some(port));
+                let idle_and_down = self
+                    .player
+                    .lock()
+                    .is_ok_and(|p| !p.is_playing && p.sink.is_none() && p.link_down.is_some());
+                if idle_and_down {
+                    self.connect();
+                }
+            }
+        }
+    }
+
+    fn connect(&mut self) {
+        let target = if self.sink_kind == SinkKind::Serial {
+            &self.selected_port
+        } else {
+            &self.output_path
+        };
+        let format = self.player.lock().unwrap().format;
+        match sink::open_sink(self.sink_kind, target, format, self.protocol) {
+            Ok(sink) => {
+                let link = match self.sink_kind {
+                    SinkKind::Serial => self.selected_port_info().map(|device| Link {
+                        device,
+                        protocol: self.protocol,
+                    }),
+                    _ => None,
+                };
+                if let Ok(mut player) = self.player.lock() {
+                    println!("Connected to {}", sink.name());
+                    player.sink = Some(sink);
+                    player.link = link;
+                    player.link_down = None;
+                }
+            }
+            Err(e) => {
+                eprintln!(
+                    "Failed to open {} {}: {}",
+                    self.sink_kind.label(),
+                    target,
+                    e
+                );
+            }
+        }
+    }
+
+    // The device format, fixed while playing. An open output is reopened
+    // when it changes, since sinks like wav files are set up for one format.
+    fn format_menu(&mut self, ui: &mut egui::Ui) {
+        let Some((format, playing)) = self.player.lock().ok().map(|p| (p.format, p.is_playing))
+        else {
+            return;
+        };
+        let mut changed = format;
+        ui.add_enabled_ui(!playing, |ui| {
+            ui.menu_button("Format", |ui| {
+                egui::ComboBox::from_id_salt("sample_rate")
+                    .selected_text(format!("{} Hz", changed.sample_rate))
+                    .show_ui(ui, |ui| {
+                        for rate in SAMPLE_RATES {
+                            ui.selectable_value(
+                                &mut changed.sample_rate,
+                                rate,
+                                format!("{} Hz", rate),
+                            );
+                        }
+                    });
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut changed.channels, 2, "Stereo");
+                    ui.radio_value(&mut changed.channels, 1, "Mono");
+                });
+                for encoding in SampleEncoding::ALL {
+                    ui.radio_value(&mut changed.encoding, encoding, encoding.label());
+                }
+            })
+            .response
+            .on_hover_text(format.to_string())
+            .on_disabled_hover_text("Stop playback to change the format");
+        });
+        if changed == format {
+            return;
+        }
+        let reopen = self.player.lock().is_ok_and(|mut player| {
+            player.format = changed;
+            player.sink.take().is_some()
+        });
+        if reopen {
+            self.connect();
+        }
+    }
+
+    // Queues stdin or a named pipe, and sets how they're read.
+    fn live_input_menu(&mut self, ui: &mut egui::Ui) {
+        ui.menu_button("Live input", |ui| {
+            let Ok(mut player) = self.player.lock() else {
+                return;
+            };
+            ui.horizontal(|ui| {
+                if ui.button("Queue stdin").clicked() {
+                    player
+                        .queue
+                        .push_back(AudioFile::from_path(live::STDIN.as_ref()));
+                }
+                if ui.button("Queue named pipe").clicked()
+                    && let Some(path) = FileDialog::new().pick_file()
+                {
+                    player.queue.push_back(AudioFile::from_path(&path));
+                }
+            });
+            let input = &mut player.live_input;
+            egui::ComboBox::from_id_salt("input_format")
+                .selected_text(input.format.label())
+                .show_ui(ui, |ui| {
+                    for format in InputFormat::ALL {
+                        ui.selectable_value(&mut input.format, format, format.label());
+                    }
+                });
+            ui.add_enabled_ui(input.format == InputFormat::Raw, |ui| {
+                egui::ComboBox::from_id_salt("input_rate")
+                    .selected_text(format!("{} Hz", input.pcm.sample_rate))
+                    .show_ui(ui, |ui| {
+                        for rate in SAMPLE_RATES {
+                            ui.selectable_value(
+                                &mut input.pcm.sample_rate,
+                                rate,
+                                format!("{} Hz", rate),
+                            );
+                        }
+                    });
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut input.pcm.channels, 2, "Stereo");
+                    ui.radio_value(&mut input.pcm.channels, 1, "Mono");
+                });
+                for encoding in SampleEncoding::ALL {
+                    ui.radio_value(&mut input.pcm.encoding, encoding, encoding.label());
+                }
+            });
+            ui.add(
+                egui::Slider::new(&mut input.jitter_buffer, 0.0..=live::MAX_JITTER_BUFFER)
+                    .text("Jitter buffer")
+                    .suffix(" s"),
+            )
+            .on_hover_text("Held back before playing, and again when the source falls behind");
+        });
+    }
+
+    fn analyzer(&mut self, ui: &mut egui::Ui, sample_rate: u32) {
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_salt("analyzer_view")
+                .selected_text(self.analyzer_view.label())
+                .show_ui(ui, |ui| {
+                    for view in AnalyzerView::ALL {
+                        ui.selectable_value(&mut self.analyzer_view, view, view.label());
+                    }
+                });
+            match self.analyzer_view {
+                AnalyzerView::Off => {}
+                AnalyzerView::Spectrum => {
+                    egui::ComboBox::from_id_salt("fft_window")
+                        .selected_text(self.spectrum.window.label())
+                        .show_ui(ui, |ui| {
+                            for window in Window::ALL {
+                                ui.selectable_value(
+                                    &mut self.spectrum.window,
+                                    window,
+                                    window.label(),
+                                );
+                            }
+                        });
+                    egui::ComboBox::from_id_salt("fft_size")
+                        .selected_text(format!("{} points", self.spectrum.size))
+                        .show_ui(ui, |ui| {
+                            for size in analyzer::FFT_SIZES {
+                                ui.selectable_value(
+                                    &mut self.spectrum.size,
+                                    size,
+                                    format!("{} points", size),
+                                );
+                            }
+                        });
+                    egui::ComboBox::from_id_salt("fft_averaging")
+                        .selected_text(self.spectrum.averaging.label())
+                        .show_ui(ui, |ui| {
+                            for averaging in Averaging::ALL {
+                                ui.selectable_value(
+                                    &mut self.spectrum.averaging,
+                                    averaging,
+                                    averaging.label(),
+                                );
+                            }
+                        });
+                }
+                AnalyzerView::Waveform => {
+                    egui::ComboBox::from_id_salt("waveform_span")
+                        .selected_text(format!("{} ms", self.waveform.span * 1000.0))
+                        .show_ui(ui, |ui| {
+                            for span in analyzer::WAVEFORM_SPANS {
+                                ui.selectable_value(
+                                    &mut self.waveform.span,
+                                    span,
+                                    format!("{} ms", span * 1000.0),
+                                );
+                            }
+                        });
+                    if ui.button("Clear").clicked() {
+                        self.waveform.clear();
+                    }
+                }
+            }
+        });
+        match self.analyzer_view {
+            AnalyzerView::Off => {}
+            AnalyzerView::Spectrum => spectrum_plot(ui, &self.spectrum, sample_rate),
+            AnalyzerView::Waveform => waveform_plot(ui, &self.waveform, sample_rate),
+        }
+    }
+
+    fn equalizer_window(&mut self, ctx: &egui::Context) {
+        ctx.show_viewport_immediate(
+            egui::ViewportId::from_hash_of("equalizer"),
+            egui::ViewportBuilder::default()
+                .with_title(format!("Equalizer - {}", TITLE))
+                .with_inner_size([560.0, 420.0]),
+            |ctx, _| {
+                if ctx.input(|i| i.viewport().close_requested()) {
+                    self.show_eq = false;
+                }
+                egui::CentralPanel::default().show(ctx, |ui| self.equalizer_editor(ui));
+            },
+        );
+    }
+
+    fn equalizer_editor(&mut self, ui: &mut egui::Ui) {
+        let player = Arc::clone(&self.player);
+        let Ok(mut player) = player.lock() else {
+            return;
+        };
+        let sample_rate = player.format.sample_rate;
+        let settings = &mut player.eq;
+
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut settings.enabled, "Enabled");
+            ui.add(
+                egui::Slider::new(&mut settings.preamp, -15.0..=15.0)
+                    .text("Preamp")
+                    .suffix(" dB"),
+            );
+        });
+
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_salt("eq_preset")
+                .selected_text("Load preset")
+                .show_ui(ui, |ui| {
+                    for preset in eq::builtin_presets().iter().chain(&self.eq_presets) {
+                        if ui.selectable_label(false, &preset.name).clicked() {
+                            settings.apply_preset(preset);
+                            self.eq_preset_name = preset.name.clone();
+                        }
+                    }
+                });
+            ui.add(egui::TextEdit::singleline(&mut self.eq_preset_name).desired_width(140.0));
+            let name = self.eq_preset_name.trim().to_string();
+            let saved = self.eq_presets.iter().position(|p| p.name == name);
+            if ui
+                .add_enabled(!name.is_empty(), egui::Button::new("Save"))
+                .clicked()
+            {
+                let preset = EqPreset::from_settings(&name, settings);
+                match saved {
+                    Some(index) => self.eq_presets[index] = preset,
+                    None => self.eq_presets.push(preset),
+                }
+                self.save_eq_presets();
+            }
+            if ui
+                .add_enabled(saved.is_some(), egui::Button::new("Delete"))
+                .clicked()
+                && let Some(index) = saved
+            {
+                self.eq_presets.remove(index);
+                self.save_eq_presets();
+            }
+        });
+
+        response_plot(ui, settings, sample_rate);
+
+        let mut to_remove = None;
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            for (i, band) in settings.bands.iter_mut().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut band.enabled, "");
+                    egui::ComboBox::from_id_salt(("eq_kind", i))
+                        .selected_text(band.kind.label())
+                        .width(90.0)
+                        .show_ui(ui, |ui| {
+                            for kind in FilterKind::ALL {
+                                ui.selectable_value(&mut band.kind, kind, kind.label());
+                            }
+                        });
+                    ui.add(
+                        egui::Slider::new(&mut band.frequency, 20.0..=20000.0)
+                            .logarithmic(true)
+                            .suffix(" Hz"),
+                    );
+                    ui.add_enabled(
+                        band.kind.has_gain(),
+                        egui::Slider::new(&mut band.gain, -15.0..=15.0).suffix(" dB"),
+                    );
+                    ui.add(
+                        egui::DragValue::new(&mut band.q)
+                            .range(0.1..=10.0)
+                            .speed(0.01)
+                            .prefix("Q "),
+                    );
+                    if ui.button("Remove").clicked() {
+                        to_remove = Some(i);
+                    }
+                });
+            }
+        });
+        if let Some(index) = to_remove {
+            settings.bands.remove(index);
+        }
+        if ui.button("Add band").clicked() {
+            settings
+                .bands
+                .push(Band::new(FilterKind::Peaking, 1000.0, 0.0, 1.0));
+        }
+    }
+
+    fn save_eq_presets(&self) {
+        let Some(path) = eq::presets_path() else {
+            eprintln!("No config directory to save EQ presets in");
+            return;
+        };
+        if let Err(e) = eq::save_presets(&path, &self.eq_presets) {
+            eprintln!("Failed to save EQ presets to {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn volume_slider(db: &mut f32, top: f32) -> egui::Slider<'_> {
+    egui::Slider::new(db, MIN_VOLUME_DB..=top.max(MIN_VOLUME_DB))
+        .text("Volume")
+        .custom_formatter(|db, _| {
+            if db <= MIN_VOLUME_DB as f64 {
+                "-inf dB".to_string()
+            } else {
+                format!("{:.1} dB", db)
+            }
+        })
+}
+
+// One bar per channel: RMS filled in, the peak as a thinner bar over it and
+// the held peak as a tick, on a dB scale from the meter floor to full scale.
+fn level_meter(ui: &mut egui::Ui, meter: &Meter) {
+    let visuals = ui.visuals().clone();
+    let color = |db: f32| {
+        if db >= -0.1 {
+            egui::Color32::RED
+        } else if db >= -6.0 {
+            egui::Color32::YELLOW
+        } else {
+            egui::Color32::GREEN
+        }
+    };
+    for (channel, label) in meter.channels.iter().zip(["L", "R"]) {
+        ui.horizontal(|ui| {
+            ui.label(label);
+            let width = ui.available_width() - 50.0;
+            let (rect, _) = ui.allocate_exact_size(egui::vec2(width, 10.0), egui::Sense::hover());
+            let painter = ui.painter_at(rect);
+            painter.rect_filled(rect, 1.0, visuals.extreme_bg_color);
+            let x =
+                |db: f32| rect.left() + (db - meter::FLOOR_DB) / -meter::FLOOR_DB * rect.width();
+
+            let mut rms = rect;
+            rms.set_right(x(channel.rms));
+            painter.rect_filled(rms, 1.0, color(channel.rms).gamma_multiply(0.6));
+            let mut peak = rect.shrink2(egui::vec2(0.0, 3.0));
+            peak.set_right(x(channel.peak));
+            painter.rect_filled(peak, 0.0, color(channel.peak));
+            if channel.hold > meter::FLOOR_DB {
+                painter.vline(
+                    x(channel.hold),
+                    rect.y_range(),
+                    egui::Stroke::new(2.0, color(channel.hold)),
+                );
+            }
+            for db in [-48.0, -36.0, -24.0, -12.0, -6.0] {
+                painter.vline(
+                    x(db),
+                    rect.y_range(),
+                    egui::Stroke::new(1.0, visuals.faint_bg_color),
+                );
+            }
+
+            let hold = if channel.hold > meter::FLOOR_DB {
+                format!("{:.1}", channel.hold)
+            } else {
+                "-inf".to_string()
+            };
+            ui.monospace(hold);
+        });
+    }
+}
+
+const ANALYZER_HEIGHT: f32 = 120.0;
+
+// Log frequency axis from 20 Hz up to Nyquist, one bar per couple of pixels.
+fn spectrum_plot(ui: &mut egui::Ui, spectrum: &Spectrum, sample_rate: u32) {
+    let size = egui::vec2(ui.available_width(), ANALYZER_HEIGHT);
+    let (rect, _) = ui.allocate_exact_size(size, egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    let visuals = ui.visuals();
+    painter.rect_filled(rect, 2.0, visuals.extreme_bg_color);
+
+    let (min, max) = (20f32.log10(), (sample_rate as f32 / 2.0).max(40.0).log10());
+    let frequency = |x: f32| 10f32.powf(min + (x - rect.left()) / rect.width() * (max - min));
+    let x = |frequency: f32| rect.left() + (frequency.log10() - min) / (max - min) * rect.width();
+    let y = |db: f32| rect.top() + db / analyzer::FLOOR_DB * rect.height();
+    let grid = egui::Stroke::new(1.0, visuals.faint_bg_color);
+    for db in [-20.0, -40.0, -60.0, -80.0] {
+        painter.hline(rect.x_range(), y(db), grid);
+    }
+    for (hz, label) in [(100.0, "100"), (1000.0, "1k"), (10000.0, "10k")] {
+        painter.vline(x(hz), rect.y_range(), grid);
+        painter.text(
+            egui::pos2(x(hz) + 2.0, rect.bottom() - 2.0),
+            egui::Align2::LEFT_BOTTOM,
+            label,
+            egui::FontId::proportional(10.0),
+            visuals.weak_text_color(),
+        );
+    }
+
+    let step = 2.0;
+    let mut left = rect.left();
+    while left < rect.right() {
+        let level = spectrum.level_db(frequency(left), frequency(left + step), sample_rate);
+        let bar = egui::Rect::from_x_y_ranges(left..=left + step - 0.5, y(level)..=rect.bottom());
+        painter.rect_filled(bar, 0.0, visuals.selection.bg_fill);
+        left += step;
+    }
+}
+
+// Both channels over the chosen span, newest on the right; left above, right
+// below.
+fn waveform_plot(ui: &mut egui::Ui, waveform: &Waveform, sample_rate: u32) {
+    let size = egui::vec2(ui.available_width(), ANALYZER_HEIGHT);
+    let (rect, _) = ui.allocate_exact_size(size, egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    let visuals = ui.visuals();
+    painter.rect_filled(rect, 2.0, visuals.extreme_bg_color);
+
+    let lanes = [
+        egui::Rect::from_min_max(rect.min, egui::pos2(rect.right(), rect.center().y)),
+        egui::Rect::from_min_max(egui::pos2(rect.left(), rect.center().y), rect.max),
+    ];
+    for lane in lanes {
+        painter.hline(
+            rect.x_range(),
+            lane.center().y,
+            egui::Stroke::new(1.0, visuals.faint_bg_color),
+        );
+    }
+    let stroke = egui::Stroke::new(1.0, visuals.selection.bg_fill);
+    let columns = waveform.columns(sample_rate, rect.width() as usize);
+    for (i, column) in columns.iter().enumerate() {
+        let x = rect.left() + i as f32 + 0.5;
+        for (lane, &(low, high)) in lanes.iter().zip(column) {
+            let y = |sample: f32| lane.center().y - sample.clamp(-1.0, 1.0) * lane.height() / 2.0;
+            // Keep flat stretches visible as a one-pixel line.
+            let (top, bottom) = (y(high), y(low).max(y(high) + 1.0));
+            painter.vline(x, top..=bottom, stroke);
+        }
+    }
+}
+
+// Frequency response on a log axis from 20 Hz to 20 kHz.
+fn response_plot(ui: &mut egui::Ui, settings: &EqSettings, sample_rate: u32) {
+    const RANGE_DB: f32 = 18.0;
+    let (min, max) = (20f32.log10(), 20000f32.log10());
+    let size = egui::vec2(ui.available_width(), 160.0);
+    let (rect, _) = ui.allocate_exact_size(size, egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    let visuals = ui.visuals();
+    painter.rect_filled(rect, 2.0, visuals.extreme_bg_color);
+
+    let x = |frequency: f32| rect.left() + (frequency.log10() - min) / (max - min) * rect.width();
+    let y =
+        |db: f32| rect.center().y - db.clamp(-RANGE_DB, RANGE_DB) / RANGE_DB * rect.height() / 2.0;
+    let grid = egui::Stroke::new(1.0, visuals.faint_bg_color);
+    for frequency in [50.0, 100.0, 200.0, 500.0, 1000.0, 2000.0, 5000.0, 10000.0] {
+        painter.vline(x(frequency), rect.y_range(), grid);
+    }
+    for db in [-12.0, -6.0, 6.0, 12.0] {
+        painter.hline(rect.x_range(), y(db), grid);
+    }
+    painter.hline(
+        rect.x_range(),
+        y(0.0),
+        egui::Stroke::new(1.0, visuals.weak_text_color()),
+    );
+    for (frequency, label) in [(100.0, "100"), (1000.0, "1k"), (10000.0, "10k")] {
+        painter.text(
+            egui::pos2(x(frequency) + 2.0, rect.bottom() - 2.0),
+            egui::Align2::LEFT_BOTTOM,
+            label,
+            egui::FontId::proportional(10.0),
+            visuals.weak_text_color(),
+        );
+    }
+
+    let points: Vec<egui::Pos2> = (0..=rect.width() as usize / 2)
+        .map(|i| {
+            let position = rect.left() + i as f32 * 2.0;
+            let frequency = 10f32.powf(min + (position - rect.left()) / rect.width() * (max - min));
+            egui::pos2(position, y(settings.response(sample_rate, frequency)))
+        })
+        .collect();
+    let color = if settings.enabled {
+        visuals.selection.bg_fill
+    } else {
+        visuals.weak_text_color()
+    };
+    painter.add(egui::Shape::line(points, egui::Stroke::new(2.0, color)));
+}
+
+impl eframe::App for App {
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        eframe::set_value(storage, ANALYZER_VIEW_KEY, &self.analyzer_view);
+        self.save_settings();
+    }
+
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        for event in self.events.try_iter() {
+            match event {
+                PlayerEvent::TrackStarted(file) => ctx.send_viewport_cmd(
+                    egui::ViewportCommand::Title(format!("{} - {}", file.name, TITLE)),
+                ),
+                PlayerEvent::TrackFailed(file, e) => eprintln!("Skipping {}: {}", file.path, e),
+                PlayerEvent::QueueFinished => {
+                    ctx.send_viewport_cmd(egui::ViewportCommand::Title(TITLE.to_string()))
+                }
+                PlayerEvent::LinkLost(e) => eprintln!("{}; waiting for the device", e),
+                PlayerEvent::LinkRestored(name) => println!("Reconnected to {}", name),
+            }
+        }
+
+        // Arrow keys seek 5 s, or 30 s with Shift held.
+        if !ctx.wants_keyboard_input() {
+            let step = ctx.input(|i| {
+                let amount = if i.modifiers.shift { 30.0 } else { 5.0 };
+                if i.key_pressed(egui::Key::ArrowRight) {
+                    amount
+                } else if i.key_pressed(egui::Key::ArrowLeft) {
+                    -amount
+                } else {
+                    0.0
+                }
+            });
+            if step != 0.0
+                && let Ok(mut player) = self.player.lock()
+            {
+                player.seek_by(step);
+            }
+        }
+
+        let port_events: Vec<PortEvent> = self.port_watcher.events().collect();
+        for event in port_events {
+            self.handle_port_event(event);
+        }
+
+        let now = Instant::now();
+        let sample_rate = self.player.lock().map_or(0, |p| p.format.sample_rate);
+        let mut levels = Vec::new();
+        for block in self.blocks.try_iter() {
+            self.spectrum.push(&block.samples);
+            self.waveform.push(&block.samples, sample_rate);
+            levels.push(block.levels);
+        }
+        self.meter.update(levels, now);
+        if self.analyzer_view == AnalyzerView::Spectrum {
+            self.spectrum.update(now);
+        }
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Output:");
+                egui::ComboBox::from_id_salt("sink_kind")
+                    .selected_text(self.sink_kind.label())
+                    .show_ui(ui, |ui| {
+                        for kind in SinkKind::ALL {
+                            ui.selectable_value(&mut self.sink_kind, kind, kind.label());
+                        }
+                    });
+                match self.sink_kind {
+                    SinkKind::Serial => {
+                        let selected = self
+                            .available_ports
+                            .iter()
+                            .find(|p| p.name == self.selected_port);
+                        let no_dac = self.selected_port.is_empty()
+                            && !self
+                                .available_ports
+                                .iter()
+                                .any(|p| self.config.dac.matches(p));
+                        let hover = match selected {
+                            Some(port) => port.label(),
+                            None if no_dac => format!("Looking for {}", self.config.dac.describe()),
+                            None => "Not present".to_string(),
+                        };
+                        let text = if no_dac {
+                            egui::RichText::new("No DAC found").color(egui::Color32::YELLOW)
+                        } else {
+                            egui::RichText::new(&self.selected_port)
+                        };
+                        let mut changed = false;
+                        egui::ComboBox::from_id_salt("port")
+                            .selected_text(text)
+                            .show_ui(ui, |ui| {
+                                for port in &self.available_ports {
+                                    changed |= ui
+                                        .selectable_value(
+                                            &mut self.selected_port,
+                                            port.name.clone(),
+                                            port.label(),
+                                        )
+                                        .changed();
+                                }
+                            })
+                            .response
+                            .on_hover_text(hover);
+                        if changed {
+                            self.port_notice = None;
+                            self.watch_selected_port();
+                        }
+                    }
+                    SinkKind::Raw | SinkKind::Wav => {
+                        ui.add(
+                            egui::TextEdit::singleline(&mut self.output_path).desired_width(160.0),
+                        );
+                        if ui.button("...").clicked()
+                            && let Some(path) = FileDialog::new().save_file()
+                        {
+                            self.output_path = path.to_string_lossy().to_string();
+                        }
+                    }
+                    _ => {}
+                }
+                let mut framed = self.protocol == Protocol::Framed;
+                if ui.checkbox(&mut framed, "Framed").changed() {
+                    self.protocol = if framed {
+                        Protocol::Framed
+                    } else {
+                        Protocol::Raw
+                    };
+                }
+                self.format_menu(ui);
+                if ui.button("Connect").clicked() {
+                    self.connect();
+                }
+            });
+
+            ui.separator();
+
+            ui.horizontal(|ui| {
+                if ui.button("Select audio file").clicked()
+                    && let Some(path) = FileDialog::new()
+                        .add_filter("Audio files", &["mp3", "wav", "flac", "ogg", "m4a", "aac"])
+                        .pick_file()
+                    && let Ok(mut player) = self.player.lock()
+                {
+                    player.queue.push_back(AudioFile::from_path(&path));
+                    player.measure_queue();
+                }
+                self.live_input_menu(ui);
+                if let Ok(mut player) = self.player.lock() {
+                    egui::ComboBox::from_id_salt("repeat")
+                        .selected_text(player.repeat.label())
+                        .show_ui(ui, |ui| {
+                            for mode in RepeatMode::ALL {
+                                ui.selectable_value(&mut player.repeat, mode, mode.label());
+                            }
+                        });
+                    ui.checkbox(&mut player.shuffle, "Shuffle");
+                    ui.checkbox(&mut player.resume_after_reconnect, "Resume after reconnect")
+                        .on_hover_text(
+                            "Pick the track up where it stopped once a lost device is back",
+                        );
+                }
+            });
+            if let Ok(mut player) = self.player.lock() {
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::Slider::new(&mut player.crossfade, 0.0..=MAX_FADE)
+                            .text("Crossfade")
+                            .suffix(" s"),
+                    );
+                    egui::ComboBox::from_id_salt("crossfade_curve")
+                        .selected_text(player.crossfade_curve.label())
+                        .show_ui(ui, |ui| {
+                            for curve in FadeCurve::ALL {
+                                ui.selectable_value(
+                                    &mut player.crossfade_curve,
+                                    curve,
+                                    curve.label(),
+                                );
+                            }
+                        });
+                    let boost = ui.add(
+                        egui::Slider::new(&mut player.max_boost, 0.0..=2.0)
+                            .text("Max boost")
+                            .max_decimals(2),
+                    );
+                    if boost.changed() {
+                        player.volume = player.volume.min(player.max_boost);
+                    }
+                });
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_id_salt("gain_mode")
+                        .selected_text(player.gain_mode.label())
+                        .show_ui(ui, |ui| {
+                            for mode in GainMode::ALL {
+                                ui.selectable_value(&mut player.gain_mode, mode, mode.label());
+                            }
+                        });
+                    ui.add(
+                        egui::Slider::new(&mut player.preamp, -MAX_PREAMP..=MAX_PREAMP)
+                            .text("Preamp")
+                            .suffix(" dB"),
+                    );
+                    ui.checkbox(&mut player.clip_prevention, "Prevent clipping");
+                });
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_id_salt("dither")
+                        .selected_text(player.dither.label())
+                        .show_ui(ui, |ui| {
+                            for mode in DitherMode::ALL {
+                                ui.selectable_value(&mut player.dither, mode, mode.label());
+                            }
+                        });
+                    egui::ComboBox::from_id_salt("noise_shaping")
+                        .selected_text(player.noise_shaping.label())
+                        .show_ui(ui, |ui| {
+                            for shaping in NoiseShaping::ALL {
+                                ui.selectable_value(
+                                    &mut player.noise_shaping,
+                                    shaping,
+                                    shaping.label(),
+                                );
+                            }
+                        });
+                    ui.toggle_value(&mut self.show_eq, "Equalizer");
+                });
+            }
+            let mut reset = false;
+            ui.horizontal(|ui| {
+                reset = ui
+                    .button("Reset settings")
+                    .on_hover_text("Put volume, DSP and output options back to their defaults")
+                    .clicked();
+            });
+            if reset {
+                self.reset_settings();
+            }
+
+            ui.label("Queue:");
+            let mut to_remove = None;
+            let mut forget_resume = false;
+            if let Ok(player) = self.player.lock() {
+                if let Some((file, position)) = &player.resume {
+                    ui.horizontal(|ui| {
+                        ui.label(format!(
+                            "Resume {} at {}",
+                            file.name,
+                            format_duration(*position)
+                        ));
+                        forget_resume = ui.button("Remove").clicked();
+                    });
+                }
+                let queue = &player.queue;
+                egui::ScrollArea::vertical()
+                    .id_salt("queue")
+                    .max_height(160.0)
+                    .show(ui, |ui| {
+                        for (i, file) in queue.iter().enumerate() {
+                            ui.horizontal(|ui| {
+                                ui.label(format!("{}. {}", i + 1, file.name));
+                                if ui.button("Remove").clicked() {
+                                    to_remove = Some(i);
+                                }
+                            });
+                        }
+                    });
+            }
+            if let Some(index) = to_remove
+                && let Ok(mut player) = self.player.lock()
+            {
+                player.queue.remove(index);
+            }
+            if forget_resume && let Ok(mut player) = self.player.lock() {
+                player.resume = None;
+            }
+
+            ui.separator();
+
+            ui.horizontal(|ui| {
+                let (can_play, is_playing, is_paused, sink_connected) = if let Ok(player) =
+                    self.player.lock()
+                {
+                    (
+                        !player.is_playing && (!player.queue.is_empty() || player.resume.is_some()),
+                        player.is_playing,
+                        player.is_paused,
+                        player.sink.is_some(),
+                    )
+                } else {
+                    (false, false, false, false)
+                };
+
+                if ui.button("Play").clicked() && can_play && sink_connected {
+                    // Taken before the thread starts, so a second click can't
+                    // start another one on the same sink.
+                    if let Ok(mut player) = self.player.lock() {
+                        player.is_playing = true;
+                    }
+                    let player_clone = Arc::clone(&self.player);
+                    self.playback_thread = Some(thread::spawn(move || {
+                        AudioPlayer::play_queue(player_clone);
+                    }));
+                }
+                if is_playing
+                    && ui
+                        .button(if is_paused { "Resume" } else { "Pause" })
+                        .clicked()
+                    && let Ok(mut player) = self.player.lock()
+                {
+                    player.is_paused = !player.is_paused;
+                }
+                if ui.button("Stop").clicked()
+                    && let Ok(mut player) = self.player.lock()
+                {
+                    player.is_playing = false;
+                }
+                let mut volume = MIN_VOLUME_DB;
+                if let Ok(mut player) = self.player.lock() {
+                    ui.toggle_value(&mut player.muted, "Mute");
+                    let mut volume = player.volume_db();
+                    let top = 20.0 * player.max_boost.log10();
+                    if ui.add(volume_slider(&mut volume, top)).changed() {
+                        player.set_volume_db(volume);
+                    }
+                    egui::ComboBox::from_id_salt("resampler")
+                        .selected_text(player.resampler.label())
+                        .show_ui(ui, |ui| {
+                            for quality in ResampleQuality::ALL {
+                                ui.selectable_value(
+                                    &mut player.resampler,
+                                    quality,
+                                    quality.label(),
+                                );
+                            }
+                        });
+                } else {
+                    ui.add(volume_slider(&mut volume, 0.0));
+                }
+            });
+
+            if let Ok(mut player) = self.player.lock() {
+                if player.is_playing
+                    && let Some(name) = player.current_file.as_ref().map(|f| f.name.clone())
+                {
+                    if player.is_paused {
+                        ui.label(format!("Paused: {}", name));
+                    } else {
+                        ui.label(format!("Now playing: {}", name));
+                    }
+
+                    let mut position = self.seek_preview.unwrap_or(player.current_duration);
+                    ui.horizontal(|ui| {
+                        ui.label(format!(
+                            "{} / {}",
+                            format_duration(position),
+                            format_duration(player.total_duration)
+                        ));
+                        if player.total_duration > 0.0 {
+                            ui.spacing_mut().slider_width = ui.available_width();
+                            let response = ui.add(
+                                egui::Slider::new(&mut position, 0.0..=player.total_duration)
+                                    .show_value(false),
+                            );
+                            if response.dragged() {
+                                self.seek_preview = Some(position);
+                            }
+                            if response.drag_stopped()
+                                || (response.changed() && !response.dragged())
+                            {
+                                player.seek(position);
+                                self.seek_preview = None;
+                            }
+                        }
+                    });
+                }
+
+                if let Some(ref error) = player.last_error {
+                    ui.colored_label(egui::Color32::RED, error);
+                }
+
+                ui.horizontal(|ui| {
+                    if let Some(ref sink) = player.sink {
+                        ui.colored_label(
+                            egui::Color32::GREEN,
+                            format!("Connected: {}", sink.name()),
+                        );
+                    } else if let (Some(link), Some(reason)) = (&player.link, &player.link_down) {
+                        let waiting = if player.is_playing {
+                            "reconnecting"
+                        } else {
+                            "waiting for it"
+                        };
+                        ui.colored_label(
+                            egui::Color32::YELLOW,
+                            format!("Lost {}, {}", link.device.name, waiting),
+                        )
+                        .on_hover_text(reason);
+                    } else {
+                        ui.colored_label(egui::Color32::RED, "Not connected");
+                    }
+                    if let Some(ref notice) = self.port_notice {
+                        ui.colored_label(egui::Color32::YELLOW, notice);
+                    }
+
+                    egui::ComboBox::from_id_salt("limiter")
+                        .selected_text(player.limiter.label())
+                        .show_ui(ui, |ui| {
+                            for mode in LimiterMode::ALL {
+                                ui.selectable_value(&mut player.limiter, mode, mode.label());
+                            }
+                        });
+                    // Lit while overs keep coming, dimmed once they've stopped.
+                    let color = match player.last_over {
+                        Some(at) if at.elapsed() < CLIP_HOLD => egui::Color32::RED,
+                        Some(_) => egui::Color32::YELLOW,
+                        None => ui.visuals().weak_text_color(),
+                    };
+                    let indicator = ui
+                        .add(
+                            egui::Label::new(
+                                egui::RichText::new(format!("Clip: {}", player.overs)).color(color),
+                            )
+                            .sense(egui::Sense::click()),
+                        )
+                        .on_hover_text("Frames that went past full scale. Click to reset.");
+                    if indicator.clicked() {
+                        player.overs = 0;
+                        player.last_over = None;
+                    }
+                });
+            }
+
+            level_meter(ui, &self.meter);
+            self.analyzer(ui, sample_rate);
+        });
+
+        if self.show_eq {
+            self.equalizer_window(ctx);
+        }
+
+        ctx.request_repaint();
+    }
+}
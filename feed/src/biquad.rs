@@ -0,0 +1,231 @@
+use std::f64::consts::PI;
+
+// Second-order IIR section in transposed direct form II.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    // Takes coefficients already divided through by a0.
+    pub fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self { b, a, z: [0.0; 2] }
+    }
+
+    // The designs below follow Robert Bristow-Johnson's Audio EQ Cookbook.
+    // `gain` is in dB and `q` sets the bandwidth, or the shelf slope for the
+    // shelves.
+
+    pub fn peaking(sample_rate: u32, frequency: f64, gain: f64, q: f64) -> Self {
+        let (cos, alpha) = Self::prototype(sample_rate, frequency, q);
+        let a = 10f64.powf(gain / 40.0);
+        Self::normalized(
+            [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+            [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+        )
+    }
+
+    pub fn low_shelf(sample_rate: u32, frequency: f64, gain: f64, q: f64) -> Self {
+        let (cos, alpha) = Self::prototype(sample_rate, frequency, q);
+        let a = 10f64.powf(gain / 40.0);
+        let root = 2.0 * a.sqrt() * alpha;
+        Self::normalized(
+            [
+                a * ((a + 1.0) - (a - 1.0) * cos + root),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - root),
+            ],
+            [
+                (a + 1.0) + (a - 1.0) * cos + root,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - root,
+            ],
+        )
+    }
+
+    pub fn high_shelf(sample_rate: u32, frequency: f64, gain: f64, q: f64) -> Self {
+        let (cos, alpha) = Self::prototype(sample_rate, frequency, q);
+        let a = 10f64.powf(gain / 40.0);
+        let root = 2.0 * a.sqrt() * alpha;
+        Self::normalized(
+            [
+                a * ((a + 1.0) + (a - 1.0) * cos + root),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - root),
+            ],
+            [
+                (a + 1.0) - (a - 1.0) * cos + root,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - root,
+            ],
+        )
+    }
+
+    pub fn low_pass(sample_rate: u32, frequency: f64, q: f64) -> Self {
+        let (cos, alpha) = Self::prototype(sample_rate, frequency, q);
+        Self::normalized(
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    pub fn high_pass(sample_rate: u32, frequency: f64, q: f64) -> Self {
+        let (cos, alpha) = Self::prototype(sample_rate, frequency, q);
+        Self::normalized(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    fn prototype(sample_rate: u32, frequency: f64, q: f64) -> (f64, f64) {
+        // Kept below Nyquist so a band dragged to the top stays stable.
+        let frequency = frequency.clamp(1.0, sample_rate as f64 * 0.499);
+        let w0 = 2.0 * PI * frequency / sample_rate as f64;
+        (w0.cos(), w0.sin() / (2.0 * q.max(0.01)))
+    }
+
+    fn normalized(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self::new(
+            [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            [a[1] / a[0], a[2] / a[0]],
+        )
+    }
+
+    pub fn coefficients(&self) -> ([f64; 3], [f64; 2]) {
+        (self.b, self.a)
+    }
+
+    // Takes over another section's coefficients but keeps this one's state,
+    // so a filter can be retuned while audio runs through it.
+    pub fn retune(&mut self, other: &Biquad) {
+        self.b = other.b;
+        self.a = other.a;
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+
+    // Magnitude response in dB at `frequency`.
+    pub fn response(&self, sample_rate: u32, frequency: f64) -> f64 {
+        let w = 2.0 * PI * frequency / sample_rate as f64;
+        // H(e^jw) with z^-1 = e^-jw, as (re, im) pairs.
+        let eval = |c0: f64, c1: f64, c2: f64| {
+            (
+                c0 + c1 * w.cos() + c2 * (2.0 * w).cos(),
+                -c1 * w.sin() - c2 * (2.0 * w).sin(),
+            )
+        };
+        let (nr, ni) = eval(self.b[0], self.b[1], self.b[2]);
+        let (dr, di) = eval(1.0, self.a[0], self.a[1]);
+        10.0 * ((nr * nr + ni * ni) / (dr * dr + di * di)).log10()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::FRAC_1_SQRT_2;
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() < tolerance,
+            "{} vs {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn coefficients_match_the_cookbook() {
+        // Reference values worked out by hand from the cookbook formulas.
+        let (b, a) = Biquad::low_pass(48000, 1000.0, FRAC_1_SQRT_2).coefficients();
+        let expected_b = [0.003_916_127, 0.007_832_253, 0.003_916_127];
+        let expected_a = [-1.815_341_083, 0.831_005_589];
+        for (x, y) in b.iter().zip(expected_b).chain(a.iter().zip(expected_a)) {
+            assert_close(*x, y, 1e-8);
+        }
+
+        let (b, a) = Biquad::peaking(48000, 1000.0, 6.0, FRAC_1_SQRT_2).coefficients();
+        let expected_b = [1.061_042_425, -1.861_273_144, 0.816_291_571];
+        let expected_a = [-1.861_273_144, 0.877_333_997];
+        for (x, y) in b.iter().zip(expected_b).chain(a.iter().zip(expected_a)) {
+            assert_close(*x, y, 1e-8);
+        }
+    }
+
+    #[test]
+    fn peaking_hits_its_gain_at_the_centre() {
+        let rate = 46875;
+        for gain in [-12.0, -3.0, 4.5, 12.0] {
+            let filter = Biquad::peaking(rate, 2000.0, gain, 1.4);
+            assert_close(filter.response(rate, 2000.0), gain, 1e-9);
+            assert_close(filter.response(rate, 20.0), 0.0, 0.01);
+            assert_close(filter.response(rate, 20000.0), 0.0, 0.1);
+        }
+    }
+
+    #[test]
+    fn shelves_reach_their_gain_on_one_side_only() {
+        let rate = 46875;
+        let low = Biquad::low_shelf(rate, 200.0, 6.0, FRAC_1_SQRT_2);
+        assert_close(low.response(rate, 5.0), 6.0, 0.01);
+        assert_close(low.response(rate, 200.0), 3.0, 0.05);
+        assert_close(low.response(rate, 15000.0), 0.0, 0.01);
+
+        let high = Biquad::high_shelf(rate, 5000.0, -8.0, FRAC_1_SQRT_2);
+        assert_close(high.response(rate, 23000.0), -8.0, 0.05);
+        assert_close(high.response(rate, 5000.0), -4.0, 0.05);
+        assert_close(high.response(rate, 20.0), 0.0, 0.01);
+    }
+
+    #[test]
+    fn butterworth_passes_are_3_db_down_at_the_corner() {
+        let rate = 46875;
+        let q = FRAC_1_SQRT_2;
+        let low = Biquad::low_pass(rate, 3000.0, q);
+        assert_close(low.response(rate, 3000.0), -3.0103, 1e-3);
+        assert_close(low.response(rate, 30.0), 0.0, 1e-3);
+        assert!(low.response(rate, 20000.0) < -30.0);
+
+        let high = Biquad::high_pass(rate, 80.0, q);
+        assert_close(high.response(rate, 80.0), -3.0103, 1e-3);
+        assert_close(high.response(rate, 10000.0), 0.0, 1e-3);
+        assert!(high.response(rate, 10.0) < -30.0);
+    }
+
+    #[test]
+    fn filtered_sine_matches_the_computed_response() {
+        let rate = 46875;
+        let mut filter = Biquad::peaking(rate, 1000.0, 9.0, 2.0);
+        let expected = filter.response(rate, 700.0);
+
+        let w = 2.0 * PI * 700.0 / rate as f64;
+        let output: Vec<f64> = (0..rate)
+            .map(|n| filter.process((w * n as f64).sin()))
+            .collect();
+        // Past the transient, the amplitude is the steady-state gain.
+        let peak = output[rate as usize / 2..]
+            .iter()
+            .fold(0f64, |m, y| m.max(y.abs()));
+        assert_close(20.0 * peak.log10(), expected, 0.01);
+    }
+
+    #[test]
+    fn retuning_keeps_the_state() {
+        let mut filter = Biquad::low_pass(48000, 1000.0, 0.7);
+        filter.process(1.0);
+        let before = filter.z;
+        filter.retune(&Biquad::high_pass(48000, 500.0, 0.7));
+        assert_eq!(filter.z, before);
+        assert_eq!(
+            filter.coefficients(),
+            Biquad::high_pass(48000, 500.0, 0.7).coefficients()
+        );
+    }
+}
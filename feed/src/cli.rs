@@ -0,0 +1,627 @@
+use crate::config::{self, Config, OutputSettings, PlaybackSettings};
+use crate::decode::DecoderBackend;
+use crate::dither::{DitherMode, NoiseShaping};
+use crate::eq::{self, Band};
+use crate::fade::FadeCurve;
+use crate::flow::FlowControl;
+use crate::format::SampleEncoding;
+use crate::limiter::LimiterMode;
+use crate::link::Link;
+use crate::live::InputFormat;
+use crate::loudness::{GainMode, MAX_PREAMP, db_to_linear};
+use crate::player::{AudioFile, AudioPlayer, PlayerEvent, RepeatMode, format_duration};
+use crate::ports::{self, DacMatch, Detection, PortInfo};
+use crate::resample::ResampleQuality;
+use crate::sink::{self, Protocol, SinkKind};
+use clap::{Args, Parser, Subcommand};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Parser)]
+#[command(about = "Streams audio files to the STM32F4 USB DAC")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub sink: SinkArgs,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Play files without opening the window; options left out come from
+    /// the saved settings
+    Play(Box<PlayArgs>),
+    /// List the serial ports that can be used as a sink
+    ListPorts,
+    /// Show or reset the saved settings
+    Settings {
+        #[command(subcommand)]
+        action: SettingsAction,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SettingsAction {
+    /// Print the settings in effect
+    Show,
+    /// Print where the settings are kept
+    Path,
+    /// Go back to the default settings, keeping the DAC's description
+    Reset,
+}
+
+#[derive(Args, Clone)]
+pub struct SinkArgs {
+    /// Where to send the converted audio [default: serial]
+    #[arg(long, value_enum)]
+    pub sink: Option<SinkKind>,
+    /// Serial port for the serial sink, found by the DAC's USB descriptors
+    /// when left out
+    #[arg(long)]
+    pub port: Option<String>,
+    /// Output file for the raw and wav sinks
+    #[arg(long)]
+    pub output: Option<String>,
+    /// Wire format used on the link [default: raw]
+    #[arg(long, value_enum)]
+    pub protocol: Option<Protocol>,
+}
+
+impl SinkArgs {
+    // Lays the options that were given over the saved ones.
+    pub fn apply(&self, output: &mut OutputSettings) {
+        if let Some(sink) = self.sink {
+            output.sink = sink;
+        }
+        if let Some(port) = &self.port {
+            output.port = Some(port.clone());
+        }
+        if let Some(path) = &self.output {
+            output.path = Some(path.clone());
+        }
+        if let Some(protocol) = self.protocol {
+            output.protocol = protocol;
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct PlayArgs {
+    #[command(flatten)]
+    pub sink: SinkArgs,
+    /// Playback volume, either a gain where 1.0 leaves the samples
+    /// untouched or a level in dB such as -6dB [default: 1.0]
+    #[arg(long, value_parser = parse_volume, allow_hyphen_values = true)]
+    pub volume: Option<f32>,
+    /// Highest gain the volume may reach, from 0.0 to 2.0 [default: 2.0]
+    #[arg(long, value_parser = parse_max_boost)]
+    pub max_boost: Option<f32>,
+    /// Start over once every file has been played, same as `--repeat all`
+    #[arg(long = "loop")]
+    pub repeat_all: bool,
+    /// What happens when a track or the whole list ends [default: off]
+    #[arg(long, value_enum)]
+    pub repeat: Option<RepeatMode>,
+    /// Play the files in random order
+    #[arg(long)]
+    pub shuffle: bool,
+    /// Play the files in the order given, even if shuffle was saved on
+    #[arg(long, conflicts_with = "shuffle")]
+    pub no_shuffle: bool,
+    /// Sample rate the device runs at [default: 46875]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub rate: Option<u32>,
+    /// Channels the device takes, 1 mixes the stereo down [default: 2]
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..=2))]
+    pub channels: Option<u16>,
+    /// How the device expects each sample to be laid out [default: s16le]
+    #[arg(long, value_enum)]
+    pub encoding: Option<SampleEncoding>,
+    /// How the send rate is paced [default: timed]
+    #[arg(long, value_enum)]
+    pub flow_control: Option<FlowControl>,
+    /// Which decoder turns the files into samples [default: auto]
+    #[arg(long, value_enum)]
+    pub decoder: Option<DecoderBackend>,
+    /// Sample-rate converter used when a file isn't at the device rate
+    /// [default: polyphase]
+    #[arg(long, value_enum)]
+    pub resampler: Option<ResampleQuality>,
+    /// Seconds to fade in when playback starts or seeks [default: 0.05]
+    #[arg(long, value_parser = parse_seconds)]
+    pub fade_in: Option<f32>,
+    /// Seconds to fade out when playback is interrupted [default: 0.05]
+    #[arg(long, value_parser = parse_seconds)]
+    pub fade_out: Option<f32>,
+    /// Shape of the fade-in and fade-out [default: equal-power]
+    #[arg(long, value_enum)]
+    pub fade_curve: Option<FadeCurve>,
+    /// Seconds each track overlaps the next, 0 plays them back to back
+    /// [default: 0]
+    #[arg(long, value_parser = parse_seconds)]
+    pub crossfade: Option<f32>,
+    /// Shape of the crossfade [default: equal-power]
+    #[arg(long, value_enum)]
+    pub crossfade_curve: Option<FadeCurve>,
+    /// Loudness normalization from ReplayGain/R128 tags, measuring files
+    /// that have none [default: off]
+    #[arg(long, value_enum)]
+    pub replay_gain: Option<GainMode>,
+    /// dB added on top of the normalization gain, from -15 to 15 [default: 0]
+    #[arg(long, value_parser = parse_preamp, allow_hyphen_values = true)]
+    pub preamp: Option<f32>,
+    /// Let the normalization gain push peaks past full scale
+    #[arg(long)]
+    pub no_clip_prevention: bool,
+    /// What happens to peaks that volume or gain push past full scale
+    /// [default: lookahead]
+    #[arg(long, value_enum)]
+    pub limiter: Option<LimiterMode>,
+    /// Noise added before rounding to the output sample format [default: tpdf]
+    #[arg(long, value_enum)]
+    pub dither: Option<DitherMode>,
+    /// Filter that shapes the rounding noise spectrum [default: off]
+    #[arg(long, value_enum)]
+    pub noise_shaping: Option<NoiseShaping>,
+    /// Equalizer preset, built in or saved from the GUI
+    #[arg(long)]
+    pub eq_preset: Option<String>,
+    /// Equalizer band as TYPE:FREQ[:GAIN[:Q]], e.g. peaking:1000:-3:1.4;
+    /// replaces the saved bands or follows the preset's, may be repeated
+    #[arg(long = "eq-band", value_name = "BAND", allow_hyphen_values = true)]
+    pub eq_bands: Vec<Band>,
+    /// How stdin or a named pipe is read [default: auto]
+    #[arg(long, value_enum)]
+    pub input_format: Option<InputFormat>,
+    /// Sample rate of raw input [default: 48000]
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub input_rate: Option<u32>,
+    /// Channels in raw input [default: 2]
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..=2))]
+    pub input_channels: Option<u16>,
+    /// Sample layout of raw input [default: s16le]
+    #[arg(long, value_enum)]
+    pub input_encoding: Option<SampleEncoding>,
+    /// Seconds of live input buffered before it plays, and again whenever
+    /// the source falls behind [default: 0.2]
+    #[arg(long, value_parser = parse_seconds)]
+    pub jitter_buffer: Option<f32>,
+    /// Seconds to wait for a serial device that drops off the bus, 0 stops
+    /// playback straight away [default: 30]
+    #[arg(long, value_parser = parse_seconds)]
+    pub reconnect_timeout: Option<f32>,
+    /// After a reconnect, stop instead of resuming the interrupted track
+    #[arg(long)]
+    pub no_resume: bool,
+    /// Keep the options given here as the new defaults
+    #[arg(long)]
+    pub save_settings: bool,
+    /// Audio files to play; `-` or a named pipe plays live input as it
+    /// arrives
+    #[arg(required = true)]
+    pub files: Vec<PathBuf>,
+}
+
+impl PlayArgs {
+    // Lays the options that were given over the saved ones.
+    fn apply(&self, settings: &mut PlaybackSettings) -> Result<(), Box<dyn std::error::Error>> {
+        fn set<T: Copy>(setting: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *setting = value;
+            }
+        }
+        set(&mut settings.max_boost, self.max_boost);
+        set(&mut settings.volume, self.volume);
+        if self.repeat_all {
+            settings.repeat = RepeatMode::All;
+        } else {
+            set(&mut settings.repeat, self.repeat);
+        }
+        if self.shuffle || self.no_shuffle {
+            settings.shuffle = self.shuffle;
+        }
+        set(&mut settings.format.sample_rate, self.rate);
+        set(&mut settings.format.channels, self.channels);
+        set(&mut settings.format.encoding, self.encoding);
+        set(&mut settings.flow_control, self.flow_control);
+        set(&mut settings.decoder, self.decoder);
+        set(&mut settings.resampler, self.resampler);
+        set(&mut settings.fade_in, self.fade_in);
+        set(&mut settings.fade_out, self.fade_out);
+        set(&mut settings.fade_curve, self.fade_curve);
+        set(&mut settings.crossfade, self.crossfade);
+        set(&mut settings.crossfade_curve, self.crossfade_curve);
+        set(&mut settings.gain_mode, self.replay_gain);
+        set(&mut settings.preamp, self.preamp);
+        settings.clip_prevention &= !self.no_clip_prevention;
+        set(&mut settings.limiter, self.limiter);
+        set(&mut settings.dither, self.dither);
+        set(&mut settings.noise_shaping, self.noise_shaping);
+        let live = &mut settings.live_input;
+        set(&mut live.format, self.input_format);
+        set(&mut live.pcm.sample_rate, self.input_rate);
+        set(&mut live.pcm.channels, self.input_channels);
+        set(&mut live.pcm.encoding, self.input_encoding);
+        set(&mut live.jitter_buffer, self.jitter_buffer);
+        set(&mut settings.reconnect_timeout, self.reconnect_timeout);
+        settings.resume_after_reconnect &= !self.no_resume;
+
+        let eq = &mut settings.eq;
+        if let Some(name) = &self.eq_preset {
+            eq.apply_preset(&eq::find_preset(name)?);
+            eq.enabled = true;
+        } else if !self.eq_bands.is_empty() {
+            eq.bands.clear();
+        }
+        if !self.eq_bands.is_empty() {
+            eq.bands.extend(self.eq_bands.iter().copied());
+            eq.enabled = true;
+        }
+        Ok(())
+    }
+}
+
+fn parse_volume(value: &str) -> Result<f32, String> {
+    let volume = match value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+    {
+        Some(db) => db
+            .trim()
+            .parse::<f32>()
+            .map(db_to_linear)
+            .map_err(|_| format!("invalid level '{}'", value))?,
+        None => value
+            .parse::<f32>()
+            .map_err(|_| format!("invalid volume '{}'", value))?,
+    };
+    if !volume.is_finite() {
+        return Err(format!("invalid volume '{}'", value));
+    }
+    if volume < 0.0 {
+        return Err("volume can't be negative".to_string());
+    }
+    Ok(volume)
+}
+
+fn parse_max_boost(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(boost) if (0.0..=2.0).contains(&boost) => Ok(boost),
+        _ => Err(format!("'{}' isn't between 0.0 and 2.0", value)),
+    }
+}
+
+fn parse_preamp(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(db) if (-MAX_PREAMP..=MAX_PREAMP).contains(&db) => Ok(db),
+        _ => Err(format!("'{}' isn't between -15 and 15 dB", value)),
+    }
+}
+
+fn parse_seconds(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(seconds) if seconds >= 0.0 => Ok(seconds),
+        _ => Err(format!("'{}' isn't a number of seconds", value)),
+    }
+}
+
+pub fn run(command: Command) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Play(args) => play(*args),
+        Command::ListPorts => list_ports(),
+        Command::Settings { action } => settings(action),
+    }
+}
+
+fn play(args: PlayArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load();
+    args.sink.apply(&mut config.output);
+    args.apply(&mut config.playback)?;
+    if args.save_settings {
+        config.save()?;
+        eprintln!("Saved these options as the new defaults");
+    }
+
+    let output = &config.output;
+    let device = match output.sink {
+        SinkKind::Serial => Some(serial_device(
+            output.port.as_deref(),
+            args.sink.port.is_some(),
+            &config.dac,
+        )?),
+        _ => None,
+    };
+    let target = device
+        .as_ref()
+        .map_or(output.target(), |device| device.name.as_str());
+
+    let format = config.playback.format;
+    let sink = sink::open_sink(output.sink, target, format, output.protocol)?;
+    eprintln!("Streaming {} to {}", format, sink.name());
+    let link = device.map(|device| Link {
+        device,
+        protocol: output.protocol,
+    });
+
+    let mut player = AudioPlayer {
+        sink: Some(sink),
+        link,
+        ..Default::default()
+    };
+    config.playback.apply_to(&mut player);
+    let player = Arc::new(Mutex::new(player));
+
+    let files: Vec<AudioFile> = args.files.iter().map(|p| AudioFile::from_path(p)).collect();
+    let events = {
+        let mut p = player.lock().unwrap();
+        p.queue.extend(files.iter().cloned());
+        p.measure_queue();
+        p.subscribe()
+    };
+
+    let handle = {
+        let player = Arc::clone(&player);
+        thread::spawn(move || AudioPlayer::play_queue(player))
+    };
+
+    let mut stderr = std::io::stderr();
+    let mut status_shown = false;
+    while !handle.is_finished() {
+        for event in events.try_iter() {
+            if status_shown {
+                let _ = writeln!(stderr);
+                status_shown = false;
+            }
+            print_event(event, &files);
+        }
+
+        let (current, total) = {
+            let p = player.lock().unwrap();
+            (p.current_duration, p.total_duration)
+        };
+        if total > 0.0 {
+            let _ = write!(
+                stderr,
+                "\r{} / {}",
+                format_duration(current),
+                format_duration(total)
+            );
+            status_shown = true;
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+    if status_shown {
+        let _ = writeln!(stderr);
+    }
+    for event in events.try_iter() {
+        print_event(event, &files);
+    }
+
+    let finished = handle.join().map_err(|_| "playback thread panicked")?;
+    let (overs, limiter) = {
+        let p = player.lock().unwrap();
+        (p.overs, p.limiter)
+    };
+    if overs > 0 {
+        let handling = match limiter {
+            LimiterMode::Off => "clipped",
+            _ => "limited",
+        };
+        eprintln!(
+            "{} frames went past full scale and were {}",
+            overs, handling
+        );
+    }
+    if finished == 0 {
+        return Err("none of the files could be played".into());
+    }
+    Ok(())
+}
+
+fn print_event(event: PlayerEvent, files: &[AudioFile]) {
+    match event {
+        PlayerEvent::TrackStarted(file) => {
+            let index = files.iter().position(|f| f == &file).unwrap_or(0);
+            eprintln!("[{}/{}] {}", index + 1, files.len(), file.name);
+        }
+        PlayerEvent::TrackFailed(file, e) => eprintln!("Skipping {}: {}", file.path, e),
+        PlayerEvent::QueueFinished => {}
+        PlayerEvent::LinkLost(e) => eprintln!("{}; waiting for the device to come back", e),
+        PlayerEvent::LinkRestored(name) => eprintln!("Reconnected to {}", name),
+    }
+}
+
+// The port to stream to. A port that was asked for on the command line is
+// used even if it isn't listed; a remembered one that's gone falls back to
+// looking for the DAC.
+fn serial_device(
+    port: Option<&str>,
+    explicit: bool,
+    dac: &DacMatch,
+) -> Result<PortInfo, Box<dyn std::error::Error>> {
+    let ports = ports::available_ports();
+    if let Some(name) = port {
+        if let Some(found) = ports.iter().find(|p| p.name == name) {
+            return Ok(found.clone());
+        }
+        if explicit {
+            return Ok(PortInfo::named(name));
+        }
+        eprintln!("{} isn't there any more, looking for the DAC", name);
+    }
+    find_dac(&ports, dac)
+}
+
+// The one connected port that looks like the DAC.
+fn find_dac(ports: &[PortInfo], dac: &DacMatch) -> Result<PortInfo, Box<dyn std::error::Error>> {
+    match ports::detect_dac(ports, dac) {
+        Detection::Found(port) => {
+            eprintln!("Found the DAC on {}", port.name);
+            Ok(port)
+        }
+        Detection::NotFound => Err(format!(
+            "No DAC found (looking for {}); plug it in or pass --port",
+            dac.describe()
+        )
+        .into()),
+        Detection::Ambiguous(found) => {
+            let names: Vec<&str> = found.iter().map(|p| p.name.as_str()).collect();
+            Err(format!(
+                "More than one DAC found ({}); pick one with --port",
+                names.join(", ")
+            )
+            .into())
+        }
+    }
+}
+
+fn list_ports() -> Result<(), Box<dyn std::error::Error>> {
+    let ports = ports::available_ports();
+    if ports.is_empty() {
+        eprintln!("No serial ports found");
+    }
+
+    let dac = Config::load().dac;
+    for port in ports {
+        let mut description = match &port.usb {
+            Some(usb) => usb.describe(),
+            None => "Not USB".to_string(),
+        };
+        if dac.matches(&port) {
+            description += " [DAC]";
+        }
+        println!("{}\t{}", port.name, description);
+    }
+
+    Ok(())
+}
+
+fn settings(action: SettingsAction) -> Result<(), Box<dyn std::error::Error>> {
+    let path = config::config_path().ok_or("no config directory")?;
+    match action {
+        SettingsAction::Show => print!("{}", toml::to_string_pretty(&Config::load())?),
+        SettingsAction::Path => println!("{}", path.display()),
+        SettingsAction::Reset => {
+            let mut config = Config::load();
+            config.reset();
+            config.save_to(&path)?;
+            eprintln!("Reset the settings in {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eq::FilterKind;
+
+    fn play_args(options: &[&str]) -> PlayArgs {
+        let args = ["feed", "play"].iter().chain(options).chain(&["a.wav"]);
+        match Cli::try_parse_from(args).unwrap().command {
+            Some(Command::Play(args)) => *args,
+            _ => unreachable!(),
+        }
+    }
+
+    fn applied(saved: &PlaybackSettings, options: &[&str]) -> PlaybackSettings {
+        let mut settings = saved.clone();
+        play_args(options).apply(&mut settings).unwrap();
+        settings
+    }
+
+    #[test]
+    fn options_left_out_keep_the_saved_settings() {
+        let saved = PlaybackSettings {
+            volume: 0.5,
+            shuffle: true,
+            crossfade: 2.0,
+            repeat: RepeatMode::One,
+            ..Default::default()
+        };
+        assert_eq!(applied(&saved, &[]), saved);
+
+        let given = applied(&saved, &["--volume", "-6dB", "--crossfade", "0", "--loop"]);
+        assert!((given.volume - 0.501).abs() < 0.001);
+        assert_eq!(given.crossfade, 0.0);
+        assert_eq!(given.repeat, RepeatMode::All);
+        assert!(given.shuffle);
+    }
+
+    #[test]
+    fn shuffle_can_be_turned_off_again() {
+        let saved = PlaybackSettings {
+            shuffle: true,
+            ..Default::default()
+        };
+        assert!(!applied(&saved, &["--no-shuffle"]).shuffle);
+        let saved = PlaybackSettings::default();
+        assert!(applied(&saved, &["--shuffle"]).shuffle);
+        assert!(
+            Cli::try_parse_from(["feed", "play", "--shuffle", "--no-shuffle", "a.wav"]).is_err()
+        );
+    }
+
+    #[test]
+    fn negative_times_are_rejected() {
+        for option in [
+            "--fade-in",
+            "--fade-out",
+            "--crossfade",
+            "--reconnect-timeout",
+            "--jitter-buffer",
+        ] {
+            let args = ["feed", "play", option, "-1", "a.wav"];
+            assert!(Cli::try_parse_from(args).is_err(), "{}", option);
+            let args = ["feed", "play", option, "NaN", "a.wav"];
+            assert!(Cli::try_parse_from(args).is_err(), "{}", option);
+        }
+        assert_eq!(play_args(&["--fade-in", "0.5"]).fade_in, Some(0.5));
+    }
+
+    #[test]
+    fn volume_must_be_a_finite_level() {
+        for value in ["NaN", "inf", "infdB", "NaN dB", "-1", "loud"] {
+            assert!(parse_volume(value).is_err(), "{}", value);
+        }
+        assert_eq!(parse_volume("0.5"), Ok(0.5));
+        assert_eq!(parse_volume("-infdB"), Ok(0.0));
+    }
+
+    #[test]
+    fn preamp_stays_within_the_slider_range() {
+        for value in ["NaN", "inf", "1e9", "-16"] {
+            assert!(parse_preamp(value).is_err(), "{}", value);
+        }
+        assert_eq!(play_args(&["--preamp", "-6"]).preamp, Some(-6.0));
+    }
+
+    #[test]
+    fn eq_bands_replace_the_saved_ones() {
+        let mut saved = PlaybackSettings::default();
+        saved.eq.bands = vec![Band::new(FilterKind::Peaking, 500.0, -3.0, 1.0)];
+        let band = Band::new(FilterKind::Peaking, 1000.0, 2.0, 1.4);
+
+        let given = applied(&saved, &["--eq-band", "peaking:1000:2:1.4"]);
+        assert_eq!(given.eq.bands, [band]);
+        assert!(given.eq.enabled);
+        assert_eq!(applied(&saved, &[]).eq.bands, saved.eq.bands);
+
+        // Bands given with a preset go after the preset's.
+        let given = applied(
+            &saved,
+            &[
+                "--eq-preset",
+                "Bass boost",
+                "--eq-band",
+                "peaking:1000:2:1.4",
+            ],
+        );
+        assert_eq!(given.eq.bands.len(), 2);
+        assert_eq!(given.eq.bands[0].kind, FilterKind::LowShelf);
+        assert_eq!(given.eq.bands[1], band);
+    }
+}
@@ -0,0 +1,490 @@
+use crate::decode::DecoderBackend;
+use crate::dither::{DitherMode, NoiseShaping};
+use crate::eq::EqSettings;
+use crate::fade::FadeCurve;
+use crate::flow::FlowControl;
+use crate::format::DeviceFormat;
+use crate::limiter::LimiterMode;
+use crate::link::MAX_RECONNECT_TIMEOUT;
+use crate::live::{LiveInput, MAX_JITTER_BUFFER};
+use crate::loudness::{GainMode, MAX_PREAMP};
+use crate::player::{AudioFile, AudioPlayer, DEFAULT_MAX_BOOST, MAX_FADE, RepeatMode};
+use crate::ports::DacMatch;
+use crate::resample::ResampleQuality;
+use crate::sink::{Protocol, SinkKind};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Bumped whenever a setting is renamed or moved; `migrate` brings older files
+// up to date. New settings don't need a bump, they start at their defaults.
+pub const CONFIG_VERSION: u32 = 2;
+
+// Settings kept in `config.toml` in the user's config directory, shared by the
+// window and the command line. Every field has a default, so a missing file
+// or section changes nothing.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub version: u32,
+    // How to recognise the DAC among the serial ports.
+    pub dac: DacMatch,
+    pub output: OutputSettings,
+    pub playback: PlaybackSettings,
+    // What the window was playing when it closed.
+    pub session: SessionState,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            dac: DacMatch::default(),
+            output: OutputSettings::default(),
+            playback: PlaybackSettings::default(),
+            session: SessionState::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutputSettings {
+    pub sink: SinkKind,
+    // Left out to pick the DAC by its USB descriptors.
+    pub port: Option<String>,
+    pub path: Option<String>,
+    pub protocol: Protocol,
+}
+
+impl Default for OutputSettings {
+    fn default() -> Self {
+        Self {
+            sink: SinkKind::Serial,
+            port: None,
+            path: None,
+            protocol: Protocol::Raw,
+        }
+    }
+}
+
+impl OutputSettings {
+    // The port or file the sink opens.
+    pub fn target(&self) -> &str {
+        let target = if self.sink == SinkKind::Serial {
+            &self.port
+        } else {
+            &self.path
+        };
+        target.as_deref().unwrap_or("")
+    }
+}
+
+// Everything the user can tune on the player.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlaybackSettings {
+    pub volume: f32,
+    pub muted: bool,
+    pub max_boost: f32,
+    pub format: DeviceFormat,
+    pub flow_control: FlowControl,
+    pub decoder: DecoderBackend,
+    pub resampler: ResampleQuality,
+    pub live_input: LiveInput,
+    pub repeat: RepeatMode,
+    pub shuffle: bool,
+    pub fade_in: f32,
+    pub fade_out: f32,
+    pub fade_curve: FadeCurve,
+    pub crossfade: f32,
+    pub crossfade_curve: FadeCurve,
+    pub gain_mode: GainMode,
+    pub preamp: f32,
+    pub clip_prevention: bool,
+    pub limiter: LimiterMode,
+    pub dither: DitherMode,
+    pub noise_shaping: NoiseShaping,
+    pub eq: EqSettings,
+    pub resume_after_reconnect: bool,
+    pub reconnect_timeout: f32,
+}
+
+impl Default for PlaybackSettings {
+    fn default() -> Self {
+        Self::from_player(&AudioPlayer::default())
+    }
+}
+
+impl PlaybackSettings {
+    pub fn from_player(player: &AudioPlayer) -> Self {
+        Self {
+            volume: player.volume,
+            muted: player.muted,
+            max_boost: player.max_boost,
+            format: player.format,
+            flow_control: player.flow_control,
+            decoder: player.decoder,
+            resampler: player.resampler,
+            live_input: player.live_input,
+            repeat: player.repeat,
+            shuffle: player.shuffle,
+            fade_in: player.fade_in,
+            fade_out: player.fade_out,
+            fade_curve: player.fade_curve,
+            crossfade: player.crossfade,
+            crossfade_curve: player.crossfade_curve,
+            gain_mode: player.gain_mode,
+            preamp: player.preamp,
+            clip_prevention: player.clip_prevention,
+            limiter: player.limiter,
+            dither: player.dither,
+            noise_shaping: player.noise_shaping,
+            eq: player.eq.clone(),
+            resume_after_reconnect: player.resume_after_reconnect,
+            reconnect_timeout: player.reconnect_timeout,
+        }
+    }
+
+    pub fn apply_to(&self, player: &mut AudioPlayer) {
+        player.max_boost = within(self.max_boost, 0.0, 2.0, DEFAULT_MAX_BOOST);
+        player.volume = within(
+            self.volume,
+            0.0,
+            player.max_boost,
+            player.max_boost.min(1.0),
+        );
+        player.muted = self.muted;
+        player.format = match self.format.validate() {
+            Ok(()) => self.format,
+            Err(e) => {
+                eprintln!("Ignoring output format {}: {}", self.format, e);
+                DeviceFormat::default()
+            }
+        };
+        player.flow_control = self.flow_control;
+        player.decoder = self.decoder;
+        player.resampler = self.resampler;
+        player.live_input = LiveInput {
+            jitter_buffer: seconds(self.live_input.jitter_buffer, MAX_JITTER_BUFFER),
+            ..self.live_input
+        };
+        player.repeat = self.repeat;
+        player.shuffle = self.shuffle;
+        player.fade_in = seconds(self.fade_in, MAX_FADE);
+        player.fade_out = seconds(self.fade_out, MAX_FADE);
+        player.fade_curve = self.fade_curve;
+        player.crossfade = seconds(self.crossfade, MAX_FADE);
+        player.crossfade_curve = self.crossfade_curve;
+        player.gain_mode = self.gain_mode;
+        player.preamp = within(self.preamp, -MAX_PREAMP, MAX_PREAMP, 0.0);
+        player.clip_prevention = self.clip_prevention;
+        player.limiter = self.limiter;
+        player.dither = self.dither;
+        player.noise_shaping = self.noise_shaping;
+        player.eq = self.eq.clone();
+        player.resume_after_reconnect = self.resume_after_reconnect;
+        player.reconnect_timeout = seconds(self.reconnect_timeout, MAX_RECONNECT_TIMEOUT);
+    }
+}
+
+// A value from a hand-edited file, kept within what the player copes with.
+fn within(value: f32, min: f32, max: f32, fallback: f32) -> f32 {
+    if value.is_nan() {
+        return fallback;
+    }
+    value.clamp(min, max)
+}
+
+// A length of time, in seconds.
+fn seconds(value: f32, max: f32) -> f32 {
+    within(value, 0.0, max, 0.0)
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionState {
+    // The track that was playing, and how far into it.
+    pub current: Option<PathBuf>,
+    pub position: f32,
+    pub queue: Vec<PathBuf>,
+}
+
+impl SessionState {
+    pub fn from_player(player: &AudioPlayer) -> Self {
+        let current = if player.is_playing {
+            player
+                .current_file
+                .as_ref()
+                .map(|file| (file.clone(), player.current_duration))
+        } else {
+            player.resume.clone()
+        };
+        Self {
+            position: current.as_ref().map_or(0.0, |(_, position)| *position),
+            current: current.map(|(file, _)| PathBuf::from(file.path)),
+            queue: player
+                .queue
+                .iter()
+                .map(|f| PathBuf::from(&f.path))
+                .collect(),
+        }
+    }
+
+    // Queues the saved tracks again, skipping any that have gone away since.
+    pub fn apply_to(&self, player: &mut AudioPlayer) {
+        player.resume = self
+            .current
+            .as_ref()
+            .filter(|path| path.exists())
+            .map(|path| (AudioFile::from_path(path), self.position));
+        player.queue = self
+            .queue
+            .iter()
+            .filter(|path| path.exists())
+            .map(|path| AudioFile::from_path(path))
+            .collect();
+    }
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("feed").join("config.toml"))
+}
+
+// Steps from each older version to the next, indexed by the version they
+// start from.
+const MIGRATIONS: [fn(&mut toml::Table); CONFIG_VERSION as usize] = [
+    // 0: the file only held the `[dac]` section, which is unchanged.
+    |_| {},
+    // 1: the sample rate moved into the device format.
+    |table| {
+        let Some(toml::Value::Table(playback)) = table.get_mut("playback") else {
+            return;
+        };
+        if let Some(rate) = playback.remove("sample_rate") {
+            let mut format = toml::Table::new();
+            format.insert("sample_rate".to_string(), rate);
+            playback.insert("format".to_string(), toml::Value::Table(format));
+        }
+    },
+];
+
+fn migrate(table: &mut toml::Table) -> Result<(), String> {
+    let version = match table.get("version") {
+        None => 0,
+        Some(toml::Value::Integer(version)) if *version >= 0 => *version as u32,
+        Some(other) => return Err(format!("invalid version {}", other)),
+    };
+    if version > CONFIG_VERSION {
+        eprintln!(
+            "Config version {} is newer than this build understands ({}); unknown settings are ignored",
+            version, CONFIG_VERSION
+        );
+    }
+    for step in MIGRATIONS.iter().skip(version as usize) {
+        step(table);
+    }
+    table.insert(
+        "version".to_string(),
+        toml::Value::Integer(CONFIG_VERSION.max(version) as i64),
+    );
+    Ok(())
+}
+
+impl Config {
+    pub fn load_from(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let parse = || -> Result<Self, Box<dyn std::error::Error>> {
+            let mut table: toml::Table = toml::from_str(&text)?;
+            migrate(&mut table)?;
+            Ok(toml::Value::Table(table).try_into()?)
+        };
+        parse().map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    // The user's config, falling back to the defaults when it can't be read.
+    // A file that doesn't parse is moved aside rather than overwritten later.
+    pub fn load() -> Self {
+        let Some(path) = config_path() else {
+            return Self::default();
+        };
+        Self::load_from(&path).unwrap_or_else(|e| {
+            let backup = path.with_extension("toml.bak");
+            match fs::rename(&path, &backup) {
+                Ok(()) => eprintln!("Ignoring config, moved to {}: {}", backup.display(), e),
+                Err(_) => eprintln!("Ignoring config: {}", e),
+            }
+            Self::default()
+        })
+    }
+
+    pub fn save_to(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Written next to the real file and renamed over it, so a crash
+        // halfway through can't leave a truncated config behind.
+        let temp = path.with_extension("toml.tmp");
+        fs::write(&temp, toml::to_string_pretty(self)?)?;
+        fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let path = config_path().ok_or("no config directory")?;
+        self.save_to(&path)
+    }
+
+    // Back to the defaults, keeping what describes the hardware and the
+    // session.
+    pub fn reset(&mut self) {
+        *self = Self {
+            dac: self.dac.clone(),
+            session: std::mem::take(&mut self.session),
+            ..Self::default()
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("feed-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn missing_values_fall_back_to_the_defaults() {
+        let dir = temp_dir("config");
+        let path = dir.join("config.toml");
+
+        assert_eq!(Config::load_from(&path).unwrap(), Config::default());
+
+        fs::write(&path, "[dac]\npid = 0x5741\nproduct = \"Feed DAC\"\n").unwrap();
+        let config = Config::load_from(&path).unwrap();
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.dac.vid, 0x0483);
+        assert_eq!(config.dac.pid, 0x5741);
+        assert_eq!(config.dac.product.as_deref(), Some("Feed DAC"));
+        assert_eq!(
+            config.dac.manufacturer.as_deref(),
+            Some("STMicroelectronics")
+        );
+        assert_eq!(config.playback, PlaybackSettings::default());
+
+        fs::write(&path, "[dac]\npid = \"oops\"\n").unwrap();
+        assert!(Config::load_from(&path).is_err());
+        fs::write(&path, "version = \"one\"\n").unwrap();
+        assert!(Config::load_from(&path).is_err());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn older_files_are_migrated() {
+        let dir = temp_dir("migrate");
+        let path = dir.join("config.toml");
+        fs::write(
+            &path,
+            "version = 1\n[playback]\nsample_rate = 48000\nvolume = 0.5\n",
+        )
+        .unwrap();
+        let config = Config::load_from(&path).unwrap();
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.playback.format.sample_rate, 48000);
+        assert_eq!(config.playback.format.channels, 2);
+        assert_eq!(config.playback.volume, 0.5);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn settings_survive_a_round_trip() {
+        let dir = temp_dir("settings");
+        let path = dir.join("feed").join("config.toml");
+
+        let mut player = AudioPlayer {
+            volume: 0.5,
+            muted: true,
+            limiter: LimiterMode::Off,
+            repeat: RepeatMode::All,
+            crossfade: 2.5,
+            ..Default::default()
+        };
+        player.eq.enabled = true;
+        player.eq.bands.push("peaking:1000:-3".parse().unwrap());
+        player.queue.push_back(AudioFile::from_path(&path));
+
+        let config = Config {
+            output: OutputSettings {
+                sink: SinkKind::Wav,
+                path: Some("out.wav".to_string()),
+                ..Default::default()
+            },
+            playback: PlaybackSettings::from_player(&player),
+            session: SessionState::from_player(&player),
+            ..Default::default()
+        };
+        config.save_to(&path).unwrap();
+        let loaded = Config::load_from(&path).unwrap();
+        assert_eq!(loaded, config);
+
+        let mut restored = AudioPlayer::default();
+        loaded.playback.apply_to(&mut restored);
+        loaded.session.apply_to(&mut restored);
+        assert_eq!(PlaybackSettings::from_player(&restored), config.playback);
+        assert_eq!(restored.queue, player.queue);
+        assert_eq!(loaded.output.target(), "out.wav");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn out_of_range_settings_are_clamped() {
+        let mut settings = PlaybackSettings {
+            fade_in: -1.0,
+            fade_out: f32::NAN,
+            crossfade: 1000.0,
+            reconnect_timeout: f32::INFINITY,
+            ..Default::default()
+        };
+        settings.live_input.jitter_buffer = -0.5;
+        settings.format.channels = 0;
+        settings.volume = f32::NAN;
+        settings.max_boost = f32::NAN;
+        settings.preamp = 1e9;
+        let mut player = AudioPlayer::default();
+        settings.apply_to(&mut player);
+        assert_eq!(player.fade_in, 0.0);
+        assert_eq!(player.fade_out, 0.0);
+        assert_eq!(player.crossfade, MAX_FADE);
+        assert_eq!(player.reconnect_timeout, MAX_RECONNECT_TIMEOUT);
+        assert_eq!(player.live_input.jitter_buffer, 0.0);
+        assert_eq!(player.format, DeviceFormat::default());
+        assert_eq!(player.volume, 1.0);
+        assert_eq!(player.max_boost, DEFAULT_MAX_BOOST);
+        assert_eq!(player.preamp, MAX_PREAMP);
+    }
+
+    #[test]
+    fn reset_keeps_the_device_and_the_queue() {
+        let mut config = Config::default();
+        config.dac.pid = 0x1234;
+        config.playback.volume = 0.1;
+        config.output.port = Some("/dev/ttyACM3".to_string());
+        config.session.queue.push(PathBuf::from("a.flac"));
+
+        config.reset();
+        assert_eq!(config.dac.pid, 0x1234);
+        assert_eq!(config.session.queue, [PathBuf::from("a.flac")]);
+        assert_eq!(config.playback, PlaybackSettings::default());
+        assert_eq!(config.output, OutputSettings::default());
+    }
+}
@@ -0,0 +1,956 @@
+use crate::loudness::ReplayGain;
+use crate::resample::{self, ResampleQuality};
+use crate::ring::RingBuffer;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{CODEC_TYPE_NULL, DecoderOptions};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, StandardTagKey, Tag};
+use symphonia::core::probe::Hint;
+use symphonia::core::units::{Time, TimeBase};
+
+// How much decoded audio is kept ahead of the writer.
+const BUFFER_SECONDS: usize = 2;
+
+// Called from another thread to make a stalled `decode` give up.
+pub type Interrupt = Box<dyn FnOnce() + Send>;
+
+// Produces interleaved stereo f32 samples at `sample_rate()`.
+pub trait Decoder: Send {
+    fn sample_rate(&self) -> u32;
+    fn duration(&self) -> Option<f32>;
+
+    // Appends the next block of samples to `out`. Returns false once the
+    // stream is exhausted.
+    fn decode(&mut self, out: &mut Vec<f32>) -> Result<bool, Box<dyn std::error::Error>>;
+
+    // Continues decoding from `seconds` into the track.
+    fn seek(&mut self, seconds: f32) -> Result<(), Box<dyn std::error::Error>>;
+
+    // Loudness tags found in the file.
+    fn replay_gain(&self) -> ReplayGain {
+        ReplayGain::default()
+    }
+
+    // Some for a source that may never deliver, such as live input. A
+    // decode thread reading one is interrupted rather than waited for.
+    fn interrupt(&self) -> Option<Interrupt> {
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DecoderBackend {
+    /// Built-in decoder, falling back to ffmpeg for formats it can't handle
+    #[default]
+    Auto,
+    /// Built-in decoder only
+    Native,
+    /// Always decode with an ffmpeg binary from PATH
+    Ffmpeg,
+}
+
+pub fn open(
+    path: &str,
+    backend: DecoderBackend,
+) -> Result<Box<dyn Decoder>, Box<dyn std::error::Error>> {
+    match backend {
+        DecoderBackend::Native => Ok(Box::new(NativeDecoder::open(path)?)),
+        DecoderBackend::Ffmpeg => Ok(Box::new(FfmpegDecoder::spawn(path)?)),
+        DecoderBackend::Auto => match NativeDecoder::open(path) {
+            Ok(decoder) => Ok(Box::new(decoder)),
+            Err(native) => match FfmpegDecoder::spawn(path) {
+                Ok(decoder) => Ok(Box::new(decoder)),
+                Err(ffmpeg) => Err(format!("{}; ffmpeg fallback: {}", native, ffmpeg).into()),
+            },
+        },
+    }
+}
+
+pub struct NativeDecoder {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn symphonia::core::codecs::Decoder>,
+    track_id: u32,
+    sample_rate: u32,
+    time_base: Option<TimeBase>,
+    duration: Option<f32>,
+    replay_gain: ReplayGain,
+    buffer: Option<SampleBuffer<f32>>,
+    // Frames still to be dropped to land exactly on a seek target.
+    skip_frames: usize,
+}
+
+impl NativeDecoder {
+    pub fn open(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = File::open(path)?;
+        let mut hint = Hint::new();
+        if let Some(ext) = Path::new(path).extension().and_then(|e| e.to_str()) {
+            hint.with_extension(ext);
+        }
+
+        let mut probed = symphonia::default::get_probe().format(
+            &hint,
+            MediaSourceStream::new(Box::new(file), Default::default()),
+            &FormatOptions {
+                // Trims encoder delay and padding where the container reports it.
+                enable_gapless: true,
+                ..Default::default()
+            },
+            &MetadataOptions::default(),
+        )?;
+        // Tags can sit ahead of the container (ID3v2) or inside it.
+        let mut tags = Vec::new();
+        if let Some(metadata) = probed.metadata.get()
+            && let Some(revision) = metadata.current()
+        {
+            tags.extend_from_slice(revision.tags());
+        }
+        let mut format = probed.format;
+        if let Some(revision) = format.metadata().current() {
+            tags.extend_from_slice(revision.tags());
+        }
+        let tags: Vec<(&str, String)> = tags
+            .iter()
+            .map(|tag| (tag_key(tag), tag.value.to_string()))
+            .collect();
+        let replay_gain = ReplayGain::from_tags(tags.iter().map(|(k, v)| (*k, v.as_str())));
+        let track = format
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or("no audio track")?;
+        let params = &track.codec_params;
+        let sample_rate = params.sample_rate.ok_or("unknown sample rate")?;
+        let duration = params
+            .n_frames
+            .map(|frames| frames as f32 / sample_rate as f32);
+        let decoder = symphonia::default::get_codecs().make(params, &DecoderOptions::default())?;
+        let time_base = params.time_base;
+
+        Ok(Self {
+            track_id: track.id,
+            format,
+            decoder,
+            sample_rate,
+            time_base,
+            duration,
+            replay_gain,
+            buffer: None,
+            skip_frames: 0,
+        })
+    }
+}
+
+impl Decoder for NativeDecoder {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn duration(&self) -> Option<f32> {
+        self.duration
+    }
+
+    fn decode(&mut self, out: &mut Vec<f32>) -> Result<bool, Box<dyn std::error::Error>> {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(e))
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    return Ok(false);
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                // A corrupt packet only costs its own samples.
+                Err(SymphoniaError::DecodeError(_)) => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            let spec = *decoded.spec();
+            let needed = decoded.capacity() * spec.channels.count();
+            if self.buffer.as_ref().is_none_or(|b| b.capacity() < needed) {
+                self.buffer = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+            }
+            let buffer = self.buffer.as_mut().unwrap();
+            buffer.copy_interleaved_ref(decoded);
+
+            let start = out.len();
+            push_stereo(buffer.samples(), spec.channels.count(), out);
+            if self.skip_frames > 0 {
+                let skipped = self.skip_frames.min((out.len() - start) / 2);
+                out.drain(start..start + skipped * 2);
+                self.skip_frames -= skipped;
+            }
+            return Ok(true);
+        }
+    }
+
+    fn seek(&mut self, seconds: f32) -> Result<(), Box<dyn std::error::Error>> {
+        let seeked = self.format.seek(
+            SeekMode::Accurate,
+            SeekTo::Time {
+                time: Time::from(seconds.max(0.0)),
+                track_id: Some(self.track_id),
+            },
+        )?;
+        self.decoder.reset();
+
+        // The reader lands on a packet boundary at or before the target.
+        let early = seeked.required_ts.saturating_sub(seeked.actual_ts);
+        self.skip_frames = match self.time_base {
+            Some(time_base) => {
+                let time = time_base.calc_time(early);
+                ((time.seconds as f64 + time.frac) * self.sample_rate as f64).round() as usize
+            }
+            None => early as usize,
+        };
+        Ok(())
+    }
+
+    fn replay_gain(&self) -> ReplayGain {
+        self.replay_gain
+    }
+}
+
+// Some containers have their ReplayGain keys recognised and renamed.
+fn tag_key(tag: &Tag) -> &str {
+    match tag.std_key {
+        Some(StandardTagKey::ReplayGainTrackGain) => "REPLAYGAIN_TRACK_GAIN",
+        Some(StandardTagKey::ReplayGainTrackPeak) => "REPLAYGAIN_TRACK_PEAK",
+        Some(StandardTagKey::ReplayGainAlbumGain) => "REPLAYGAIN_ALBUM_GAIN",
+        Some(StandardTagKey::ReplayGainAlbumPeak) => "REPLAYGAIN_ALBUM_PEAK",
+        _ => &tag.key,
+    }
+}
+
+// Mono is duplicated to both sides; anything beyond the front pair is dropped.
+fn push_stereo(samples: &[f32], channels: usize, out: &mut Vec<f32>) {
+    match channels {
+        0 => {}
+        1 => out.extend(samples.iter().flat_map(|&s| [s, s])),
+        2 => out.extend_from_slice(samples),
+        _ => out.extend(samples.chunks_exact(channels).flat_map(|f| [f[0], f[1]])),
+    }
+}
+
+pub struct FfmpegDecoder {
+    // None when ffmpeg reads a pipe, which can't be started over.
+    path: Option<String>,
+    // Shared so a pipe can be shut down while `decode` waits on it.
+    child: Arc<Mutex<Child>>,
+    stdout: ChildStdout,
+    sample_rate: u32,
+    duration: Option<f32>,
+    replay_gain: ReplayGain,
+    pending: Vec<u8>,
+}
+
+// Rate ffmpeg is asked for when ffprobe can't tell the source rate.
+const FFMPEG_FALLBACK_RATE: u32 = 48000;
+
+impl FfmpegDecoder {
+    // Decodes at the source rate when ffprobe reports one, so rate conversion
+    // stays with our own resampler.
+    pub fn spawn(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let info = probe(path);
+        let sample_rate = info.sample_rate.unwrap_or(FFMPEG_FALLBACK_RATE);
+        let (child, stdout) = Self::start(path, Stdio::null(), sample_rate, 0.0)?;
+
+        Ok(Self {
+            path: Some(path.to_string()),
+            child: Arc::new(Mutex::new(child)),
+            stdout,
+            sample_rate,
+            duration: info.duration,
+            replay_gain: ReplayGain::from_tags(
+                info.tags.iter().map(|(k, v)| (k.as_str(), v.as_str())),
+            ),
+            pending: Vec::new(),
+        })
+    }
+
+    // Decodes whatever arrives on `url`, `pipe:0` being `input`. Nothing can
+    // be probed ahead of time, so it comes out at the fallback rate.
+    pub fn spawn_pipe(url: &str, input: Stdio) -> Result<Self, Box<dyn std::error::Error>> {
+        let sample_rate = FFMPEG_FALLBACK_RATE;
+        let (child, stdout) = Self::start(url, input, sample_rate, 0.0)?;
+        Ok(Self {
+            path: None,
+            child: Arc::new(Mutex::new(child)),
+            stdout,
+            sample_rate,
+            duration: None,
+            replay_gain: ReplayGain::default(),
+            pending: Vec::new(),
+        })
+    }
+
+    fn start(
+        path: &str,
+        stdin: Stdio,
+        sample_rate: u32,
+        offset: f32,
+    ) -> Result<(Child, ChildStdout), Box<dyn std::error::Error>> {
+        let offset_arg = format!("{:.3}", offset.max(0.0));
+        let sample_rate_arg = sample_rate.to_string();
+        let mut child = Command::new("ffmpeg")
+            .args([
+                "-ss",
+                &offset_arg,
+                "-i",
+                path,
+                "-ar",
+                &sample_rate_arg,
+                "-ac",
+                "2",
+                "-f",
+                "f32le",
+                "-acodec",
+                "pcm_f32le",
+                "-hide_banner",
+                "-loglevel",
+                "error",
+                "pipe:1",
+            ])
+            .stdin(stdin)
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("failed to start ffmpeg: {}", e))?;
+        let stdout = child.stdout.take().ok_or("ffmpeg has no stdout")?;
+        Ok((child, stdout))
+    }
+
+    fn stop(&mut self) {
+        kill(&self.child);
+    }
+}
+
+fn kill(child: &Mutex<Child>) {
+    let mut child = child.lock().unwrap();
+    if let Ok(None) = child.try_wait() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+impl Decoder for FfmpegDecoder {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn duration(&self) -> Option<f32> {
+        self.duration
+    }
+
+    fn decode(&mut self, out: &mut Vec<f32>) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut buf = [0u8; 8192];
+        let n = self.stdout.read(&mut buf)?;
+        if n == 0 {
+            let status = self.child.lock().unwrap().wait()?;
+            if !status.success() {
+                return Err("ffmpeg conversion failed".into());
+            }
+            return Ok(false);
+        }
+
+        self.pending.extend_from_slice(&buf[..n]);
+        let whole = self.pending.len() - self.pending.len() % 4;
+        out.extend(
+            self.pending[..whole]
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        );
+        self.pending.drain(..whole);
+        Ok(true)
+    }
+
+    // ffmpeg can't be steered once it's running, so start a new one at the
+    // offset.
+    fn seek(&mut self, seconds: f32) -> Result<(), Box<dyn std::error::Error>> {
+        let path = self.path.clone().ok_or("can't seek in a pipe")?;
+        self.stop();
+        let (child, stdout) = Self::start(&path, Stdio::null(), self.sample_rate, seconds)?;
+        self.child = Arc::new(Mutex::new(child));
+        self.stdout = stdout;
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn replay_gain(&self) -> ReplayGain {
+        self.replay_gain
+    }
+
+    // A file always comes to an end, but a pipe may wait on its writer.
+    fn interrupt(&self) -> Option<Interrupt> {
+        if self.path.is_some() {
+            return None;
+        }
+        let child = Arc::clone(&self.child);
+        Some(Box::new(move || kill(&child)))
+    }
+}
+
+impl Drop for FfmpegDecoder {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[derive(Default)]
+pub struct ProbeInfo {
+    pub duration: Option<f32>,
+    pub sample_rate: Option<u32>,
+    pub tags: Vec<(String, String)>,
+}
+
+pub fn probe(path: &str) -> ProbeInfo {
+    let mut info = ProbeInfo::default();
+    let output = match Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "a:0",
+            "-show_entries",
+            "format=duration:format_tags:stream=sample_rate:stream_tags",
+            "-of",
+            "default=noprint_wrappers=1",
+            path,
+        ])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+    {
+        Ok(output) if output.status.success() => output,
+        _ => return info,
+    };
+
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        match line.split_once('=') {
+            Some(("duration", value)) => info.duration = value.trim().parse().ok(),
+            Some(("sample_rate", value)) => info.sample_rate = value.trim().parse().ok(),
+            Some((key, value)) if key.starts_with("TAG:") => {
+                info.tags.push((key[4..].to_string(), value.to_string()))
+            }
+            _ => {}
+        }
+    }
+    info
+}
+
+// Interleaved stereo at the device rate, produced on a background thread and
+// handed over through a bounded ring so memory use doesn't depend on the
+// track length. Once a decoder runs dry the thread asks for the next one, so
+// consecutive tracks come out of the same ring without a gap.
+pub struct DecodeStream {
+    ring: Arc<RingBuffer<f32>>,
+    handoff: Arc<Handoff>,
+    next: Option<Sender<Option<Box<dyn Decoder>>>>,
+    duration: Option<f32>,
+    position: u64,
+    jitter: Option<Jitter>,
+    // One for each live decoder handed to the thread.
+    interrupts: Vec<Interrupt>,
+    thread: Option<JoinHandle<Result<(), String>>>,
+}
+
+// Slack for a source that delivers in real time: playback holds off until
+// `target` samples are buffered, and again whenever the source falls behind,
+// sending silence meanwhile so the device keeps its pace.
+struct Jitter {
+    target: usize,
+    filling: bool,
+}
+
+pub enum Chunk {
+    Audio(usize),
+    // Everything before this point belonged to the previous track.
+    TrackChange,
+    End,
+}
+
+#[derive(Default)]
+struct Handoff {
+    state: Mutex<HandoffState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct HandoffState {
+    // The decode thread has run out of input and waits for `append`.
+    waiting: bool,
+    // A decoder was appended but its start hasn't been recorded yet.
+    expecting: bool,
+    done: bool,
+    // Sample positions where appended tracks begin.
+    boundaries: VecDeque<u64>,
+}
+
+impl DecodeStream {
+    pub fn spawn(decoder: Box<dyn Decoder>, device_rate: u32, quality: ResampleQuality) -> Self {
+        let duration = decoder.duration();
+        let interrupts = decoder.interrupt().into_iter().collect();
+        let ring = Arc::new(RingBuffer::new(device_rate as usize * 2 * BUFFER_SECONDS));
+        let handoff = Arc::new(Handoff::default());
+        let (next, next_decoders) = mpsc::channel();
+        let thread = {
+            let ring = Arc::clone(&ring);
+            let handoff = Arc::clone(&handoff);
+            thread::spawn(move || {
+                let result = pump(
+                    decoder,
+                    next_decoders,
+                    device_rate,
+                    quality,
+                    &ring,
+                    &handoff,
+                )
+                .map_err(|e| e.to_string());
+                ring.finish();
+                handoff.state.lock().unwrap().done = true;
+                handoff.changed.notify_all();
+                result
+            })
+        };
+
+        Self {
+            ring,
+            handoff,
+            next: Some(next),
+            duration,
+            position: 0,
+            jitter: None,
+            interrupts,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn duration(&self) -> Option<f32> {
+        self.duration
+    }
+
+    // True while the decode thread waits to hear what follows the last
+    // decoder it was given.
+    pub fn wants_next(&self) -> bool {
+        self.handoff.state.lock().unwrap().waiting
+    }
+
+    // Queues the decoder to continue with, or None to end the stream.
+    pub fn append(&mut self, decoder: Option<Box<dyn Decoder>>) {
+        {
+            let mut state = self.handoff.state.lock().unwrap();
+            state.waiting = false;
+            state.expecting = decoder.is_some();
+        }
+        if let Some(decoder) = &decoder {
+            self.interrupts.extend(decoder.interrupt());
+        }
+        if let Some(next) = self.next.as_ref() {
+            let _ = next.send(decoder);
+        }
+    }
+
+    // Buffers `samples` of what follows before playing it, for a live source
+    // whose arrival can't be read ahead. Lasts until that source runs out.
+    pub fn set_jitter_buffer(&mut self, samples: usize) {
+        // A target the ring can't hold would never be reached.
+        let target = samples.min(self.ring.capacity() / 2);
+        self.jitter = (target > 0).then_some(Jitter {
+            target,
+            filling: true,
+        });
+    }
+
+    // Blocks until `buf` is full, a track boundary is reached or the decoder
+    // is done. A live source that is behind yields silence instead.
+    pub fn read(&mut self, buf: &mut [f32]) -> Chunk {
+        if self.jitter.is_some() {
+            if self.ring.is_finished() || self.wants_next() {
+                self.jitter = None;
+            } else if let Some(jitter) = self.jitter.as_mut() {
+                let buffered = self.ring.len();
+                // Reading more than is there would stall the device.
+                jitter.filling |= buffered < buf.len();
+                if jitter.filling && buffered < jitter.target.max(buf.len()) {
+                    buf.fill(0.0);
+                    return Chunk::Audio(buf.len());
+                }
+                jitter.filling = false;
+            }
+        }
+
+        let limit = {
+            let mut state = self.handoff.state.lock().unwrap();
+            while state.expecting && state.boundaries.is_empty() && !state.done {
+                state = self.handoff.changed.wait(state).unwrap();
+            }
+            match state.boundaries.front() {
+                Some(&boundary) if boundary == self.position => {
+                    state.boundaries.pop_front();
+                    return Chunk::TrackChange;
+                }
+                Some(&boundary) => ((boundary - self.position) as usize).min(buf.len()),
+                None => buf.len(),
+            }
+        };
+
+        // An empty read from a stalled ring means the decode thread is
+        // waiting for `append`. It may have pushed more and finished since,
+        // so the stream is only over once a finished ring is also empty.
+        let len = self.ring.pop(&mut buf[..limit]);
+        self.position += len as u64;
+        if len == 0 && self.ring.is_finished() && self.ring.is_empty() {
+            Chunk::End
+        } else {
+            Chunk::Audio(len)
+        }
+    }
+
+    // Waits for the decoder to exit and reports whether it succeeded.
+    pub fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result.map_err(Into::into),
+            Some(Err(_)) => Err("decoder thread panicked".into()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for DecodeStream {
+    fn drop(&mut self) {
+        self.ring.cancel();
+        // Hanging up releases a decode thread waiting for the next track.
+        self.next = None;
+        // Live input may never come back from a read, so its thread is woken
+        // where that can be done and otherwise left to exit on its own.
+        let live = !self.interrupts.is_empty();
+        for interrupt in self.interrupts.drain(..) {
+            interrupt();
+        }
+        if !live && let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn pump(
+    mut decoder: Box<dyn Decoder>,
+    next_decoders: Receiver<Option<Box<dyn Decoder>>>,
+    device_rate: u32,
+    quality: ResampleQuality,
+    ring: &RingBuffer<f32>,
+    handoff: &Handoff,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let new_resampler =
+        |rate: u32| (rate != device_rate).then(|| resample::new(quality, rate, device_rate));
+    let mut rate = decoder.sample_rate();
+    let mut resampler = new_resampler(rate);
+    let mut decoded = Vec::new();
+    let mut resampled = Vec::new();
+    let mut pushed = 0u64;
+
+    let push = |samples: &[f32], pushed: &mut u64| {
+        *pushed += samples.len() as u64;
+        ring.push(samples)
+    };
+
+    loop {
+        decoded.clear();
+        let more = decoder.decode(&mut decoded)?;
+        let samples = match resampler.as_mut() {
+            Some(resampler) => {
+                resampled.clear();
+                resampler.process(&decoded, &mut resampled);
+                &resampled
+            }
+            None => &decoded,
+        };
+        if !push(samples, &mut pushed) {
+            return Ok(());
+        }
+        if more {
+            continue;
+        }
+
+        handoff.state.lock().unwrap().waiting = true;
+        ring.set_stalled(true);
+        let next = next_decoders.recv().ok().flatten();
+        ring.set_stalled(false);
+
+        // The resampler carries on into the next track when the rates match,
+        // so its filter state bridges the splice.
+        let rate_changes = next.as_ref().is_none_or(|next| next.sample_rate() != rate);
+        if rate_changes && let Some(resampler) = resampler.as_mut() {
+            resampled.clear();
+            resampler.flush(&mut resampled);
+            if !push(&resampled, &mut pushed) {
+                return Ok(());
+            }
+        }
+
+        let Some(next) = next else {
+            return Ok(());
+        };
+        if rate_changes {
+            rate = next.sample_rate();
+            resampler = new_resampler(rate);
+        }
+        decoder = next;
+
+        let mut state = handoff.state.lock().unwrap();
+        state.boundaries.push_back(pushed);
+        state.expecting = false;
+        handoff.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{DeviceFormat, SampleEncoding};
+    use crate::test_util;
+
+    const RATE: u32 = 8000;
+
+    // Two seconds of mono where every frame holds its own index.
+    fn ramp_wav(name: &str) -> String {
+        let format = DeviceFormat {
+            sample_rate: RATE,
+            channels: 1,
+            encoding: SampleEncoding::S16le,
+        };
+        let data: Vec<u8> = (0..2 * RATE as i16).flat_map(|n| n.to_le_bytes()).collect();
+        test_util::write_wav(name, &format, &data)
+    }
+
+    fn frame_index(sample: f32) -> i32 {
+        (sample * 32768.0).round() as i32
+    }
+
+    #[test]
+    fn native_seek_is_sample_accurate() {
+        let path = ramp_wav("seek");
+        let mut decoder = NativeDecoder::open(&path).unwrap();
+        assert_eq!(decoder.duration(), Some(2.0));
+
+        decoder.seek(1.25).unwrap();
+        let mut out = Vec::new();
+        while out.is_empty() {
+            assert!(decoder.decode(&mut out).unwrap());
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(frame_index(out[0]), 10000);
+        assert_eq!(out[0], out[1]);
+        assert_eq!(frame_index(out[2]), 10001);
+    }
+
+    // Hands out a fixed signal in small blocks.
+    struct Scripted {
+        rate: u32,
+        samples: Vec<f32>,
+        position: usize,
+    }
+
+    impl Scripted {
+        fn boxed(rate: u32, samples: Vec<f32>) -> Box<dyn Decoder> {
+            Box::new(Self {
+                rate,
+                samples,
+                position: 0,
+            })
+        }
+    }
+
+    impl Decoder for Scripted {
+        fn sample_rate(&self) -> u32 {
+            self.rate
+        }
+
+        fn duration(&self) -> Option<f32> {
+            None
+        }
+
+        fn decode(&mut self, out: &mut Vec<f32>) -> Result<bool, Box<dyn std::error::Error>> {
+            let end = (self.position + 700).min(self.samples.len());
+            out.extend_from_slice(&self.samples[self.position..end]);
+            self.position = end;
+            Ok(self.position < self.samples.len())
+        }
+
+        fn seek(&mut self, _seconds: f32) -> Result<(), Box<dyn std::error::Error>> {
+            Err("not seekable".into())
+        }
+    }
+
+    // Hands out whatever the test sends, like a pipe, until it hangs up.
+    struct Fed(mpsc::Receiver<Vec<f32>>);
+
+    impl Decoder for Fed {
+        fn sample_rate(&self) -> u32 {
+            RATE
+        }
+
+        fn duration(&self) -> Option<f32> {
+            None
+        }
+
+        fn decode(&mut self, out: &mut Vec<f32>) -> Result<bool, Box<dyn std::error::Error>> {
+            match self.0.recv() {
+                Ok(samples) => {
+                    out.extend(samples);
+                    Ok(true)
+                }
+                Err(_) => Ok(false),
+            }
+        }
+
+        fn seek(&mut self, _seconds: f32) -> Result<(), Box<dyn std::error::Error>> {
+            Err("not seekable".into())
+        }
+    }
+
+    #[test]
+    fn jitter_buffer_pads_with_silence_until_it_fills() {
+        // The sender is dropped first so a failed assert doesn't leave the
+        // decode thread waiting on it.
+        let (mut stream, feed) = {
+            let (feed, input) = mpsc::channel();
+            let stream =
+                DecodeStream::spawn(Box::new(Fed(input)), RATE, ResampleQuality::Polyphase);
+            (stream, feed)
+        };
+        stream.set_jitter_buffer(100);
+        let wait_for = |stream: &DecodeStream, len: usize| {
+            while stream.ring.len() < len {
+                thread::sleep(std::time::Duration::from_millis(1));
+            }
+        };
+        let mut buf = [0.5f32; 10];
+
+        assert!(matches!(stream.read(&mut buf), Chunk::Audio(10)));
+        assert_eq!(buf, [0.0; 10]);
+        feed.send(vec![1.0; 150]).unwrap();
+        wait_for(&stream, 150);
+        for _ in 0..15 {
+            assert!(matches!(stream.read(&mut buf), Chunk::Audio(10)));
+            assert_eq!(buf, [1.0; 10]);
+        }
+
+        // Ran dry, so it holds off again until the buffer is back up.
+        assert!(matches!(stream.read(&mut buf), Chunk::Audio(10)));
+        assert_eq!(buf, [0.0; 10]);
+        feed.send(vec![1.0; 50]).unwrap();
+        wait_for(&stream, 50);
+        assert!(matches!(stream.read(&mut buf), Chunk::Audio(10)));
+        assert_eq!(buf, [0.0; 10]);
+
+        // Once the source ends, what's left plays out.
+        drop(feed);
+        while !stream.wants_next() {
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+        stream.append(None);
+        while !stream.ring.is_finished() {
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+        let mut played = 0;
+        while let Chunk::Audio(len) = stream.read(&mut buf) {
+            assert_eq!(buf[..len], vec![1.0; len][..]);
+            played += len;
+        }
+        assert_eq!(played, 50);
+        stream.finish().unwrap();
+    }
+
+    // Plays `first` followed by `rest` the way the player does and returns
+    // the samples along with the positions where the track changed.
+    fn splice(
+        first: Box<dyn Decoder>,
+        rest: Vec<Box<dyn Decoder>>,
+        device_rate: u32,
+    ) -> (Vec<f32>, Vec<usize>) {
+        let mut rest = rest.into_iter();
+        let mut stream = DecodeStream::spawn(first, device_rate, ResampleQuality::Polyphase);
+        let mut out = Vec::new();
+        let mut changes = Vec::new();
+        let mut buf = [0f32; 512];
+        loop {
+            if stream.wants_next() {
+                stream.append(rest.next());
+            }
+            match stream.read(&mut buf) {
+                Chunk::Audio(len) => out.extend_from_slice(&buf[..len]),
+                Chunk::TrackChange => changes.push(out.len()),
+                Chunk::End => break,
+            }
+        }
+        stream.finish().unwrap();
+        (out, changes)
+    }
+
+    #[test]
+    fn consecutive_tracks_are_spliced_without_a_gap() {
+        // Numbered stereo frames, the right channel negated so a frame split
+        // across tracks would show.
+        let frames = |range: std::ops::Range<usize>| {
+            range
+                .flat_map(|n| [n as f32, -(n as f32)])
+                .collect::<Vec<_>>()
+        };
+        let (out, changes) = splice(
+            Scripted::boxed(RATE, frames(0..1500)),
+            vec![
+                Scripted::boxed(RATE, frames(1500..2501)),
+                Scripted::boxed(RATE, frames(2501..3000)),
+            ],
+            RATE,
+        );
+
+        assert_eq!(out, frames(0..3000));
+        let changed_at: Vec<f32> = changes.iter().map(|&n| n as f32 / 2.0).collect();
+        assert_eq!(changed_at, [1500.0, 2501.0]);
+    }
+
+    #[test]
+    fn resampled_splice_matches_one_continuous_track() {
+        let sine = |range: std::ops::Range<usize>| {
+            range
+                .flat_map(|n| {
+                    let s = (n as f32 * 0.05).sin();
+                    [s, s]
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let (whole, _) = splice(Scripted::boxed(44100, sine(0..20000)), Vec::new(), 46875);
+        let (spliced, changes) = splice(
+            Scripted::boxed(44100, sine(0..12345)),
+            vec![Scripted::boxed(44100, sine(12345..20000))],
+            46875,
+        );
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(whole.len(), spliced.len());
+        for (a, b) in whole.iter().zip(&spliced) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+}
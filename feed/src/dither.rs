@@ -0,0 +1,326 @@
+use crate::format::DeviceFormat;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+// Feedback is clamped to this many LSBs so a clipped sample can't throw a
+// shaping filter into oscillation.
+const MAX_ERROR: f32 = 4.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DitherMode {
+    /// Round to the nearest step, which leaves distortion correlated with
+    /// the signal on quiet material
+    Off,
+    /// Triangular noise of two LSBs peak to peak, which turns the
+    /// quantization error into a steady, signal-independent hiss
+    #[default]
+    Tpdf,
+}
+
+impl DitherMode {
+    pub const ALL: [DitherMode; 2] = [DitherMode::Off, DitherMode::Tpdf];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            DitherMode::Off => "No dither",
+            DitherMode::Tpdf => "TPDF dither",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NoiseShaping {
+    /// Leave the quantization noise flat
+    #[default]
+    Off,
+    /// First-order highpass, moving noise away from low frequencies
+    FirstOrder,
+    /// Lipshitz's five-tap E-weighted filter, moving noise out of the
+    /// 2-5 kHz region where hearing is most sensitive
+    Lipshitz,
+}
+
+impl NoiseShaping {
+    pub const ALL: [NoiseShaping; 3] = [
+        NoiseShaping::Off,
+        NoiseShaping::FirstOrder,
+        NoiseShaping::Lipshitz,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            NoiseShaping::Off => "No shaping",
+            NoiseShaping::FirstOrder => "First order",
+            NoiseShaping::Lipshitz => "Lipshitz E-weighted",
+        }
+    }
+
+    // Error feedback taps, newest error first.
+    fn taps(self) -> &'static [f32] {
+        match self {
+            NoiseShaping::Off => &[],
+            NoiseShaping::FirstOrder => &[1.0],
+            NoiseShaping::Lipshitz => &[2.033, -2.165, 1.959, -1.590, 0.6149],
+        }
+    }
+}
+
+// Turns interleaved stereo f32 into the device's format. This is the only
+// place samples leave floating point, so every gain stage before it keeps full
+// precision.
+pub struct Requantizer {
+    format: DeviceFormat,
+    dither: DitherMode,
+    shaping: NoiseShaping,
+    rng: SmallRng,
+    // Past quantization errors per channel in LSBs, newest first.
+    errors: [[f32; 5]; 2],
+}
+
+impl Requantizer {
+    pub fn new(format: DeviceFormat, dither: DitherMode, shaping: NoiseShaping) -> Self {
+        Self::with_rng(
+            format,
+            dither,
+            shaping,
+            SmallRng::from_rng(&mut rand::rng()),
+        )
+    }
+
+    fn with_rng(
+        format: DeviceFormat,
+        dither: DitherMode,
+        shaping: NoiseShaping,
+        rng: SmallRng,
+    ) -> Self {
+        Self {
+            format,
+            dither,
+            shaping,
+            rng,
+            errors: [[0.0; 5]; 2],
+        }
+    }
+
+    // Switching filters drops the error history, which belongs to the old one.
+    pub fn configure(&mut self, dither: DitherMode, shaping: NoiseShaping) {
+        if shaping != self.shaping {
+            self.errors = [[0.0; 5]; 2];
+        }
+        self.dither = dither;
+        self.shaping = shaping;
+    }
+
+    pub fn process(&mut self, samples: &[f32], out: &mut Vec<u8>) {
+        let encoding = self.format.encoding;
+        for frame in samples.chunks_exact(2) {
+            if self.format.channels == 1 {
+                let quantized = self.quantize(0, (frame[0] + frame[1]) * 0.5);
+                encoding.write(quantized, out);
+                continue;
+            }
+            for (channel, &sample) in frame.iter().enumerate() {
+                let quantized = self.quantize(channel, sample);
+                encoding.write(quantized, out);
+            }
+        }
+    }
+
+    fn quantize(&mut self, channel: usize, sample: f32) -> i32 {
+        let errors = &mut self.errors[channel];
+        let feedback: f32 = self
+            .shaping
+            .taps()
+            .iter()
+            .zip(errors.iter())
+            .map(|(tap, error)| tap * error)
+            .sum();
+        // In f64, since 32-bit steps are finer than an f32 can resolve
+        // at full scale.
+        let full_scale = self.format.encoding.full_scale();
+        let wanted = sample as f64 * full_scale - feedback as f64;
+        let noise = match self.dither {
+            DitherMode::Off => 0.0,
+            DitherMode::Tpdf => self.rng.random::<f32>() - self.rng.random::<f32>(),
+        };
+        let quantized = (wanted + noise as f64)
+            .round()
+            .clamp(-full_scale, full_scale - 1.0);
+
+        errors.rotate_right(1);
+        errors[0] = ((quantized - wanted) as f32).clamp(-MAX_ERROR, MAX_ERROR);
+        quantized as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::SampleEncoding;
+
+    fn requantizer(dither: DitherMode, shaping: NoiseShaping) -> Requantizer {
+        Requantizer::with_rng(
+            DeviceFormat::default(),
+            dither,
+            shaping,
+            SmallRng::seed_from_u64(7),
+        )
+    }
+
+    fn quantize(requantizer: &mut Requantizer, samples: &[f32]) -> Vec<i16> {
+        let mut bytes = Vec::new();
+        requantizer.process(samples, &mut bytes);
+        bytes
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect()
+    }
+
+    // Quiet stereo sine with the amplitude given in LSBs.
+    fn sine(frames: usize, lsbs: f32) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|n| {
+                let s = lsbs / 32768.0 * (n as f32 * 0.05).sin();
+                [s, s]
+            })
+            .collect()
+    }
+
+    // Left-channel quantization error in LSBs.
+    fn error(input: &[f32], output: &[i16]) -> Vec<f32> {
+        input
+            .iter()
+            .zip(output)
+            .step_by(2)
+            .map(|(&x, &y)| y as f32 - x * 32768.0)
+            .collect()
+    }
+
+    // Error power below a tenth of the sample rate, summed over a spread of
+    // DFT bins.
+    fn low_band_power(error: &[f32]) -> f32 {
+        let n = error.len();
+        (1..n / 20)
+            .step_by(4)
+            .map(|bin| {
+                let w = 2.0 * std::f32::consts::PI * bin as f32 / n as f32;
+                let (re, im) = error
+                    .iter()
+                    .enumerate()
+                    .fold((0.0, 0.0), |(re, im), (i, e)| {
+                        (re + e * (w * i as f32).cos(), im - e * (w * i as f32).sin())
+                    });
+                re * re + im * im
+            })
+            .sum()
+    }
+
+    #[test]
+    fn without_dither_samples_round_to_the_nearest_step() {
+        let mut q = requantizer(DitherMode::Off, NoiseShaping::Off);
+        let lsb = 1.0 / 32768.0;
+        let input = [
+            0.0,
+            1.0,
+            -1.0,
+            2.0,
+            0.4 * lsb,
+            0.6 * lsb,
+            -0.6 * lsb,
+            100.4 * lsb,
+        ];
+        assert_eq!(
+            quantize(&mut q, &input),
+            [0, 32767, -32768, 32767, 0, 1, -1, 100]
+        );
+    }
+
+    #[test]
+    fn tpdf_error_is_bounded_and_unbiased() {
+        let mut q = requantizer(DitherMode::Tpdf, NoiseShaping::Off);
+        let input = sine(20000, 300.0);
+        let error = error(&input, &quantize(&mut q, &input));
+        assert!(error.iter().all(|e| e.abs() <= 1.5));
+        let mean = error.iter().sum::<f32>() / error.len() as f32;
+        assert!(mean.abs() < 0.02, "{}", mean);
+    }
+
+    #[test]
+    fn dither_keeps_signals_below_one_lsb() {
+        let input = sine(20000, 0.4);
+        let correlation =
+            |output: &[i16]| -> f32 { input.iter().zip(output).map(|(&x, &y)| x * y as f32).sum() };
+
+        let rounded = quantize(&mut requantizer(DitherMode::Off, NoiseShaping::Off), &input);
+        assert!(rounded.iter().all(|&s| s == 0));
+
+        let dithered = quantize(
+            &mut requantizer(DitherMode::Tpdf, NoiseShaping::Off),
+            &input,
+        );
+        assert!(correlation(&dithered) > 0.0);
+    }
+
+    #[test]
+    fn shaping_moves_noise_out_of_the_low_band() {
+        let input = sine(4096, 200.0);
+        let flat = {
+            let mut q = requantizer(DitherMode::Tpdf, NoiseShaping::Off);
+            low_band_power(&error(&input, &quantize(&mut q, &input)))
+        };
+        for shaping in [NoiseShaping::FirstOrder, NoiseShaping::Lipshitz] {
+            let mut q = requantizer(DitherMode::Tpdf, shaping);
+            let shaped = low_band_power(&error(&input, &quantize(&mut q, &input)));
+            assert!(shaped < flat / 2.0, "{:?}: {} vs {}", shaping, shaped, flat);
+        }
+    }
+
+    #[test]
+    fn shaping_recovers_from_clipping() {
+        let mut q = requantizer(DitherMode::Tpdf, NoiseShaping::Lipshitz);
+        let mut input: Vec<f32> = (0..2000).flat_map(|_| [1.5, -1.5]).collect();
+        input.extend(sine(4000, 100.0));
+        let output = quantize(&mut q, &input);
+        let settled = error(&input[4000..], &output[4000..]);
+        assert!(settled[100..].iter().all(|e| e.abs() < 20.0));
+    }
+
+    #[test]
+    fn changing_the_filter_clears_its_history() {
+        let mut q = requantizer(DitherMode::Off, NoiseShaping::FirstOrder);
+        quantize(&mut q, &[0.4 / 32768.0, 0.4 / 32768.0]);
+        q.configure(DitherMode::Off, NoiseShaping::Off);
+        assert_eq!(q.errors, [[0.0; 5]; 2]);
+    }
+
+    #[test]
+    fn writes_the_device_encoding() {
+        let format = |channels, encoding| DeviceFormat {
+            channels,
+            encoding,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        let mut q = Requantizer::with_rng(
+            format(1, SampleEncoding::U8),
+            DitherMode::Off,
+            NoiseShaping::Off,
+            SmallRng::seed_from_u64(7),
+        );
+        q.process(&[0.5, 0.0, -1.0, -1.0], &mut out);
+        assert_eq!(out, [160, 0]);
+
+        out.clear();
+        let mut q = Requantizer::with_rng(
+            format(2, SampleEncoding::S24leIn32),
+            DitherMode::Off,
+            NoiseShaping::Off,
+            SmallRng::seed_from_u64(7),
+        );
+        q.process(&[0.5, -2.0], &mut out);
+        assert_eq!(out, [0, 0, 0, 0x40, 0, 0, 0, 0x80]);
+    }
+}
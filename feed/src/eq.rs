@@ -0,0 +1,471 @@
+use crate::biquad::Biquad;
+use crate::loudness::db_to_linear;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const BUTTERWORTH_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FilterKind {
+    Peaking,
+    LowShelf,
+    HighShelf,
+    HighPass,
+    LowPass,
+}
+
+impl FilterKind {
+    pub const ALL: [FilterKind; 5] = [
+        FilterKind::Peaking,
+        FilterKind::LowShelf,
+        FilterKind::HighShelf,
+        FilterKind::HighPass,
+        FilterKind::LowPass,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            FilterKind::Peaking => "Peaking",
+            FilterKind::LowShelf => "Low shelf",
+            FilterKind::HighShelf => "High shelf",
+            FilterKind::HighPass => "High-pass",
+            FilterKind::LowPass => "Low-pass",
+        }
+    }
+
+    fn key(self) -> &'static str {
+        match self {
+            FilterKind::Peaking => "peaking",
+            FilterKind::LowShelf => "low-shelf",
+            FilterKind::HighShelf => "high-shelf",
+            FilterKind::HighPass => "high-pass",
+            FilterKind::LowPass => "low-pass",
+        }
+    }
+
+    // The passes only have a corner and a Q.
+    pub fn has_gain(self) -> bool {
+        !matches!(self, FilterKind::HighPass | FilterKind::LowPass)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Band {
+    pub kind: FilterKind,
+    // Centre or corner frequency in Hz.
+    pub frequency: f32,
+    // dB, ignored by the passes.
+    #[serde(default)]
+    pub gain: f32,
+    #[serde(default = "default_q")]
+    pub q: f32,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_q() -> f32 {
+    BUTTERWORTH_Q
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl Band {
+    pub fn new(kind: FilterKind, frequency: f32, gain: f32, q: f32) -> Self {
+        Self {
+            kind,
+            frequency,
+            gain,
+            q,
+            enabled: true,
+        }
+    }
+
+    pub fn design(&self, sample_rate: u32) -> Biquad {
+        let (frequency, gain, q) = (self.frequency as f64, self.gain as f64, self.q as f64);
+        match self.kind {
+            FilterKind::Peaking => Biquad::peaking(sample_rate, frequency, gain, q),
+            FilterKind::LowShelf => Biquad::low_shelf(sample_rate, frequency, gain, q),
+            FilterKind::HighShelf => Biquad::high_shelf(sample_rate, frequency, gain, q),
+            FilterKind::HighPass => Biquad::high_pass(sample_rate, frequency, q),
+            FilterKind::LowPass => Biquad::low_pass(sample_rate, frequency, q),
+        }
+    }
+}
+
+// `kind:frequency[:gain[:q]]`, e.g. `peaking:1000:-3:1.4` or `high-pass:40`.
+impl FromStr for Band {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut fields = spec.split(':');
+        let kind_field = fields.next().unwrap_or_default();
+        let kind = FilterKind::ALL
+            .into_iter()
+            .find(|kind| kind.key() == kind_field)
+            .ok_or_else(|| format!("unknown filter type '{}'", kind_field))?;
+        let mut number = |name: &str, default: Option<f32>| match fields.next() {
+            Some(value) if !value.is_empty() => value
+                .parse::<f32>()
+                .map_err(|_| format!("invalid {} '{}'", name, value)),
+            _ => default.ok_or_else(|| format!("missing {}", name)),
+        };
+        let frequency = number("frequency", None)?;
+        let gain = number("gain", Some(0.0))?;
+        let q = number("Q", Some(BUTTERWORTH_Q))?;
+        if frequency <= 0.0 || q <= 0.0 {
+            return Err("frequency and Q must be positive".to_string());
+        }
+        Ok(Band::new(kind, frequency, gain, q))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EqSettings {
+    pub enabled: bool,
+    // dB applied ahead of the bands, to make room for boosts.
+    pub preamp: f32,
+    pub bands: Vec<Band>,
+}
+
+impl EqSettings {
+    pub fn apply_preset(&mut self, preset: &EqPreset) {
+        self.preamp = preset.preamp;
+        self.bands = preset.bands.clone();
+    }
+
+    // Combined magnitude response in dB at `frequency`.
+    pub fn response(&self, sample_rate: u32, frequency: f32) -> f32 {
+        let bands: f64 = self
+            .bands
+            .iter()
+            .filter(|band| band.enabled)
+            .map(|band| {
+                band.design(sample_rate)
+                    .response(sample_rate, frequency as f64)
+            })
+            .sum();
+        self.preamp + bands as f32
+    }
+}
+
+// Runs `EqSettings` over interleaved stereo, one biquad per band and channel.
+pub struct Equalizer {
+    sample_rate: u32,
+    settings: EqSettings,
+    filters: Vec<[Biquad; 2]>,
+}
+
+impl Equalizer {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            settings: EqSettings::default(),
+            filters: Vec::new(),
+        }
+    }
+
+    // Picks up edited settings. Adjusting a band keeps its filter state so
+    // dragging a control doesn't click; adding or removing bands, or turning
+    // the EQ back on, starts from silence.
+    pub fn update(&mut self, settings: &EqSettings) {
+        if *settings == self.settings {
+            return;
+        }
+        let designs = settings
+            .bands
+            .iter()
+            .filter(|band| band.enabled)
+            .map(|band| band.design(self.sample_rate));
+        let restart = !self.settings.enabled
+            || settings.bands.iter().filter(|band| band.enabled).count() != self.filters.len();
+        if restart {
+            self.filters = designs.map(|design| [design; 2]).collect();
+        } else {
+            for (filters, design) in self.filters.iter_mut().zip(designs) {
+                filters[0].retune(&design);
+                filters[1].retune(&design);
+            }
+        }
+        self.settings = settings.clone();
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        if !self.settings.enabled {
+            return;
+        }
+        let preamp = db_to_linear(self.settings.preamp) as f64;
+        for frame in samples.chunks_exact_mut(2) {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let mut x = *sample as f64 * preamp;
+                for filters in &mut self.filters {
+                    x = filters[channel].process(x);
+                }
+                *sample = x as f32;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EqPreset {
+    pub name: String,
+    #[serde(default)]
+    pub preamp: f32,
+    #[serde(default)]
+    pub bands: Vec<Band>,
+}
+
+impl EqPreset {
+    pub fn from_settings(name: &str, settings: &EqSettings) -> Self {
+        Self {
+            name: name.to_string(),
+            preamp: settings.preamp,
+            bands: settings.bands.clone(),
+        }
+    }
+}
+
+// Presets that are always there, ahead of the saved ones.
+pub fn builtin_presets() -> Vec<EqPreset> {
+    let preset = |name: &str, preamp: f32, bands: Vec<Band>| EqPreset {
+        name: name.to_string(),
+        preamp,
+        bands,
+    };
+    vec![
+        preset("Flat", 0.0, Vec::new()),
+        preset(
+            "Bass boost",
+            -6.0,
+            vec![Band::new(FilterKind::LowShelf, 120.0, 6.0, BUTTERWORTH_Q)],
+        ),
+        preset(
+            "Treble boost",
+            -4.0,
+            vec![Band::new(FilterKind::HighShelf, 6000.0, 4.0, BUTTERWORTH_Q)],
+        ),
+        preset(
+            "Rumble filter",
+            0.0,
+            vec![Band::new(FilterKind::HighPass, 30.0, 0.0, BUTTERWORTH_Q)],
+        ),
+    ]
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct PresetFile {
+    #[serde(default)]
+    preset: Vec<EqPreset>,
+}
+
+pub fn presets_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("feed").join("eq_presets.toml"))
+}
+
+// Saved presets; a missing file just means there are none yet.
+pub fn load_presets(path: &Path) -> Result<Vec<EqPreset>, Box<dyn std::error::Error>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let file: PresetFile =
+        toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(file.preset)
+}
+
+pub fn save_presets(path: &Path, presets: &[EqPreset]) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let file = PresetFile {
+        preset: presets.to_vec(),
+    };
+    fs::write(path, toml::to_string_pretty(&file)?)?;
+    Ok(())
+}
+
+// Looks a preset up by name, case-insensitively, saved ones first.
+pub fn find_preset(name: &str) -> Result<EqPreset, Box<dyn std::error::Error>> {
+    let saved = match presets_path() {
+        Some(path) => load_presets(&path)?,
+        None => Vec::new(),
+    };
+    saved
+        .into_iter()
+        .chain(builtin_presets())
+        .find(|preset| preset.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("no EQ preset called '{}'", name).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 46875;
+
+    fn sine(frequency: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|n| {
+                let s =
+                    0.25 * (2.0 * std::f32::consts::PI * frequency * n as f32 / RATE as f32).sin();
+                [s, s]
+            })
+            .collect()
+    }
+
+    fn peak_db(samples: &[f32]) -> f32 {
+        let peak = samples.iter().fold(0f32, |m, s| m.max(s.abs()));
+        20.0 * (peak / 0.25).log10()
+    }
+
+    fn settings(preamp: f32, bands: Vec<Band>) -> EqSettings {
+        EqSettings {
+            enabled: true,
+            preamp,
+            bands,
+        }
+    }
+
+    #[test]
+    fn disabled_or_empty_eq_passes_audio_through() {
+        let input = sine(440.0, 2000);
+        let mut eq = Equalizer::new(RATE);
+
+        let mut disabled = settings(-6.0, vec![Band::new(FilterKind::Peaking, 440.0, 9.0, 1.0)]);
+        disabled.enabled = false;
+        eq.update(&disabled);
+        let mut output = input.clone();
+        eq.process(&mut output);
+        assert_eq!(output, input);
+
+        eq.update(&settings(0.0, Vec::new()));
+        let mut output = input.clone();
+        eq.process(&mut output);
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn bands_and_preamp_add_up_in_the_response() {
+        let eq = settings(
+            -3.0,
+            vec![
+                Band::new(FilterKind::LowShelf, 100.0, 6.0, 0.7),
+                Band::new(FilterKind::Peaking, 3000.0, -4.0, 2.0),
+                Band {
+                    enabled: false,
+                    ..Band::new(FilterKind::LowPass, 200.0, 0.0, 0.7)
+                },
+            ],
+        );
+        let at = |f: f32| eq.response(RATE, f);
+        assert!((at(10.0) - 3.0).abs() < 0.05);
+        assert!((at(3000.0) - -7.0).abs() < 0.05);
+        assert!((at(15000.0) - -3.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn processed_audio_follows_the_response() {
+        let eq_settings = settings(
+            -2.0,
+            vec![
+                Band::new(FilterKind::Peaking, 1000.0, 8.0, 1.0),
+                Band::new(FilterKind::HighPass, 100.0, 0.0, BUTTERWORTH_Q),
+            ],
+        );
+        let mut eq = Equalizer::new(RATE);
+        eq.update(&eq_settings);
+        for frequency in [60.0, 800.0, 1000.0, 5000.0] {
+            let mut samples = sine(frequency, RATE as usize);
+            eq.process(&mut samples);
+            let settled = peak_db(&samples[RATE as usize..]);
+            let expected = eq_settings.response(RATE, frequency);
+            assert!(
+                (settled - expected).abs() < 0.05,
+                "{} Hz: {} vs {}",
+                frequency,
+                settled,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn retuning_a_band_keeps_the_signal_continuous() {
+        let mut eq = Equalizer::new(RATE);
+        let mut band = Band::new(FilterKind::Peaking, 1000.0, 3.0, 1.0);
+        eq.update(&settings(0.0, vec![band]));
+        let input = sine(200.0, 4000);
+        let mut first = input[..4000].to_vec();
+        eq.process(&mut first);
+
+        band.gain = 3.5;
+        eq.update(&settings(0.0, vec![band]));
+        let mut second = input[4000..].to_vec();
+        eq.process(&mut second);
+
+        let jump = (second[0] - first[first.len() - 2]).abs();
+        assert!(jump < 0.02, "{}", jump);
+    }
+
+    #[test]
+    fn parses_band_specs() {
+        assert_eq!(
+            "peaking:1000:-3:1.4".parse::<Band>(),
+            Ok(Band::new(FilterKind::Peaking, 1000.0, -3.0, 1.4))
+        );
+        assert_eq!(
+            "high-pass:40".parse::<Band>(),
+            Ok(Band::new(FilterKind::HighPass, 40.0, 0.0, BUTTERWORTH_Q))
+        );
+        assert_eq!(
+            "low-shelf:120:4".parse::<Band>().map(|b| b.q),
+            Ok(BUTTERWORTH_Q)
+        );
+        assert!("notch:1000".parse::<Band>().is_err());
+        assert!("peaking".parse::<Band>().is_err());
+        assert!("peaking:loud".parse::<Band>().is_err());
+        assert!("peaking:-5".parse::<Band>().is_err());
+    }
+
+    #[test]
+    fn presets_round_trip_through_the_file() {
+        let path = std::env::temp_dir()
+            .join(format!("feed-eq-{}", std::process::id()))
+            .join("presets.toml");
+        let _ = fs::remove_file(&path);
+        assert_eq!(load_presets(&path).unwrap(), Vec::new());
+
+        let mut presets = builtin_presets();
+        presets.push(EqPreset::from_settings(
+            "CS43L22 headphones",
+            &settings(
+                -4.5,
+                vec![
+                    Band::new(FilterKind::Peaking, 3150.0, -3.5, 2.2),
+                    Band {
+                        enabled: false,
+                        ..Band::new(FilterKind::LowShelf, 105.0, 4.0, 0.7)
+                    },
+                ],
+            ),
+        ));
+        save_presets(&path, &presets).unwrap();
+        assert_eq!(load_presets(&path).unwrap(), presets);
+
+        fs::write(&path, "[[preset]]\nname = \"Minimal\"\n[[preset.bands]]\nkind = \"high-pass\"\nfrequency = 25.0\n").unwrap();
+        let loaded = load_presets(&path).unwrap();
+        assert_eq!(
+            loaded[0].bands,
+            [Band::new(FilterKind::HighPass, 25.0, 0.0, BUTTERWORTH_Q)]
+        );
+
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -0,0 +1,289 @@
+use serde::{Deserialize, Serialize};
+use std::f32::consts::FRAC_PI_2;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FadeCurve {
+    /// Gain rises in a straight line
+    Linear,
+    /// Sine/cosine pair whose powers add up to one, so a crossfade between
+    /// unrelated material keeps a steady loudness
+    #[default]
+    EqualPower,
+    /// Raised cosine that starts and ends gently; a crossfade keeps the
+    /// amplitudes adding up to one
+    SCurve,
+}
+
+impl FadeCurve {
+    pub const ALL: [FadeCurve; 3] = [FadeCurve::Linear, FadeCurve::EqualPower, FadeCurve::SCurve];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            FadeCurve::Linear => "Linear",
+            FadeCurve::EqualPower => "Equal power",
+            FadeCurve::SCurve => "S-curve",
+        }
+    }
+
+    // Fade-in gain at `t` between 0 and 1. A fade-out runs the same curve
+    // backwards.
+    pub fn gain(self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            FadeCurve::Linear => t,
+            FadeCurve::EqualPower => (t * FRAC_PI_2).sin(),
+            FadeCurve::SCurve => 0.5 - 0.5 * (t * std::f32::consts::PI).cos(),
+        }
+    }
+}
+
+// Gain ramp over interleaved stereo, applied a block at a time.
+#[derive(Clone, Debug)]
+pub struct Fade {
+    curve: FadeCurve,
+    rising: bool,
+    frames: usize,
+    position: usize,
+}
+
+impl Fade {
+    pub fn fade_in(curve: FadeCurve, frames: usize) -> Self {
+        Self {
+            curve,
+            rising: true,
+            frames,
+            position: 0,
+        }
+    }
+
+    pub fn fade_out(curve: FadeCurve, frames: usize) -> Self {
+        Self {
+            curve,
+            rising: false,
+            frames,
+            position: 0,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.position >= self.frames
+    }
+
+    pub fn remaining_frames(&self) -> usize {
+        self.frames.saturating_sub(self.position)
+    }
+
+    // Gain for the next frame. Past the end a fade-in holds at one and a
+    // fade-out at zero.
+    pub fn gain(&self) -> f32 {
+        if self.is_finished() {
+            return if self.rising { 1.0 } else { 0.0 };
+        }
+        let t = self.position as f32 / self.frames as f32;
+        if self.rising {
+            self.curve.gain(t)
+        } else {
+            self.curve.gain(1.0 - t)
+        }
+    }
+
+    pub fn apply(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_exact_mut(2) {
+            let gain = self.gain();
+            frame[0] *= gain;
+            frame[1] *= gain;
+            self.position += 1;
+        }
+    }
+}
+
+// Gain that glides to each new target over a fixed number of frames instead
+// of stepping, so a control moved mid-chunk doesn't zipper.
+#[derive(Clone, Debug)]
+pub struct GainRamp {
+    current: f32,
+    target: f32,
+    step: f32,
+    frames: usize,
+}
+
+impl GainRamp {
+    pub fn new(gain: f32, frames: usize) -> Self {
+        Self {
+            current: gain,
+            target: gain,
+            step: 0.0,
+            frames: frames.max(1),
+        }
+    }
+
+    pub fn gain(&self) -> f32 {
+        self.current
+    }
+
+    // A target that changes mid-ramp starts a fresh ramp from wherever the
+    // gain has got to.
+    pub fn set_target(&mut self, target: f32) {
+        if target != self.target {
+            self.target = target;
+            self.step = (target - self.current) / self.frames as f32;
+        }
+    }
+
+    pub fn apply(&mut self, samples: &mut [f32]) {
+        if self.current == self.target {
+            samples
+                .iter_mut()
+                .for_each(|sample| *sample *= self.current);
+            return;
+        }
+        for frame in samples.chunks_exact_mut(2) {
+            let next = self.current + self.step;
+            self.current = if (self.step > 0.0 && next >= self.target)
+                || (self.step < 0.0 && next <= self.target)
+            {
+                self.target
+            } else {
+                next
+            };
+            frame[0] *= self.current;
+            frame[1] *= self.current;
+        }
+    }
+}
+
+pub fn seconds_to_frames(seconds: f32, sample_rate: u32) -> usize {
+    (seconds.max(0.0) * sample_rate as f32).round() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gains(mut fade: Fade, frames: usize) -> Vec<f32> {
+        let mut samples = vec![1.0; frames * 2];
+        fade.apply(&mut samples);
+        samples.chunks_exact(2).map(|f| f[0]).collect()
+    }
+
+    #[test]
+    fn fade_in_starts_silent_and_ends_at_unity() {
+        for curve in FadeCurve::ALL {
+            let g = gains(Fade::fade_in(curve, 100), 110);
+            assert_eq!(g[0], 0.0, "{:?}", curve);
+            assert!(g.windows(2).all(|w| w[1] >= w[0]), "{:?}", curve);
+            assert!(g[99] > 0.98 && g[99] < 1.0, "{:?}", curve);
+            assert!(g[100..].iter().all(|&x| x == 1.0), "{:?}", curve);
+        }
+    }
+
+    #[test]
+    fn fade_out_starts_at_unity_and_ends_silent() {
+        for curve in FadeCurve::ALL {
+            let g = gains(Fade::fade_out(curve, 100), 110);
+            assert_eq!(g[0], 1.0, "{:?}", curve);
+            assert!(g.windows(2).all(|w| w[1] <= w[0]), "{:?}", curve);
+            assert!(g[99] < 0.05, "{:?}", curve);
+            assert!(g[100..].iter().all(|&x| x == 0.0), "{:?}", curve);
+        }
+    }
+
+    #[test]
+    fn curve_midpoints() {
+        assert_eq!(FadeCurve::Linear.gain(0.5), 0.5);
+        assert!((FadeCurve::EqualPower.gain(0.5) - 0.5f32.sqrt()).abs() < 1e-6);
+        assert!((FadeCurve::SCurve.gain(0.5) - 0.5).abs() < 1e-6);
+        assert!(FadeCurve::SCurve.gain(0.1) < FadeCurve::Linear.gain(0.1));
+    }
+
+    #[test]
+    fn equal_power_crossfade_keeps_power_constant() {
+        let g_in = gains(Fade::fade_in(FadeCurve::EqualPower, 64), 64);
+        let g_out = gains(Fade::fade_out(FadeCurve::EqualPower, 64), 64);
+        for (a, b) in g_in.iter().zip(&g_out) {
+            assert!((a * a + b * b - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn linear_and_s_curve_crossfades_keep_amplitude_constant() {
+        for curve in [FadeCurve::Linear, FadeCurve::SCurve] {
+            let g_in = gains(Fade::fade_in(curve, 64), 64);
+            let g_out = gains(Fade::fade_out(curve, 64), 64);
+            for (a, b) in g_in.iter().zip(&g_out) {
+                assert!((a + b - 1.0).abs() < 1e-5, "{:?}", curve);
+            }
+        }
+    }
+
+    #[test]
+    fn blocks_continue_where_the_last_one_stopped() {
+        let whole = gains(Fade::fade_in(FadeCurve::SCurve, 300), 300);
+
+        let mut fade = Fade::fade_in(FadeCurve::SCurve, 300);
+        let mut pieces = Vec::new();
+        for len in [1, 7, 100, 192] {
+            let mut samples = vec![1.0; len * 2];
+            fade.apply(&mut samples);
+            pieces.extend(samples.chunks_exact(2).map(|f| f[0]));
+        }
+        assert_eq!(whole, pieces);
+        assert!(fade.is_finished());
+    }
+
+    #[test]
+    fn both_channels_get_the_same_gain() {
+        let mut samples = vec![0.5, -0.5, 0.25, -0.25, 1.0, -1.0];
+        Fade::fade_out(FadeCurve::Linear, 4).apply(&mut samples);
+        assert_eq!(samples, [0.5, -0.5, 0.1875, -0.1875, 0.5, -0.5]);
+    }
+
+    #[test]
+    fn empty_fade_is_a_no_op() {
+        assert!(
+            gains(Fade::fade_in(FadeCurve::Linear, 0), 4)
+                .iter()
+                .all(|&g| g == 1.0)
+        );
+        assert!(Fade::fade_out(FadeCurve::Linear, 0).is_finished());
+    }
+
+    #[test]
+    fn gain_ramp_glides_to_the_target() {
+        let mut ramp = GainRamp::new(1.0, 4);
+        ramp.set_target(0.0);
+        let mut samples = vec![1.0; 12];
+        ramp.apply(&mut samples);
+        assert_eq!(
+            samples,
+            [
+                0.75, 0.75, 0.5, 0.5, 0.25, 0.25, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0
+            ]
+        );
+
+        ramp.set_target(2.0);
+        let mut samples = vec![0.5; 6];
+        ramp.apply(&mut samples);
+        assert_eq!(samples, [0.25, 0.25, 0.5, 0.5, 0.75, 0.75]);
+        assert_eq!(ramp.gain(), 1.5);
+    }
+
+    #[test]
+    fn retargeting_mid_ramp_never_steps() {
+        let mut ramp = GainRamp::new(0.0, 100);
+        ramp.set_target(1.0);
+        let mut gains = Vec::new();
+        for target in [1.0, 0.2, 0.2, 0.9, 0.0] {
+            ramp.set_target(target);
+            let mut samples = vec![1.0; 60];
+            ramp.apply(&mut samples);
+            gains.extend(samples.chunks_exact(2).map(|f| f[0]));
+        }
+        let largest_step = gains
+            .windows(2)
+            .map(|w| (w[1] - w[0]).abs())
+            .fold(0f32, f32::max);
+        assert!(largest_step <= 0.0101, "{}", largest_step);
+    }
+}
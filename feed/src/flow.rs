@@ -0,0 +1,444 @@
+use crate::format::DeviceFormat;
+use crate::protocol::{self, ControlCommand, DeviceStatus, FrameType};
+use crate::sink::AudioSink;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::time::{Duration, Instant};
+
+// A status older than this is ignored and pacing falls back to the clock.
+const STATUS_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_WAIT: Duration = Duration::from_millis(20);
+const DEFAULT_TARGET_FILL: f64 = 0.5;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FlowControl {
+    /// Send at the nominal sample rate using the host clock
+    #[default]
+    Timed,
+    /// Keep the device buffer at a target fill level from its status reports,
+    /// falling back to timed pacing while none arrive
+    Device,
+}
+
+pub struct Pacer {
+    mode: FlowControl,
+    sample_rate: f64,
+    target_fill: f64,
+    timed_origin: Option<Instant>,
+    timed_frames: u64,
+    status: Option<(Instant, DeviceStatus)>,
+    sent_since_status: u64,
+    paused_at: Option<Instant>,
+}
+
+impl Pacer {
+    pub fn new(mode: FlowControl, sample_rate: u32) -> Self {
+        Self {
+            mode,
+            sample_rate: sample_rate as f64,
+            target_fill: DEFAULT_TARGET_FILL,
+            timed_origin: None,
+            timed_frames: 0,
+            status: None,
+            sent_since_status: 0,
+            paused_at: None,
+        }
+    }
+
+    pub fn pause(&mut self, now: Instant) {
+        self.paused_at.get_or_insert(now);
+    }
+
+    // Moves both clocks forward by the length of the pause, so nothing is
+    // sent to catch up on it.
+    pub fn resume(&mut self, now: Instant) {
+        let Some(at) = self.paused_at.take() else {
+            return;
+        };
+        let paused = now.duration_since(at);
+        if let Some(origin) = self.timed_origin.as_mut() {
+            *origin += paused;
+        }
+        if let Some((reported, _)) = self.status.as_mut() {
+            *reported += paused;
+        }
+    }
+
+    pub fn report(&mut self, now: Instant, status: DeviceStatus) {
+        self.status = Some((now, status));
+        self.sent_since_status = 0;
+    }
+
+    pub fn is_device_paced(&self, now: Instant) -> bool {
+        self.mode == FlowControl::Device
+            && self
+                .status
+                .is_some_and(|(at, _)| now.duration_since(at) < STATUS_TIMEOUT)
+    }
+
+    // How long to wait before `frames` more frames may be sent, or None to send now.
+    pub fn delay(&mut self, now: Instant, frames: usize) -> Option<Duration> {
+        if self.is_device_paced(now) {
+            self.timed_origin = None;
+            let (at, status) = self.status.unwrap();
+            let drained = now.duration_since(at).as_secs_f64() * self.sample_rate;
+            let estimated =
+                (status.buffered_frames as f64 + self.sent_since_status as f64 - drained).max(0.0);
+            let target = status.capacity_frames as f64 * self.target_fill;
+            let excess = estimated + frames as f64 - target;
+            if excess < 1.0 {
+                return None;
+            }
+            return Some(Duration::from_secs_f64(excess / self.sample_rate).min(MAX_WAIT));
+        }
+
+        let origin = *self.timed_origin.get_or_insert_with(|| {
+            self.timed_frames = 0;
+            now
+        });
+        let due = origin + Duration::from_secs_f64(self.timed_frames as f64 / self.sample_rate);
+        due.checked_duration_since(now)
+            .filter(|wait| !wait.is_zero())
+    }
+
+    pub fn sent(&mut self, frames: usize) {
+        self.sent_since_status += frames as u64;
+        self.timed_frames += frames as u64;
+    }
+}
+
+// Stand-in for the firmware's incoming buffer: frames of the given format are
+// consumed at its sample rate and anything that doesn't fit is dropped, like
+// CDC_On_Receive does. In framed mode it also reports its fill level as
+// telemetry.
+pub struct SimulatedDevice {
+    format: DeviceFormat,
+    sample_rate: f64,
+    capacity: u32,
+    buffered: f64,
+    framed: bool,
+    paused: bool,
+    manual_time: Option<Instant>,
+    last_update: Option<Instant>,
+    last_report: Option<Instant>,
+    report_interval: Duration,
+    decoder: protocol::Decoder,
+    encoder: protocol::Encoder,
+    outgoing: Vec<u8>,
+    pub received_frames: u64,
+    pub dropped_frames: u64,
+    pub underrun_frames: u64,
+}
+
+impl SimulatedDevice {
+    pub const DEFAULT_CAPACITY: u32 = 16384;
+
+    pub fn new(format: DeviceFormat, framed: bool) -> Self {
+        Self {
+            format,
+            sample_rate: format.sample_rate as f64,
+            capacity: Self::DEFAULT_CAPACITY,
+            buffered: 0.0,
+            framed,
+            paused: false,
+            manual_time: None,
+            last_update: None,
+            last_report: None,
+            report_interval: Duration::from_millis(10),
+            decoder: protocol::Decoder::new(),
+            encoder: protocol::Encoder::new(),
+            outgoing: Vec::new(),
+            received_frames: 0,
+            dropped_frames: 0,
+            underrun_frames: 0,
+        }
+    }
+
+    // Switches the device to a caller-driven clock, for tests.
+    pub fn set_time(&mut self, now: Instant) {
+        self.manual_time = Some(now);
+        self.advance();
+    }
+
+    pub fn buffered_frames(&self) -> u32 {
+        self.buffered as u32
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    fn now(&self) -> Instant {
+        self.manual_time.unwrap_or_else(Instant::now)
+    }
+
+    fn advance(&mut self) {
+        let now = self.now();
+        if let Some(last) = self.last_update
+            && !self.paused
+        {
+            let consumed = now.duration_since(last).as_secs_f64() * self.sample_rate;
+            if self.received_frames > 0 && consumed > self.buffered {
+                self.underrun_frames += (consumed - self.buffered) as u64;
+            }
+            self.buffered = (self.buffered - consumed).max(0.0);
+        }
+        self.last_update = Some(now);
+
+        let report_due = self
+            .last_report
+            .is_none_or(|at| now.duration_since(at) >= self.report_interval);
+        if self.framed && report_due {
+            let status = DeviceStatus {
+                buffered_frames: self.buffered_frames(),
+                capacity_frames: self.capacity,
+            };
+            self.encoder
+                .encode_into(&mut self.outgoing, FrameType::Telemetry, &status.to_bytes());
+            self.last_report = Some(now);
+        }
+    }
+
+    fn accept(&mut self, frames: usize) {
+        let free = (self.capacity as f64 - 1.0 - self.buffered).max(0.0) as usize;
+        let taken = frames.min(free);
+        self.buffered += taken as f64;
+        self.received_frames += taken as u64;
+        self.dropped_frames += (frames - taken) as u64;
+    }
+}
+
+impl AudioSink for SimulatedDevice {
+    fn name(&self) -> String {
+        "simulated device".to_string()
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.advance();
+        if !self.framed {
+            self.accept(self.format.frames_in(data.len()));
+            return Ok(());
+        }
+
+        self.decoder.push(data);
+        while let Some(frame) = self.decoder.next_frame() {
+            let Ok(frame) = frame else {
+                continue;
+            };
+            match frame.frame_type {
+                FrameType::Audio => {
+                    let frames = self.format.frames_in(frame.payload.len());
+                    self.accept(frames)
+                }
+                FrameType::Control => {
+                    match frame
+                        .payload
+                        .first()
+                        .copied()
+                        .and_then(ControlCommand::from_u8)
+                    {
+                        Some(ControlCommand::Pause) => self.paused = true,
+                        Some(ControlCommand::Resume) => self.paused = false,
+                        None => {}
+                    }
+                }
+                FrameType::Telemetry => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.advance();
+        let len = self.outgoing.len().min(buf.len());
+        buf[..len].copy_from_slice(&self.outgoing[..len]);
+        self.outgoing.drain(..len);
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::FramedSink;
+
+    const RATE: u32 = 46875;
+    const CHUNK_FRAMES: usize = 1024;
+
+    // A device running at `rate`, which may be off from the nominal one.
+    fn device(rate: u32, framed: bool) -> SimulatedDevice {
+        SimulatedDevice::new(
+            DeviceFormat {
+                sample_rate: rate,
+                ..Default::default()
+            },
+            framed,
+        )
+    }
+
+    // Streams `seconds` of silence through the pacer on a virtual clock and
+    // returns the frames the host sent.
+    fn stream<S: AudioSink>(
+        sink: &mut S,
+        set_time: impl Fn(&mut S, Instant),
+        pacer: &mut Pacer,
+        start: Instant,
+        seconds: f64,
+    ) -> u64 {
+        let end = start + Duration::from_secs_f64(seconds);
+        let chunk = vec![0u8; CHUNK_FRAMES * DeviceFormat::default().bytes_per_frame()];
+        let mut now = start;
+        let mut sent = 0;
+
+        while now < end {
+            set_time(sink, now);
+            if let Some(status) = sink.poll_status() {
+                pacer.report(now, status);
+            }
+            match pacer.delay(now, CHUNK_FRAMES) {
+                Some(wait) => now += wait,
+                None => {
+                    sink.write_all(&chunk).unwrap();
+                    pacer.sent(CHUNK_FRAMES);
+                    sent += CHUNK_FRAMES as u64;
+                }
+            }
+        }
+        sent
+    }
+
+    #[test]
+    fn timed_pacing_matches_nominal_rate() {
+        let mut device = device(RATE, false);
+        let mut pacer = Pacer::new(FlowControl::Timed, RATE);
+        let sent = stream(
+            &mut device,
+            |d, t| d.set_time(t),
+            &mut pacer,
+            Instant::now(),
+            2.0,
+        );
+
+        let expected = 2 * RATE as u64;
+        assert!(
+            sent.abs_diff(expected) <= CHUNK_FRAMES as u64,
+            "sent {}",
+            sent
+        );
+        assert_eq!(device.dropped_frames, 0);
+    }
+
+    #[test]
+    fn device_mode_falls_back_without_telemetry() {
+        let mut device = device(RATE, false);
+        let mut pacer = Pacer::new(FlowControl::Device, RATE);
+        let sent = stream(
+            &mut device,
+            |d, t| d.set_time(t),
+            &mut pacer,
+            Instant::now(),
+            2.0,
+        );
+
+        assert!(!pacer.is_device_paced(Instant::now()));
+        let expected = 2 * RATE as u64;
+        assert!(
+            sent.abs_diff(expected) <= CHUNK_FRAMES as u64,
+            "sent {}",
+            sent
+        );
+    }
+
+    #[test]
+    fn timed_pacing_overflows_a_slow_device() {
+        let mut device = device(RATE * 99 / 100, false);
+        let mut pacer = Pacer::new(FlowControl::Timed, RATE);
+        stream(
+            &mut device,
+            |d, t| d.set_time(t),
+            &mut pacer,
+            Instant::now(),
+            60.0,
+        );
+
+        assert!(device.dropped_frames > 0);
+    }
+
+    #[test]
+    fn device_pacing_follows_a_slow_device() {
+        let mut sink = FramedSink::new(device(RATE * 99 / 100, true));
+        let mut pacer = Pacer::new(FlowControl::Device, RATE);
+        stream(
+            &mut sink,
+            |s, t| s.inner_mut().set_time(t),
+            &mut pacer,
+            Instant::now(),
+            60.0,
+        );
+
+        let device = sink.inner_mut();
+        assert_eq!(device.dropped_frames, 0);
+        assert_eq!(device.underrun_frames, 0);
+        let target = SimulatedDevice::DEFAULT_CAPACITY as f64 * DEFAULT_TARGET_FILL;
+        assert!((device.buffered_frames() as f64 - target).abs() < 2.0 * CHUNK_FRAMES as f64);
+    }
+
+    #[test]
+    fn device_pacing_follows_a_fast_device() {
+        let mut sink = FramedSink::new(device(RATE * 101 / 100, true));
+        let mut pacer = Pacer::new(FlowControl::Device, RATE);
+        stream(
+            &mut sink,
+            |s, t| s.inner_mut().set_time(t),
+            &mut pacer,
+            Instant::now(),
+            60.0,
+        );
+
+        let device = sink.inner_mut();
+        assert_eq!(device.dropped_frames, 0);
+        assert_eq!(device.underrun_frames, 0);
+    }
+
+    #[test]
+    fn pause_holds_the_device_buffer_and_the_clock() {
+        let mut sink = FramedSink::new(device(RATE, true));
+        let mut pacer = Pacer::new(FlowControl::Timed, RATE);
+        let start = Instant::now();
+        let mut sent = stream(
+            &mut sink,
+            |s, t| s.inner_mut().set_time(t),
+            &mut pacer,
+            start,
+            1.0,
+        );
+
+        let paused_at = start + Duration::from_secs(1);
+        pacer.pause(paused_at);
+        sink.control(ControlCommand::Pause).unwrap();
+        let held = sink.inner_mut().buffered_frames();
+        let resumed_at = paused_at + Duration::from_secs(5);
+        sink.inner_mut().set_time(resumed_at);
+        assert!(sink.inner_mut().is_paused());
+        assert_eq!(sink.inner_mut().buffered_frames(), held);
+
+        sink.control(ControlCommand::Resume).unwrap();
+        pacer.resume(resumed_at);
+        sent += stream(
+            &mut sink,
+            |s, t| s.inner_mut().set_time(t),
+            &mut pacer,
+            resumed_at,
+            1.0,
+        );
+
+        let expected = 2 * RATE as u64;
+        assert!(
+            sent.abs_diff(expected) <= 2 * CHUNK_FRAMES as u64,
+            "sent {}",
+            sent
+        );
+        assert_eq!(sink.inner_mut().dropped_frames, 0);
+    }
+}
@@ -0,0 +1,234 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// What the firmware's I2S setup runs at out of the box.
+pub const DEFAULT_SAMPLE_RATE: u32 = 46875;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+pub enum SampleEncoding {
+    /// Signed 16-bit little-endian, as the stock firmware expects
+    #[default]
+    #[value(name = "s16le")]
+    #[serde(rename = "s16le")]
+    S16le,
+    /// Signed 24-bit samples in the top three bytes of a little-endian
+    /// 32-bit word, the low byte zero
+    #[value(name = "s24le-in-32")]
+    #[serde(rename = "s24le-in-32")]
+    S24leIn32,
+    /// Signed 32-bit little-endian
+    #[value(name = "s32le")]
+    #[serde(rename = "s32le")]
+    S32le,
+    /// Unsigned 8-bit, centred on 128
+    #[value(name = "u8")]
+    #[serde(rename = "u8")]
+    U8,
+}
+
+impl SampleEncoding {
+    pub const ALL: [SampleEncoding; 4] = [
+        SampleEncoding::S16le,
+        SampleEncoding::S24leIn32,
+        SampleEncoding::S32le,
+        SampleEncoding::U8,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SampleEncoding::S16le => "16-bit",
+            SampleEncoding::S24leIn32 => "24-bit in 32",
+            SampleEncoding::S32le => "32-bit",
+            SampleEncoding::U8 => "8-bit unsigned",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SampleEncoding::S16le => "s16le",
+            SampleEncoding::S24leIn32 => "s24le-in-32",
+            SampleEncoding::S32le => "s32le",
+            SampleEncoding::U8 => "u8",
+        }
+    }
+
+    // Bytes each sample takes on the wire.
+    pub fn bytes(self) -> usize {
+        match self {
+            SampleEncoding::U8 => 1,
+            SampleEncoding::S16le => 2,
+            SampleEncoding::S24leIn32 | SampleEncoding::S32le => 4,
+        }
+    }
+
+    // Bits of resolution, which is what dither and rounding work at.
+    pub fn bits(self) -> u32 {
+        match self {
+            SampleEncoding::U8 => 8,
+            SampleEncoding::S16le => 16,
+            SampleEncoding::S24leIn32 => 24,
+            SampleEncoding::S32le => 32,
+        }
+    }
+
+    // Full scale in steps of the least significant bit.
+    pub fn full_scale(self) -> f64 {
+        (1u64 << (self.bits() - 1)) as f64
+    }
+
+    // Writes `value`, already rounded to a whole number of LSBs.
+    pub fn write(self, value: i32, out: &mut Vec<u8>) {
+        match self {
+            SampleEncoding::U8 => out.push((value + 128) as u8),
+            SampleEncoding::S16le => out.extend_from_slice(&(value as i16).to_le_bytes()),
+            SampleEncoding::S24leIn32 => out.extend_from_slice(&(value << 8).to_le_bytes()),
+            SampleEncoding::S32le => out.extend_from_slice(&value.to_le_bytes()),
+        }
+    }
+
+    // Reads one sample back as a fraction of full scale.
+    pub fn read(self, bytes: &[u8]) -> f32 {
+        let value = match self {
+            SampleEncoding::U8 => bytes[0] as i32 - 128,
+            SampleEncoding::S16le => i16::from_le_bytes([bytes[0], bytes[1]]) as i32,
+            SampleEncoding::S24leIn32 => {
+                i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) >> 8
+            }
+            SampleEncoding::S32le => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        };
+        (value as f64 / self.full_scale()) as f32
+    }
+}
+
+// The stream the device expects: everything downstream of the mixer works in
+// stereo f32 at `sample_rate`, and is turned into this on the way out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceFormat {
+    pub sample_rate: u32,
+    // 1 mixes both channels down, 2 sends them as they are.
+    pub channels: u16,
+    pub encoding: SampleEncoding,
+}
+
+impl Default for DeviceFormat {
+    fn default() -> Self {
+        Self {
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            channels: 2,
+            encoding: SampleEncoding::S16le,
+        }
+    }
+}
+
+impl fmt::Display for DeviceFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let channels = if self.channels == 1 { "mono" } else { "stereo" };
+        write!(
+            f,
+            "{} Hz {} {}",
+            self.sample_rate,
+            self.encoding.name(),
+            channels
+        )
+    }
+}
+
+impl DeviceFormat {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=2).contains(&self.channels) {
+            return Err(format!("{} channels isn't mono or stereo", self.channels));
+        }
+        if self.sample_rate == 0 {
+            return Err("sample rate can't be zero".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn bytes_per_frame(&self) -> usize {
+        self.channels as usize * self.encoding.bytes()
+    }
+
+    pub fn frames_in(&self, bytes: usize) -> usize {
+        bytes / self.bytes_per_frame()
+    }
+
+    pub fn seconds(&self, frames: usize) -> f32 {
+        frames as f32 / self.sample_rate as f32
+    }
+
+    // Decodes what was sent back into interleaved stereo, mono going to both
+    // sides, for the meters and analyzer.
+    pub fn to_stereo(&self, data: &[u8]) -> Vec<f32> {
+        let bytes = self.encoding.bytes();
+        let mut samples = Vec::with_capacity(self.frames_in(data.len()) * 2);
+        for frame in data.chunks_exact(self.bytes_per_frame()) {
+            let left = self.encoding.read(frame);
+            let right = match self.channels {
+                1 => left,
+                _ => self.encoding.read(&frame[bytes..]),
+            };
+            samples.extend([left, right]);
+        }
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_encoding_round_trips_full_scale() {
+        for encoding in SampleEncoding::ALL {
+            let max = encoding.full_scale() as i64 - 1;
+            let mut out = Vec::new();
+            encoding.write(max as i32, &mut out);
+            encoding.write(-(max as i32) - 1, &mut out);
+            encoding.write(0, &mut out);
+            assert_eq!(out.len(), 3 * encoding.bytes(), "{:?}", encoding);
+
+            let read: Vec<f32> = out
+                .chunks_exact(encoding.bytes())
+                .map(|b| encoding.read(b))
+                .collect();
+            assert!(read[0] > 0.99 && read[0] <= 1.0, "{:?}", encoding);
+            assert_eq!(read[1], -1.0, "{:?}", encoding);
+            assert_eq!(read[2], 0.0, "{:?}", encoding);
+        }
+
+        let mut out = Vec::new();
+        SampleEncoding::S24leIn32.write(1, &mut out);
+        SampleEncoding::U8.write(0, &mut out);
+        assert_eq!(out, [0, 1, 0, 0, 128]);
+    }
+
+    #[test]
+    fn frame_math_follows_the_format() {
+        let format = DeviceFormat::default();
+        assert_eq!(format.bytes_per_frame(), 4);
+        assert_eq!(format.frames_in(4096), 1024);
+        assert_eq!(format.seconds(46875), 1.0);
+        assert_eq!(format.to_string(), "46875 Hz s16le stereo");
+
+        let mono = DeviceFormat {
+            sample_rate: 48000,
+            channels: 1,
+            encoding: SampleEncoding::S24leIn32,
+        };
+        assert_eq!(mono.bytes_per_frame(), 4);
+        let mut data = Vec::new();
+        mono.encoding.write(1 << 22, &mut data);
+        assert_eq!(mono.to_stereo(&data), [0.5, 0.5]);
+
+        assert!(mono.validate().is_ok());
+        assert!(
+            DeviceFormat {
+                channels: 6,
+                ..mono
+            }
+            .validate()
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,24 @@
+pub mod analyzer;
+pub mod app;
+pub mod biquad;
+pub mod cli;
+pub mod config;
+pub mod decode;
+pub mod dither;
+pub mod eq;
+pub mod fade;
+pub mod flow;
+pub mod format;
+pub mod limiter;
+pub mod link;
+pub mod live;
+pub mod loudness;
+pub mod meter;
+pub mod player;
+pub mod ports;
+pub mod protocol;
+pub mod resample;
+pub mod ring;
+pub mod sink;
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,268 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+// Just under full scale, so rounding to 16 bits can't wrap.
+const CEILING: f32 = 0.989;
+const LOOKAHEAD_SECONDS: f32 = 0.005;
+const RELEASE_SECONDS: f32 = 0.1;
+// Soft clipping starts bending the curve here.
+const KNEE: f32 = 0.8;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LimiterMode {
+    /// Saturate anything past full scale
+    Off,
+    /// Round off peaks with a soft knee, which colours loud material a
+    /// little but never clips
+    SoftClip,
+    /// Turn the gain down just ahead of a peak and back up afterwards
+    #[default]
+    Lookahead,
+}
+
+impl LimiterMode {
+    pub const ALL: [LimiterMode; 3] = [
+        LimiterMode::Off,
+        LimiterMode::SoftClip,
+        LimiterMode::Lookahead,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            LimiterMode::Off => "Hard clip",
+            LimiterMode::SoftClip => "Soft clip",
+            LimiterMode::Lookahead => "Limiter",
+        }
+    }
+}
+
+// Look-ahead peak limiter over interleaved stereo. Audio is delayed by the
+// look-ahead so the gain can already be down when a peak arrives: the gain
+// each frame needs is held at its minimum over the look-ahead window, eased
+// back up with the release time, and then averaged over the window so it
+// ramps instead of stepping.
+pub struct Limiter {
+    lookahead: usize,
+    release: f32,
+    delay: VecDeque<[f32; 2]>,
+    // Candidates for the window minimum as (frame, gain), gains increasing.
+    window: VecDeque<(u64, f32)>,
+    envelope: f32,
+    smoothing: VecDeque<f32>,
+    smoothing_sum: f64,
+    frame: u64,
+}
+
+impl Limiter {
+    pub fn new(sample_rate: u32) -> Self {
+        let lookahead = ((sample_rate as f32 * LOOKAHEAD_SECONDS) as usize).max(1);
+        Self {
+            lookahead,
+            release: 1.0 - (-1.0 / (sample_rate as f32 * RELEASE_SECONDS)).exp(),
+            delay: VecDeque::from(vec![[0.0; 2]; lookahead]),
+            window: VecDeque::new(),
+            envelope: 1.0,
+            smoothing: VecDeque::from(vec![1.0; lookahead]),
+            smoothing_sum: lookahead as f64,
+            frame: 0,
+        }
+    }
+
+    // Frames between a sample going in and coming out.
+    pub fn latency(&self) -> usize {
+        self.lookahead
+    }
+
+    // Replaces `samples` with the limited audio from `latency()` frames
+    // earlier. With `engaged` off the gain eases back to unity and the audio
+    // only goes through the delay, so switching modes doesn't cause a jump.
+    pub fn process(&mut self, samples: &mut [f32], engaged: bool) {
+        for frame in samples.chunks_exact_mut(2) {
+            let peak = frame[0].abs().max(frame[1].abs());
+            let needed = if engaged && peak > CEILING {
+                CEILING / peak
+            } else {
+                1.0
+            };
+            let gain = self.gain(needed);
+
+            let delayed = self.delay.pop_front().unwrap();
+            self.delay.push_back([frame[0], frame[1]]);
+            frame[0] = delayed[0] * gain;
+            frame[1] = delayed[1] * gain;
+        }
+    }
+
+    // The audio still held back by the look-ahead, once nothing else is
+    // coming.
+    pub fn drain(&mut self, engaged: bool) -> Vec<f32> {
+        let mut tail = vec![0.0; self.lookahead * 2];
+        self.process(&mut tail, engaged);
+        tail
+    }
+
+    fn gain(&mut self, needed: f32) -> f32 {
+        // The window covers this frame and the `lookahead` before it, which
+        // includes the frame leaving the delay line.
+        while self.window.back().is_some_and(|&(_, gain)| gain >= needed) {
+            self.window.pop_back();
+        }
+        self.window.push_back((self.frame, needed));
+        while self
+            .window
+            .front()
+            .is_some_and(|&(frame, _)| frame + (self.lookahead as u64) < self.frame)
+        {
+            self.window.pop_front();
+        }
+        self.frame += 1;
+        let held = self.window.front().unwrap().1;
+
+        self.envelope = if held <= self.envelope {
+            held
+        } else {
+            self.envelope + (held - self.envelope) * self.release
+        };
+
+        self.smoothing_sum -= self.smoothing.pop_front().unwrap() as f64;
+        self.smoothing.push_back(self.envelope);
+        self.smoothing_sum += self.envelope as f64;
+        (self.smoothing_sum / self.lookahead as f64) as f32
+    }
+}
+
+// Leaves everything under the knee alone and bends the rest smoothly towards
+// full scale.
+pub fn soft_clip(sample: f32) -> f32 {
+    let magnitude = sample.abs();
+    if magnitude <= KNEE {
+        return sample;
+    }
+    let headroom = 1.0 - KNEE;
+    let bent = KNEE + headroom * ((magnitude - KNEE) / headroom).tanh();
+    bent.copysign(sample)
+}
+
+// Frames with a sample past full scale, the ones that would clip.
+pub fn count_overs(samples: &[f32]) -> usize {
+    samples
+        .chunks_exact(2)
+        .filter(|frame| frame[0].abs() > 1.0 || frame[1].abs() > 1.0)
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    fn tone(frames: usize, amplitude: impl Fn(usize) -> f32) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|n| {
+                let s = amplitude(n) * (n as f32 * 0.07).sin();
+                [s, -s]
+            })
+            .collect()
+    }
+
+    fn limit(samples: &[f32], block: usize) -> Vec<f32> {
+        let mut limiter = Limiter::new(RATE);
+        let mut out = Vec::new();
+        for piece in samples.chunks(block * 2) {
+            let mut piece = piece.to_vec();
+            limiter.process(&mut piece, true);
+            out.extend(piece);
+        }
+        out.extend(limiter.drain(true));
+        out
+    }
+
+    #[test]
+    fn quiet_audio_comes_out_delayed_and_untouched() {
+        let input = tone(2000, |_| 0.5);
+        let latency = Limiter::new(RATE).latency();
+        let out = limit(&input, 300);
+
+        assert_eq!(latency, 240);
+        assert!(out[..latency * 2].iter().all(|&s| s == 0.0));
+        assert_eq!(&out[latency * 2..], &input[..]);
+    }
+
+    #[test]
+    fn peaks_never_pass_the_ceiling() {
+        // A quiet passage, a sudden burst at 4x full scale, then moderate.
+        let input = tone(20000, |n| match n {
+            0..5000 => 0.3,
+            5000..5003 => 4.0,
+            5003..12000 => 1.6,
+            _ => 0.5,
+        });
+        let out = limit(&input, 512);
+        let peak = out.iter().fold(0f32, |m, s| m.max(s.abs()));
+        assert!(peak <= CEILING + 1e-6, "peak {}", peak);
+        assert_eq!(count_overs(&out), 0);
+    }
+
+    #[test]
+    fn gain_ramps_instead_of_stepping() {
+        // A step from 0.5 to 2.0 on a DC signal shows the gain directly.
+        let input: Vec<f32> = (0..4000)
+            .flat_map(|n| if n < 1000 { [0.5, 0.5] } else { [2.0, 2.0] })
+            .collect();
+        let out = limit(&input, 64);
+        let gains: Vec<f32> = out
+            .chunks_exact(2)
+            .skip(240)
+            .zip(input.chunks_exact(2))
+            .map(|(out, input)| out[0] / input[0])
+            .collect();
+        let largest_step = gains
+            .windows(2)
+            .map(|w| (w[1] - w[0]).abs())
+            .fold(0f32, f32::max);
+        assert!(
+            largest_step <= (1.0 - CEILING / 2.0) / 240.0 + 1e-5,
+            "{}",
+            largest_step
+        );
+        assert!((gains[3000] * 2.0 - CEILING).abs() < 1e-4);
+    }
+
+    #[test]
+    fn result_does_not_depend_on_block_size() {
+        let input = tone(6000, |n| if n % 1500 < 200 { 1.8 } else { 0.6 });
+        assert_eq!(limit(&input, 1), limit(&input, 1000));
+    }
+
+    #[test]
+    fn released_gain_returns_to_unity() {
+        let input = tone(RATE as usize, |n| if n < 1000 { 3.0 } else { 0.5 });
+        let out = limit(&input, 512);
+        let late = &out[(RATE as usize - 2000) * 2..];
+        let expected = &input[(RATE as usize - 2000 - 240) * 2..(RATE as usize - 240) * 2];
+        for (a, b) in late.iter().zip(expected) {
+            assert!((a - b).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn soft_clip_is_smooth_and_bounded() {
+        assert_eq!(soft_clip(0.5), 0.5);
+        assert_eq!(soft_clip(-KNEE), -KNEE);
+        assert!(soft_clip(10.0) <= 1.0);
+        assert_eq!(soft_clip(-3.0), -soft_clip(3.0));
+
+        let curve: Vec<f32> = (0..400).map(|n| soft_clip(n as f32 * 0.01)).collect();
+        assert!(curve.windows(2).all(|w| w[1] >= w[0]));
+        // Slope is continuous through the knee.
+        let slope = (soft_clip(KNEE + 1e-3) - soft_clip(KNEE)) / 1e-3;
+        assert!((slope - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn counts_frames_past_full_scale() {
+        assert_eq!(count_overs(&[0.5, 1.0, -1.2, 0.0, 0.3, 1.5, 2.0, 2.0]), 3);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::format::DeviceFormat;
+use crate::ports::PortInfo;
+use crate::sink::{self, AudioSink, Protocol, SinkKind};
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+
+// Pause between scans while waiting for a device to come back.
+pub const RECONNECT_POLL: Duration = Duration::from_millis(250);
+pub const DEFAULT_RECONNECT_TIMEOUT: f32 = 30.0;
+pub const MAX_RECONNECT_TIMEOUT: f32 = 3600.0;
+
+// How to get a serial output back after the board resets or the cable
+// glitches: the device, recognised by its USB serial number, and the protocol
+// spoken on it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Link {
+    pub device: PortInfo,
+    pub protocol: Protocol,
+}
+
+impl Link {
+    pub fn reopen(
+        &self,
+        port: &PortInfo,
+        format: DeviceFormat,
+    ) -> Result<Box<dyn AudioSink>, Box<dyn std::error::Error>> {
+        sink::open_sink(SinkKind::Serial, &port.name, format, self.protocol)
+    }
+}
+
+// A write to a supervised link failed. Playback waits for the device to
+// return instead of treating it as a broken track.
+#[derive(Debug)]
+pub struct LinkLost(pub String);
+
+impl fmt::Display for LinkLost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for LinkLost {}
+
+// Scans until `device` is back, possibly under a new name, or until
+// `keep_waiting` says to give up.
+pub fn wait_for(
+    device: &PortInfo,
+    mut scan: impl FnMut() -> Vec<PortInfo>,
+    poll: Duration,
+    mut keep_waiting: impl FnMut() -> bool,
+) -> Option<PortInfo> {
+    loop {
+        if let Some(port) = scan().into_iter().find(|p| p.same_device(device)) {
+            return Some(port);
+        }
+        if !keep_waiting() {
+            return None;
+        }
+        thread::sleep(poll);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ports::UsbInfo;
+
+    fn dac(name: &str) -> PortInfo {
+        PortInfo {
+            name: name.to_string(),
+            usb: Some(UsbInfo {
+                vid: 0x0483,
+                pid: 0x5740,
+                serial_number: Some("206A36A15748".to_string()),
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn finds_the_device_under_its_new_name() {
+        let mut scans = vec![
+            vec![],
+            vec![PortInfo::named("/dev/ttyS0")],
+            vec![PortInfo::named("/dev/ttyS0"), dac("/dev/ttyACM1")],
+        ]
+        .into_iter();
+        let found = wait_for(
+            &dac("/dev/ttyACM0"),
+            || scans.next().unwrap_or_default(),
+            Duration::ZERO,
+            || true,
+        );
+        assert_eq!(found, Some(dac("/dev/ttyACM1")));
+    }
+
+    #[test]
+    fn stops_waiting_when_told_to() {
+        let mut polls = 0;
+        let found = wait_for(&dac("/dev/ttyACM0"), Vec::new, Duration::ZERO, || {
+            polls += 1;
+            polls < 3
+        });
+        assert_eq!(found, None);
+        assert_eq!(polls, 3);
+    }
+
+    #[test]
+    fn link_loss_can_be_told_apart_from_other_errors() {
+        let lost: Box<dyn std::error::Error> = Box::new(LinkLost("unplugged".to_string()));
+        let other: Box<dyn std::error::Error> = "bad file".into();
+        assert!(lost.is::<LinkLost>());
+        assert!(!other.is::<LinkLost>());
+        assert_eq!(lost.to_string(), "unplugged");
+    }
+}
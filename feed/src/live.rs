@@ -0,0 +1,295 @@
+use crate::decode::{Decoder, FfmpegDecoder, Interrupt};
+use crate::format::{DeviceFormat, SampleEncoding};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, Read};
+use std::process::Stdio;
+
+// The path that stands for standard input.
+pub const STDIN: &str = "-";
+
+// Seconds of live input held back to ride out a source that stalls.
+pub const DEFAULT_JITTER_BUFFER: f32 = 0.2;
+// Half of what the decode ring holds, so the buffer can always fill.
+pub const MAX_JITTER_BUFFER: f32 = 1.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InputFormat {
+    /// Anything ffmpeg can decode from a stream, e.g. WAV or MP3
+    #[default]
+    Auto,
+    /// Headerless PCM laid out as the --input-* options say
+    Raw,
+}
+
+impl InputFormat {
+    pub const ALL: [InputFormat; 2] = [InputFormat::Auto, InputFormat::Raw];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            InputFormat::Auto => "Detect with ffmpeg",
+            InputFormat::Raw => "Raw PCM",
+        }
+    }
+}
+
+// How audio arriving on stdin or a named pipe is read.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LiveInput {
+    pub format: InputFormat,
+    // Layout of raw input; ignored when ffmpeg works it out.
+    pub pcm: DeviceFormat,
+    // Seconds buffered before playing, and again after the source falls
+    // behind.
+    pub jitter_buffer: f32,
+}
+
+impl Default for LiveInput {
+    fn default() -> Self {
+        Self {
+            format: InputFormat::Auto,
+            pcm: DeviceFormat {
+                sample_rate: 48000,
+                channels: 2,
+                encoding: SampleEncoding::S16le,
+            },
+            jitter_buffer: DEFAULT_JITTER_BUFFER,
+        }
+    }
+}
+
+impl LiveInput {
+    // Samples of device-rate stereo the jitter buffer holds.
+    pub fn jitter_samples(&self, sample_rate: u32) -> usize {
+        (self.jitter_buffer.max(0.0) * sample_rate as f32) as usize * 2
+    }
+}
+
+// Whether `path` is a source that can only be read once, front to back.
+pub fn is_live(path: &str) -> bool {
+    path == STDIN || is_fifo(path)
+}
+
+#[cfg(unix)]
+fn is_fifo(path: &str) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    std::fs::metadata(path).is_ok_and(|m| m.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_path: &str) -> bool {
+    false
+}
+
+// Opening a named pipe waits for a writer, so it's left to whoever reads it
+// rather than done on the playback thread.
+pub fn open(path: &str, input: &LiveInput) -> Result<Box<dyn Decoder>, Box<dyn std::error::Error>> {
+    match input.format {
+        InputFormat::Raw => {
+            input.pcm.validate()?;
+            let source: Box<dyn Read + Send> = match path {
+                STDIN => Box::new(io::stdin()),
+                _ => Box::new(Pipe {
+                    path: path.to_string(),
+                    file: None,
+                }),
+            };
+            Ok(Box::new(RawDecoder::new(source, input.pcm)))
+        }
+        InputFormat::Auto => {
+            let decoder = match path {
+                STDIN => FfmpegDecoder::spawn_pipe("pipe:0", Stdio::inherit())?,
+                _ => FfmpegDecoder::spawn_pipe(&format!("file:{}", path), Stdio::null())?,
+            };
+            Ok(Box::new(decoder))
+        }
+    }
+}
+
+// A named pipe that is opened on the first read.
+struct Pipe {
+    path: String,
+    file: Option<File>,
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.file.is_none() {
+            self.file = Some(File::open(&self.path)?);
+        }
+        self.file.as_mut().unwrap().read(buf)
+    }
+}
+
+// Headerless PCM, taken as it comes.
+pub struct RawDecoder {
+    source: Box<dyn Read + Send>,
+    format: DeviceFormat,
+    pending: Vec<u8>,
+}
+
+impl RawDecoder {
+    pub fn new(source: Box<dyn Read + Send>, format: DeviceFormat) -> Self {
+        Self {
+            source,
+            format,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl Decoder for RawDecoder {
+    fn sample_rate(&self) -> u32 {
+        self.format.sample_rate
+    }
+
+    fn duration(&self) -> Option<f32> {
+        None
+    }
+
+    fn decode(&mut self, out: &mut Vec<f32>) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut buf = [0u8; 8192];
+        let n = match self.source.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(true),
+            Err(e) => return Err(e.into()),
+        };
+        if n == 0 {
+            // A partial frame left at the end is dropped.
+            return Ok(false);
+        }
+
+        self.pending.extend_from_slice(&buf[..n]);
+        let whole = self.pending.len() - self.pending.len() % self.format.bytes_per_frame();
+        out.extend(self.format.to_stereo(&self.pending[..whole]));
+        self.pending.drain(..whole);
+        Ok(true)
+    }
+
+    fn seek(&mut self, _seconds: f32) -> Result<(), Box<dyn std::error::Error>> {
+        Err("can't seek in live input".into())
+    }
+
+    // A blocked read can't be woken, but marks the source as live so the
+    // thread reading it isn't waited for.
+    fn interrupt(&self) -> Option<Interrupt> {
+        Some(Box::new(|| {}))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::DecodeStream;
+    use crate::resample::ResampleQuality;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    // Hands out its bytes a few at a time, the way a pipe does.
+    struct Trickle(Vec<u8>, usize);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.1.min(buf.len()).min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0.drain(..n);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn raw_input_keeps_frames_whole_across_reads() {
+        let format = DeviceFormat {
+            sample_rate: 8000,
+            channels: 1,
+            encoding: SampleEncoding::S16le,
+        };
+        let mut data = Vec::new();
+        for value in [16384, -16384, 0] {
+            format.encoding.write(value, &mut data);
+        }
+        data.push(0x7f);
+        let mut decoder = RawDecoder::new(Box::new(Trickle(data, 3)), format);
+
+        let mut out = Vec::new();
+        while decoder.decode(&mut out).unwrap() {}
+        assert_eq!(out, [0.5, 0.5, -0.5, -0.5, 0.0, 0.0]);
+        assert_eq!(decoder.duration(), None);
+        assert!(decoder.seek(1.0).is_err());
+    }
+
+    // A named pipe nobody writes to yet.
+    #[cfg(unix)]
+    fn fifo(name: &str) -> String {
+        let path = crate::test_util::temp_path(name, "pipe");
+        let _ = std::fs::remove_file(&path);
+        let made = std::process::Command::new("mkfifo").arg(&path).status();
+        assert!(made.unwrap().success());
+        path.to_str().unwrap().to_string()
+    }
+
+    #[cfg(unix)]
+    fn raw_input() -> LiveInput {
+        LiveInput {
+            format: InputFormat::Raw,
+            ..Default::default()
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_pipe_with_no_writer_yet_opens_straight_away() {
+        use std::io::Write;
+
+        let path = fifo("live-fifo");
+        assert!(is_live(&path));
+
+        let input = raw_input();
+        let (tx, rx) = mpsc::channel();
+        let opening = path.clone();
+        std::thread::spawn(move || tx.send(open(&opening, &input).map_err(|e| e.to_string())));
+        let mut decoder = rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+
+        let writer = std::thread::spawn({
+            let path = path.clone();
+            move || {
+                let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+                file.write_all(&[0, 0x40, 0, 0xc0]).unwrap();
+            }
+        });
+        let mut out = Vec::new();
+        while decoder.decode(&mut out).unwrap() {}
+        writer.join().unwrap();
+        assert_eq!(out, [0.5, -0.5]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dropping_a_stream_over_an_idle_pipe_returns() {
+        let path = fifo("live-idle");
+        let decoder = open(&path, &raw_input()).unwrap();
+        let stream = DecodeStream::spawn(decoder, 48000, ResampleQuality::default());
+
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            drop(stream);
+            tx.send(())
+        });
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // Lets the thread that was left behind finish opening.
+        let _ = std::fs::OpenOptions::new().write(true).open(&path);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn only_stdin_and_pipes_are_live() {
+        assert!(is_live(STDIN));
+        assert!(!is_live("Cargo.toml"));
+        assert!(!is_live("no such file"));
+        assert_eq!(LiveInput::default().jitter_samples(48000), 19200);
+    }
+}
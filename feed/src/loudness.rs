@@ -0,0 +1,710 @@
+use crate::biquad::Biquad;
+use crate::decode::{self, Decoder, DecoderBackend};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+// Loudness that ReplayGain 2 gains are relative to.
+pub const REFERENCE_LUFS: f32 = -18.0;
+
+// Furthest the preamp goes either way, in dB.
+pub const MAX_PREAMP: f32 = 15.0;
+
+// Opus R128 gains are relative to -23 LUFS instead.
+const R128_OFFSET: f32 = REFERENCE_LUFS - -23.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GainMode {
+    /// Play every track as it is
+    #[default]
+    Off,
+    /// Bring every track to the same loudness
+    Track,
+    /// Keep the level differences within an album, using track gain when a
+    /// file has no album tags; untagged files are measured on their own, so
+    /// they get track gain too
+    Album,
+}
+
+impl GainMode {
+    pub const ALL: [GainMode; 3] = [GainMode::Off, GainMode::Track, GainMode::Album];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            GainMode::Off => "No normalization",
+            GainMode::Track => "Track gain",
+            GainMode::Album => "Album gain",
+        }
+    }
+}
+
+// Gains in dB and sample peaks as read from a file's tags.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl ReplayGain {
+    // Picks up REPLAYGAIN_* and R128_* tags, whatever the container calls
+    // them otherwise. ReplayGain tags win over R128 ones.
+    pub fn from_tags<'a>(tags: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        let mut gain = Self::default();
+        let mut r128 = Self::default();
+        for (key, value) in tags {
+            // ID3 user frames come through as `TXXX:name`.
+            let key = key.rsplit(':').next().unwrap_or(key).to_ascii_uppercase();
+            match key.as_str() {
+                "REPLAYGAIN_TRACK_GAIN" => gain.track_gain = parse_db(value),
+                "REPLAYGAIN_TRACK_PEAK" => gain.track_peak = value.trim().parse().ok(),
+                "REPLAYGAIN_ALBUM_GAIN" => gain.album_gain = parse_db(value),
+                "REPLAYGAIN_ALBUM_PEAK" => gain.album_peak = value.trim().parse().ok(),
+                "R128_TRACK_GAIN" => r128.track_gain = parse_q78(value),
+                "R128_ALBUM_GAIN" => r128.album_gain = parse_q78(value),
+                _ => {}
+            }
+        }
+        if gain.track_gain.is_none() {
+            gain.track_gain = r128.track_gain;
+        }
+        if gain.album_gain.is_none() {
+            gain.album_gain = r128.album_gain;
+        }
+        gain
+    }
+
+    pub fn select(&self, mode: GainMode) -> Option<Normalization> {
+        let track = self.track_gain.map(|gain| Normalization {
+            gain,
+            peak: self.track_peak,
+        });
+        match mode {
+            GainMode::Off => None,
+            GainMode::Track => track,
+            GainMode::Album => self
+                .album_gain
+                .map(|gain| Normalization {
+                    gain,
+                    peak: self.album_peak,
+                })
+                .or(track),
+        }
+    }
+}
+
+// "-6.52 dB" and friends.
+fn parse_db(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let number = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value);
+    number.trim().trim_start_matches('+').parse().ok()
+}
+
+// R128 gains are Q7.8 fixed point.
+fn parse_q78(value: &str) -> Option<f32> {
+    let raw: i32 = value.trim().parse().ok()?;
+    Some(raw as f32 / 256.0 + R128_OFFSET)
+}
+
+// Gain to bring a track to the reference loudness, with the peak it reaches
+// before that gain when it's known.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Normalization {
+    pub gain: f32,
+    pub peak: Option<f32>,
+}
+
+impl Normalization {
+    pub fn from_analysis(analysis: Analysis) -> Option<Self> {
+        analysis.loudness.map(|loudness| Self {
+            gain: REFERENCE_LUFS - loudness,
+            peak: Some(analysis.peak),
+        })
+    }
+
+    // Linear factor with `preamp` dB on top. Clip prevention holds it back so
+    // the peak never goes past full scale.
+    pub fn factor(&self, preamp: f32, clip_prevention: bool) -> f32 {
+        let factor = db_to_linear(self.gain + preamp);
+        match self.peak {
+            Some(peak) if clip_prevention && peak > 0.0 => factor.min(1.0 / peak),
+            _ => factor,
+        }
+    }
+}
+
+pub fn db_to_linear(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Analysis {
+    // Integrated loudness in LUFS, None for a track that's silent throughout.
+    pub loudness: Option<f32>,
+    pub peak: f32,
+}
+
+// Gain for a track from its tags, or failing that from a measurement of the
+// file. A measurement only ever covers the one file, so album mode falls back
+// to track loudness for untagged files.
+pub fn normalization(
+    mode: GainMode,
+    tags: ReplayGain,
+    measured: Option<Analysis>,
+) -> Option<Normalization> {
+    if mode == GainMode::Off {
+        return None;
+    }
+    tags.select(mode)
+        .or_else(|| measured.and_then(Normalization::from_analysis))
+}
+
+// Decodes the whole file and measures it.
+pub fn analyze(
+    path: &str,
+    backend: DecoderBackend,
+) -> Result<Analysis, Box<dyn std::error::Error>> {
+    measure(decode::open(path, backend)?)
+}
+
+fn measure(mut decoder: Box<dyn Decoder>) -> Result<Analysis, Box<dyn std::error::Error>> {
+    let mut meter = LoudnessMeter::new(decoder.sample_rate());
+    let mut samples = Vec::new();
+    loop {
+        samples.clear();
+        let more = decoder.decode(&mut samples)?;
+        meter.process(&samples);
+        if !more {
+            break;
+        }
+    }
+    Ok(meter.finish())
+}
+
+// Integrated loudness of interleaved stereo after ITU-R BS.1770: K-weighted
+// power over 400 ms blocks overlapping by 75%, gated at -70 LUFS and then at
+// 10 LU below the loudness of what's left.
+pub struct LoudnessMeter {
+    filters: [[Biquad; 2]; 2],
+    // Frames in a 100 ms step; four steps make a block.
+    step: usize,
+    step_frames: usize,
+    step_power: f64,
+    steps: [f64; 4],
+    steps_seen: usize,
+    blocks: Vec<f64>,
+    peak: f32,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: u32) -> Self {
+        let filter = || [k_shelf(sample_rate), k_highpass(sample_rate)];
+        Self {
+            filters: [filter(), filter()],
+            step: (sample_rate as usize / 10).max(1),
+            step_frames: 0,
+            step_power: 0.0,
+            steps: [0.0; 4],
+            steps_seen: 0,
+            blocks: Vec::new(),
+            peak: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(2) {
+            for (channel, &sample) in frame.iter().enumerate() {
+                self.peak = self.peak.max(sample.abs());
+                let [shelf, highpass] = &mut self.filters[channel];
+                let weighted = highpass.process(shelf.process(sample as f64));
+                self.step_power += weighted * weighted;
+            }
+            self.step_frames += 1;
+            if self.step_frames == self.step {
+                self.end_step();
+            }
+        }
+    }
+
+    fn end_step(&mut self) {
+        self.steps[self.steps_seen % 4] = self.step_power / self.step as f64;
+        self.steps_seen += 1;
+        self.step_power = 0.0;
+        self.step_frames = 0;
+        if self.steps_seen >= 4 {
+            self.blocks.push(self.steps.iter().sum::<f64>() / 4.0);
+        }
+    }
+
+    pub fn finish(&self) -> Analysis {
+        let loudness = |power: f64| -0.691 + 10.0 * power.log10();
+        let mean_power = |threshold: f64| {
+            let gated: Vec<f64> = self
+                .blocks
+                .iter()
+                .copied()
+                .filter(|&power| power > 0.0 && loudness(power) > threshold)
+                .collect();
+            (!gated.is_empty()).then(|| gated.iter().sum::<f64>() / gated.len() as f64)
+        };
+
+        let integrated = mean_power(-70.0)
+            .and_then(|power| mean_power(loudness(power) - 10.0))
+            .map(|power| loudness(power) as f32);
+        Analysis {
+            loudness: integrated,
+            peak: self.peak,
+        }
+    }
+}
+
+// High-frequency shelf of the K-weighting curve, from the 48 kHz prototype in
+// BS.1770 re-derived for `sample_rate`.
+fn k_shelf(sample_rate: u32) -> Biquad {
+    let gain = 3.999_843_853_973_347f64;
+    let q = 0.707_175_236_955_419_6;
+    let k = (std::f64::consts::PI * 1_681.974_450_955_533 / sample_rate as f64).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.499_666_774_154_541_6);
+    let a0 = 1.0 + k / q + k * k;
+    Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    )
+}
+
+// Low-frequency roll-off of the K-weighting curve.
+fn k_highpass(sample_rate: u32) -> Biquad {
+    let q = 0.500_327_037_323_877_3;
+    let k = (std::f64::consts::PI * 38.135_470_876_024_44 / sample_rate as f64).tan();
+    let a0 = 1.0 + k / q + k * k;
+    Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    )
+}
+
+// Analysis results keyed by path, kept valid by the file's size and
+// modification time. Stored as an append-only text file where later lines
+// win; it's loaded on first use.
+pub struct LoudnessCache {
+    path: Option<PathBuf>,
+    entries: Mutex<Option<HashMap<String, CacheEntry>>>,
+    // Files handed to the background worker that it hasn't finished yet.
+    pending: Mutex<HashSet<String>>,
+    worker: Mutex<Option<Sender<(String, DecoderBackend)>>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct CacheEntry {
+    size: u64,
+    modified: u64,
+    analysis: Analysis,
+}
+
+impl Default for LoudnessCache {
+    fn default() -> Self {
+        Self::at(dirs::cache_dir().map(|dir| dir.join("feed").join("loudness.tsv")))
+    }
+}
+
+impl LoudnessCache {
+    // None keeps results in memory only.
+    pub fn at(path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            entries: Mutex::new(None),
+            pending: Mutex::default(),
+            worker: Mutex::new(None),
+        }
+    }
+
+    // The up-to-date result for `path`, without measuring anything.
+    pub fn cached(&self, path: &str) -> Option<Analysis> {
+        let (size, modified) = file_stamp(path)?;
+        self.with_entries(|entries| entries.get(path).copied())
+            .filter(|entry| entry.size == size && entry.modified == modified)
+            .map(|entry| entry.analysis)
+    }
+
+    // Has `path` measured on a background thread, one file at a time, so the
+    // result is cached by the time it's played. Files with loudness tags are
+    // skipped since they don't need it.
+    pub fn measure_later(self: &Arc<Self>, path: &str, backend: DecoderBackend) {
+        if self.cached(path).is_some() || !self.pending.lock().unwrap().insert(path.to_string()) {
+            return;
+        }
+        let mut worker = self.worker.lock().unwrap();
+        let worker = worker.get_or_insert_with(|| {
+            let (jobs, queued) = mpsc::channel::<(String, DecoderBackend)>();
+            // Only a weak handle, so the cache and with it the sender can go
+            // away and end the thread.
+            let cache = Arc::downgrade(self);
+            thread::spawn(move || {
+                for (path, backend) in queued {
+                    let Some(cache) = cache.upgrade() else {
+                        break;
+                    };
+                    if let Err(e) = cache.measure_untagged(&path, backend) {
+                        eprintln!("Failed to measure {}: {}", path, e);
+                    }
+                    cache.pending.lock().unwrap().remove(&path);
+                }
+            });
+            jobs
+        });
+        let _ = worker.send((path.to_string(), backend));
+    }
+
+    fn measure_untagged(
+        &self,
+        path: &str,
+        backend: DecoderBackend,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.cached(path).is_some() {
+            return Ok(());
+        }
+        let decoder = decode::open(path, backend)?;
+        if decoder.replay_gain().track_gain.is_none() {
+            let analysis = measure(decoder)?;
+            self.store(path, analysis);
+        }
+        Ok(())
+    }
+
+    // Measures `path` unless an up-to-date result is cached.
+    pub fn analyze(
+        &self,
+        path: &str,
+        backend: DecoderBackend,
+    ) -> Result<Analysis, Box<dyn std::error::Error>> {
+        if let Some(analysis) = self.cached(path) {
+            return Ok(analysis);
+        }
+
+        let analysis = analyze(path, backend)?;
+        self.store(path, analysis);
+        Ok(analysis)
+    }
+
+    fn store(&self, path: &str, analysis: Analysis) {
+        if let Some((size, modified)) = file_stamp(path) {
+            let entry = CacheEntry {
+                size,
+                modified,
+                analysis,
+            };
+            self.with_entries(|entries| entries.insert(path.to_string(), entry));
+            if let Err(e) = self.append(path, &entry) {
+                eprintln!("Failed to update the loudness cache: {}", e);
+            }
+        }
+    }
+
+    fn with_entries<T>(&self, f: impl FnOnce(&mut HashMap<String, CacheEntry>) -> T) -> T {
+        let mut entries = self.entries.lock().unwrap();
+        let entries = entries.get_or_insert_with(|| self.load());
+        f(entries)
+    }
+
+    fn load(&self) -> HashMap<String, CacheEntry> {
+        let mut entries = HashMap::new();
+        let Some(file) = self.path.as_ref().and_then(|path| File::open(path).ok()) else {
+            return entries;
+        };
+        for line in BufReader::new(file).lines().map_while(Result::ok) {
+            if let Some((path, entry)) = parse_entry(&line) {
+                entries.insert(path.to_string(), entry);
+            }
+        }
+        entries
+    }
+
+    fn append(&self, path: &str, entry: &CacheEntry) -> std::io::Result<()> {
+        let Some(cache) = self.path.as_ref() else {
+            return Ok(());
+        };
+        if let Some(dir) = cache.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let loudness = entry
+            .analysis
+            .loudness
+            .map_or("-".to_string(), |l| l.to_string());
+        let mut file = OpenOptions::new().create(true).append(true).open(cache)?;
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{}\t{}",
+            entry.size, entry.modified, loudness, entry.analysis.peak, path
+        )
+    }
+}
+
+fn parse_entry(line: &str) -> Option<(&str, CacheEntry)> {
+    let mut fields = line.splitn(5, '\t');
+    let size = fields.next()?.parse().ok()?;
+    let modified = fields.next()?.parse().ok()?;
+    let loudness = match fields.next()? {
+        "-" => None,
+        value => Some(value.parse().ok()?),
+    };
+    let peak = fields.next()?.parse().ok()?;
+    let path = fields.next()?;
+    Some((
+        path,
+        CacheEntry {
+            size,
+            modified,
+            analysis: Analysis { loudness, peak },
+        },
+    ))
+}
+
+fn file_stamp(path: &str) -> Option<(u64, u64)> {
+    let metadata = fs::metadata(Path::new(path)).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((metadata.len(), modified.as_secs()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    fn sine(sample_rate: u32, frequency: f32, amplitude: f32, seconds: f32) -> Vec<f32> {
+        let frames = (sample_rate as f32 * seconds) as usize;
+        (0..frames)
+            .flat_map(|n| {
+                let phase = 2.0 * std::f32::consts::PI * frequency * n as f32 / sample_rate as f32;
+                let s = amplitude * phase.sin();
+                [s, s]
+            })
+            .collect()
+    }
+
+    fn measure(sample_rate: u32, samples: &[f32]) -> Analysis {
+        let mut meter = LoudnessMeter::new(sample_rate);
+        for block in samples.chunks(1234) {
+            meter.process(block);
+        }
+        meter.finish()
+    }
+
+    #[test]
+    fn full_scale_1k_sine_in_both_channels_reads_about_zero_lufs() {
+        // BS.1770 calibrates a 0 dBFS 1 kHz sine in one channel to -3.01.
+        for rate in [44100, 48000, 96000] {
+            let analysis = measure(rate, &sine(rate, 1000.0, 1.0, 3.0));
+            let loudness = analysis.loudness.unwrap();
+            assert!(loudness.abs() < 0.1, "{} Hz: {}", rate, loudness);
+            assert!((analysis.peak - 1.0).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn loudness_follows_level() {
+        let loud = measure(48000, &sine(48000, 1000.0, 0.5, 3.0))
+            .loudness
+            .unwrap();
+        let quiet = measure(48000, &sine(48000, 1000.0, 0.05, 3.0))
+            .loudness
+            .unwrap();
+        assert!((loud - quiet - 20.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn gating_ignores_silence_and_quiet_passages() {
+        let tone = measure(48000, &sine(48000, 1000.0, 0.25, 4.0))
+            .loudness
+            .unwrap();
+
+        let mut padded = vec![0.0; 48000 * 2 * 5];
+        padded.extend(sine(48000, 1000.0, 0.25, 4.0));
+        // 30 dB down, which the relative gate drops.
+        padded.extend(sine(48000, 1000.0, 0.008, 4.0));
+        let gated = measure(48000, &padded).loudness.unwrap();
+        // Blocks straddling the edges of the tone still count; averaging
+        // everything would land about 5 dB lower.
+        assert!((gated - tone).abs() < 0.5, "{} vs {}", gated, tone);
+    }
+
+    #[test]
+    fn k_weighting_favours_treble_over_bass() {
+        let bass = measure(48000, &sine(48000, 40.0, 0.5, 3.0))
+            .loudness
+            .unwrap();
+        let treble = measure(48000, &sine(48000, 8000.0, 0.5, 3.0))
+            .loudness
+            .unwrap();
+        assert!(treble - bass > 4.0, "{} vs {}", treble, bass);
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        let analysis = measure(48000, &vec![0.0; 48000 * 2]);
+        assert_eq!(analysis.loudness, None);
+        assert_eq!(Normalization::from_analysis(analysis), None);
+    }
+
+    #[test]
+    fn reads_replaygain_and_r128_tags() {
+        let gain = ReplayGain::from_tags([
+            ("REPLAYGAIN_TRACK_GAIN", "-6.50 dB"),
+            ("replaygain_track_peak", "0.988"),
+            ("TXXX:REPLAYGAIN_ALBUM_GAIN", "+1.25 dB"),
+            ("R128_TRACK_GAIN", "-512"),
+        ]);
+        assert_eq!(gain.track_gain, Some(-6.5));
+        assert_eq!(gain.track_peak, Some(0.988));
+        assert_eq!(gain.album_gain, Some(1.25));
+        assert_eq!(gain.album_peak, None);
+
+        let r128 = ReplayGain::from_tags([("R128_TRACK_GAIN", "-512")]);
+        assert_eq!(r128.track_gain, Some(3.0));
+    }
+
+    #[test]
+    fn album_mode_falls_back_to_track_gain() {
+        let track_only = ReplayGain {
+            track_gain: Some(-3.0),
+            ..Default::default()
+        };
+        assert_eq!(track_only.select(GainMode::Off), None);
+        assert_eq!(track_only.select(GainMode::Album).unwrap().gain, -3.0);
+
+        let both = ReplayGain {
+            album_gain: Some(-5.0),
+            album_peak: Some(0.5),
+            ..track_only
+        };
+        assert_eq!(
+            both.select(GainMode::Album),
+            Some(Normalization {
+                gain: -5.0,
+                peak: Some(0.5)
+            })
+        );
+        assert_eq!(both.select(GainMode::Track).unwrap().gain, -3.0);
+    }
+
+    #[test]
+    fn clip_prevention_keeps_the_peak_at_full_scale() {
+        let boost = Normalization {
+            gain: 6.0,
+            peak: Some(0.8),
+        };
+        assert!((boost.factor(0.0, false) - 1.9953).abs() < 1e-3);
+        assert_eq!(boost.factor(0.0, true), 1.25);
+        assert!((boost.factor(-12.0, true) - 0.5012).abs() < 1e-3);
+    }
+
+    #[test]
+    fn album_mode_uses_track_loudness_for_measured_files() {
+        let measured = Some(Analysis {
+            loudness: Some(-24.0),
+            peak: 0.5,
+        });
+        let track = Normalization {
+            gain: 6.0,
+            peak: Some(0.5),
+        };
+        let untagged = ReplayGain::default();
+        assert_eq!(
+            normalization(GainMode::Album, untagged, measured),
+            Some(track)
+        );
+        assert_eq!(
+            normalization(GainMode::Track, untagged, measured),
+            Some(track)
+        );
+        assert_eq!(normalization(GainMode::Off, untagged, measured), None);
+        assert_eq!(normalization(GainMode::Album, untagged, None), None);
+
+        let tagged = ReplayGain {
+            album_gain: Some(-2.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            normalization(GainMode::Album, tagged, measured).map(|n| n.gain),
+            Some(-2.0)
+        );
+    }
+
+    #[test]
+    fn files_are_measured_in_the_background() {
+        let format = crate::format::DeviceFormat {
+            sample_rate: 8000,
+            ..Default::default()
+        };
+        let tone = test_util::s16(&sine(8000, 440.0, 0.5, 1.0));
+        let path = test_util::write_wav("loudness-later", &format, &tone);
+        let cache = Arc::new(LoudnessCache::at(None));
+        assert_eq!(cache.cached(&path), None);
+
+        cache.measure_later(&path, DecoderBackend::Native);
+        cache.measure_later(&path, DecoderBackend::Native);
+        let started = std::time::Instant::now();
+        while cache.cached(&path).is_none() {
+            assert!(started.elapsed() < std::time::Duration::from_secs(10));
+            thread::sleep(std::time::Duration::from_millis(5));
+        }
+        assert!(cache.cached(&path).unwrap().loudness.is_some());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn cache_survives_a_reload_and_notices_changed_files() {
+        let dir = std::env::temp_dir().join(format!("feed-loudness-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cache_path = dir.join("cache.tsv");
+        let _ = fs::remove_file(&cache_path);
+
+        let format = crate::format::DeviceFormat {
+            sample_rate: 8000,
+            ..Default::default()
+        };
+        let tone = test_util::s16(&sine(8000, 440.0, 0.5, 1.0));
+        let audio_path = test_util::write_wav("loudness-tone", &format, &tone);
+
+        let first = LoudnessCache::at(Some(cache_path.clone()))
+            .analyze(&audio_path, DecoderBackend::Native)
+            .unwrap();
+        assert!(first.loudness.is_some());
+
+        // A fresh cache answers from the file without decoding: a stale size
+        // would be a miss, so fake a result and look for it.
+        let contents = fs::read_to_string(&cache_path).unwrap();
+        let mut fields: Vec<&str> = contents.trim_end().split('\t').collect();
+        fields[2] = "-30";
+        fs::write(&cache_path, fields.join("\t") + "\n").unwrap();
+        let cached = LoudnessCache::at(Some(cache_path.clone()))
+            .analyze(&audio_path, DecoderBackend::Native)
+            .unwrap();
+        assert_eq!(cached.loudness, Some(-30.0));
+
+        fields[0] = "1";
+        fs::write(&cache_path, fields.join("\t") + "\n").unwrap();
+        let remeasured = LoudnessCache::at(Some(cache_path))
+            .analyze(&audio_path, DecoderBackend::Native)
+            .unwrap();
+        assert_eq!(remeasured, first);
+
+        let _ = fs::remove_file(&audio_path);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,206 @@
+use crate::format::DeviceFormat;
+use std::time::{Duration, Instant};
+
+// The bottom of the meter scale.
+pub const FLOOR_DB: f32 = -60.0;
+// Peaks fall back this fast once the audio gets quieter.
+const PEAK_FALL_DB_PER_SECOND: f32 = 20.0;
+// Time constant of the RMS reading, close to a VU meter's.
+const RMS_SECONDS: f32 = 0.3;
+const HOLD: Duration = Duration::from_millis(1500);
+// Without fresh blocks for this long the output has gone quiet.
+const STALE: Duration = Duration::from_millis(100);
+
+// Peak and mean square per channel of one block of interleaved stereo,
+// relative to full scale.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Levels {
+    pub peak: [f32; 2],
+    pub mean_square: [f32; 2],
+}
+
+impl Levels {
+    pub fn measure(samples: &[f32]) -> Self {
+        let mut levels = Levels::default();
+        let mut frames = 0;
+        for frame in samples.chunks_exact(2) {
+            for (channel, &x) in frame.iter().enumerate() {
+                levels.peak[channel] = levels.peak[channel].max(x.abs());
+                levels.mean_square[channel] += x * x;
+            }
+            frames += 1;
+        }
+        if frames > 0 {
+            for mean_square in &mut levels.mean_square {
+                *mean_square /= frames as f32;
+            }
+        }
+        levels
+    }
+}
+
+// One chunk as it was written to the sink, read back from the device format:
+// its levels, and its samples as interleaved stereo for the analyzer views.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Block {
+    pub levels: Levels,
+    pub samples: Vec<f32>,
+}
+
+impl Block {
+    pub fn new(format: &DeviceFormat, data: &[u8]) -> Self {
+        let samples = format.to_stereo(data);
+        Self {
+            levels: Levels::measure(&samples),
+            samples,
+        }
+    }
+}
+
+pub fn to_db(linear: f32) -> f32 {
+    if linear > 0.0 {
+        (20.0 * linear.log10()).max(FLOOR_DB)
+    } else {
+        FLOOR_DB
+    }
+}
+
+// Meter ballistics for one channel, all in dB.
+#[derive(Clone, Copy, Debug)]
+pub struct ChannelMeter {
+    pub peak: f32,
+    pub rms: f32,
+    pub hold: f32,
+    held_at: Option<Instant>,
+}
+
+impl Default for ChannelMeter {
+    fn default() -> Self {
+        Self {
+            peak: FLOOR_DB,
+            rms: FLOOR_DB,
+            hold: FLOOR_DB,
+            held_at: None,
+        }
+    }
+}
+
+// Turns the blocks the playback thread measured into readings that rise
+// instantly, fall at a readable rate and hold the highest recent peak.
+#[derive(Default)]
+pub struct Meter {
+    pub channels: [ChannelMeter; 2],
+    mean_square: [f32; 2],
+    // The most recent blocks' mean square, reused between their arrivals.
+    latest: [f32; 2],
+    last_update: Option<Instant>,
+    last_block: Option<Instant>,
+}
+
+impl Meter {
+    pub fn update(&mut self, blocks: impl IntoIterator<Item = Levels>, now: Instant) {
+        let elapsed = self
+            .last_update
+            .map_or(0.0, |at| now.duration_since(at).as_secs_f32());
+        self.last_update = Some(now);
+
+        let mut peak = [0f32; 2];
+        let mut mean_square = [0f32; 2];
+        let mut count = 0;
+        for block in blocks {
+            for channel in 0..2 {
+                peak[channel] = peak[channel].max(block.peak[channel]);
+                mean_square[channel] += block.mean_square[channel];
+            }
+            count += 1;
+        }
+        if count > 0 {
+            self.last_block = Some(now);
+            self.latest = mean_square.map(|sum| sum / count as f32);
+        } else if self
+            .last_block
+            .is_none_or(|at| now.duration_since(at) >= STALE)
+        {
+            self.latest = [0.0; 2];
+        }
+
+        let smoothing = 1.0 - (-elapsed / RMS_SECONDS).exp();
+        for (channel, meter) in self.channels.iter_mut().enumerate() {
+            self.mean_square[channel] +=
+                (self.latest[channel] - self.mean_square[channel]) * smoothing;
+            meter.rms = to_db(self.mean_square[channel].sqrt());
+
+            meter.peak = to_db(peak[channel])
+                .max(meter.peak - PEAK_FALL_DB_PER_SECOND * elapsed)
+                .max(FLOOR_DB);
+            let expired = meter
+                .held_at
+                .is_none_or(|at| now.duration_since(at) >= HOLD);
+            if meter.peak >= meter.hold || expired {
+                meter.hold = meter.peak;
+                meter.held_at = Some(now);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(left: i16, right: i16, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|n| {
+                let sign = if n % 2 == 0 { 1 } else { -1 };
+                [left * sign, right * sign].map(|s| s as f32 / 32768.0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn measures_each_channel_separately() {
+        let levels = Levels::measure(&block(16384, 0, 100));
+        assert_eq!(levels.peak, [0.5, 0.0]);
+        assert_eq!(levels.mean_square, [0.25, 0.0]);
+        assert_eq!(Levels::measure(&[]), Levels::default());
+        assert_eq!(to_db(0.5).round(), -6.0);
+        assert_eq!(to_db(0.0), FLOOR_DB);
+    }
+
+    #[test]
+    fn peaks_rise_at_once_fall_slowly_and_hold() {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let loud = Levels::measure(&block(32767, 3277, 512));
+        let quiet = Levels::measure(&block(3277, 3277, 512));
+
+        let mut meter = Meter::default();
+        meter.update([loud], at(0));
+        assert!(meter.channels[0].peak > -0.01);
+        assert!((meter.channels[1].peak - -20.0).abs() < 0.01);
+
+        meter.update([quiet], at(500));
+        assert!((meter.channels[0].peak - -10.0).abs() < 0.01);
+        assert!(meter.channels[0].hold > -0.01);
+
+        meter.update([quiet], at(1600));
+        assert!((meter.channels[0].peak - -20.0).abs() < 0.01);
+        assert_eq!(meter.channels[0].hold, meter.channels[0].peak);
+    }
+
+    #[test]
+    fn rms_settles_on_the_signal_and_falls_when_blocks_stop() {
+        let start = Instant::now();
+        let mut meter = Meter::default();
+        let tone = Levels::measure(&block(16384, 16384, 512));
+        for ms in (0..2000).step_by(20) {
+            let blocks = if ms % 40 == 0 { vec![tone] } else { Vec::new() };
+            meter.update(blocks, start + Duration::from_millis(ms));
+        }
+        assert!((meter.channels[0].rms - -6.02).abs() < 0.1);
+
+        meter.update([], start + Duration::from_secs(5));
+        assert!(meter.channels[0].rms < -30.0);
+        assert_eq!(meter.channels[1].peak, FLOOR_DB);
+    }
+}
@@ -0,0 +1,1258 @@
+use crate::decode::{self, Chunk, DecodeStream, Decoder, DecoderBackend};
+use crate::dither::{DitherMode, NoiseShaping, Requantizer};
+use crate::eq::{EqSettings, Equalizer};
+use crate::fade::{self, Fade, FadeCurve, GainRamp};
+use crate::flow::{FlowControl, Pacer};
+use crate::format::DeviceFormat;
+use crate::limiter::{self, Limiter, LimiterMode};
+use crate::link::{self, Link, LinkLost};
+use crate::live::{self, LiveInput};
+use crate::loudness::{self, GainMode, LoudnessCache, Normalization};
+use crate::meter::Block;
+use crate::ports;
+use crate::protocol::ControlCommand;
+use crate::resample::ResampleQuality;
+use crate::sink::AudioSink;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often a paused stream checks whether it should go on.
+const PAUSE_POLL: Duration = Duration::from_millis(20);
+
+// Long enough to avoid a click, short enough to feel immediate.
+pub const DEFAULT_FADE: f32 = 0.05;
+// Longest fade or crossfade, in seconds.
+pub const MAX_FADE: f32 = 10.0;
+
+// The bottom of the volume control, which plays silence.
+pub const MIN_VOLUME_DB: f32 = -60.0;
+// Highest linear gain the volume control reaches, +6 dB.
+pub const DEFAULT_MAX_BOOST: f32 = 2.0;
+// Blocks the meters may fall behind by before new ones are dropped.
+const METER_BACKLOG: usize = 64;
+// How long a volume change takes to glide in.
+const VOLUME_RAMP: f32 = 0.02;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioFile {
+    pub path: String,
+    pub name: String,
+}
+
+impl AudioFile {
+    pub fn from_path(path: &Path) -> Self {
+        let name = match path.to_str() {
+            Some(live::STDIN) => "stdin",
+            _ => path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or("Unknown"),
+        }
+        .to_string();
+        Self {
+            path: path.to_string_lossy().to_string(),
+            name,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RepeatMode {
+    /// Stop once the queue is empty
+    #[default]
+    Off,
+    /// Play the current track over and over
+    One,
+    /// Start the queue over once every track has been played
+    All,
+}
+
+impl RepeatMode {
+    pub const ALL: [RepeatMode; 3] = [RepeatMode::Off, RepeatMode::One, RepeatMode::All];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            RepeatMode::Off => "Repeat off",
+            RepeatMode::One => "Repeat one",
+            RepeatMode::All => "Repeat all",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PlayerEvent {
+    TrackStarted(AudioFile),
+    TrackFailed(AudioFile, String),
+    // Playback is over, either because the queue ran out or it was stopped.
+    QueueFinished,
+    // Writing to a supervised link failed; playback waits for the device.
+    LinkLost(String),
+    // The device is back and open again, under the given name.
+    LinkRestored(String),
+}
+
+pub struct AudioPlayer {
+    pub sink: Option<Box<dyn AudioSink>>,
+    // Set for serial outputs so a lost device can be found and reopened.
+    pub link: Option<Link>,
+    // Why the link went down, while waiting for it to come back.
+    pub link_down: Option<String>,
+    // Carry on with the interrupted track once the link is back.
+    pub resume_after_reconnect: bool,
+    // Seconds to wait for the device before stopping, 0 to stop at once.
+    pub reconnect_timeout: f32,
+    pub queue: VecDeque<AudioFile>,
+    // A track to start part-way through, ahead of the queue, the next time
+    // playback starts, e.g. the one that was playing when the app closed.
+    pub resume: Option<(AudioFile, f32)>,
+    pub current_file: Option<AudioFile>,
+    pub is_playing: bool,
+    pub is_paused: bool,
+    // Linear gain, up to `max_boost`.
+    pub volume: f32,
+    pub max_boost: f32,
+    pub muted: bool,
+    // What the device takes; the whole chain runs at its sample rate.
+    pub format: DeviceFormat,
+    pub flow_control: FlowControl,
+    pub decoder: DecoderBackend,
+    pub resampler: ResampleQuality,
+    // How stdin and named pipes in the queue are read.
+    pub live_input: LiveInput,
+    pub last_error: Option<String>,
+    pub progress: f32,
+    pub total_duration: f32,
+    pub current_duration: f32,
+    // Position in seconds the stream should jump to, picked up by the
+    // playback thread.
+    pub seek_to: Option<f32>,
+    pub repeat: RepeatMode,
+    pub shuffle: bool,
+    // Tracks already played in the current cycle, fed back into the queue by
+    // repeat-all.
+    pub played: Vec<AudioFile>,
+    pub last_started: Option<AudioFile>,
+    pub subscribers: Vec<Sender<PlayerEvent>>,
+    // Every chunk written to the sink, for the meters and analyzer to read.
+    pub meter: Option<SyncSender<Block>>,
+    // Fade lengths in seconds when playback starts or seeks, and on Stop.
+    pub fade_in: f32,
+    pub fade_out: f32,
+    pub fade_curve: FadeCurve,
+    // Seconds the end of one track overlaps the start of the next; zero
+    // splices them gaplessly.
+    pub crossfade: f32,
+    pub crossfade_curve: FadeCurve,
+    pub gain_mode: GainMode,
+    // dB added on top of the normalization gain.
+    pub preamp: f32,
+    // Holds the normalization gain back when it would push the track's peak
+    // past full scale.
+    pub clip_prevention: bool,
+    // Gain for the track that's playing, if normalization is on.
+    pub normalization: Option<Normalization>,
+    pub loudness: Arc<LoudnessCache>,
+    pub limiter: LimiterMode,
+    // Frames that went past full scale before the limiter, and when the last
+    // one did, for the clip indicator.
+    pub overs: u64,
+    pub last_over: Option<Instant>,
+    pub dither: DitherMode,
+    pub noise_shaping: NoiseShaping,
+    pub eq: EqSettings,
+}
+
+impl Default for AudioPlayer {
+    fn default() -> Self {
+        Self {
+            sink: None,
+            link: None,
+            link_down: None,
+            resume_after_reconnect: true,
+            reconnect_timeout: link::DEFAULT_RECONNECT_TIMEOUT,
+            queue: VecDeque::new(),
+            resume: None,
+            current_file: None,
+            is_playing: false,
+            is_paused: false,
+            volume: 1.0,
+            max_boost: DEFAULT_MAX_BOOST,
+            muted: false,
+            format: DeviceFormat::default(),
+            flow_control: FlowControl::Timed,
+            decoder: DecoderBackend::Auto,
+            resampler: ResampleQuality::default(),
+            live_input: LiveInput::default(),
+            last_error: None,
+            progress: 0.0,
+            total_duration: 0.0,
+            current_duration: 0.0,
+            seek_to: None,
+            repeat: RepeatMode::Off,
+            shuffle: false,
+            played: Vec::new(),
+            last_started: None,
+            subscribers: Vec::new(),
+            meter: None,
+            fade_in: DEFAULT_FADE,
+            fade_out: DEFAULT_FADE,
+            fade_curve: FadeCurve::default(),
+            crossfade: 0.0,
+            crossfade_curve: FadeCurve::default(),
+            gain_mode: GainMode::Off,
+            preamp: 0.0,
+            clip_prevention: true,
+            normalization: None,
+            loudness: Arc::new(LoudnessCache::default()),
+            limiter: LimiterMode::default(),
+            overs: 0,
+            last_over: None,
+            dither: DitherMode::default(),
+            noise_shaping: NoiseShaping::default(),
+            eq: EqSettings::default(),
+        }
+    }
+}
+
+impl AudioPlayer {
+    pub fn seek(&mut self, seconds: f32) {
+        if !self.is_playing {
+            return;
+        }
+        let mut target = seconds.max(0.0);
+        if self.total_duration > 0.0 {
+            target = target.min(self.total_duration);
+        }
+        self.seek_to = Some(target);
+    }
+
+    pub fn seek_by(&mut self, delta: f32) {
+        let from = self.seek_to.unwrap_or(self.current_duration);
+        self.seek(from + delta);
+    }
+
+    pub fn volume_db(&self) -> f32 {
+        if self.volume <= loudness::db_to_linear(MIN_VOLUME_DB) {
+            MIN_VOLUME_DB
+        } else {
+            20.0 * self.volume.log10()
+        }
+    }
+
+    pub fn set_volume_db(&mut self, db: f32) {
+        self.volume = if db <= MIN_VOLUME_DB {
+            0.0
+        } else {
+            loudness::db_to_linear(db).min(self.max_boost)
+        };
+    }
+
+    // What the volume stage multiplies by, after mute and the max boost.
+    pub fn output_gain(&self) -> f32 {
+        if self.muted {
+            0.0
+        } else {
+            self.volume.clamp(0.0, self.max_boost)
+        }
+    }
+
+    pub fn subscribe(&mut self) -> Receiver<PlayerEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    // Replaces any earlier meter; there is only ever one window to draw it.
+    pub fn meter_blocks(&mut self) -> Receiver<Block> {
+        let (sender, receiver) = mpsc::sync_channel(METER_BACKLOG);
+        self.meter = Some(sender);
+        receiver
+    }
+
+    fn emit(&mut self, event: PlayerEvent) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    // Picks the track to play after the last one, following the repeat and
+    // shuffle settings. A shuffled cycle draws every queued track once before
+    // repeat-all refills the queue.
+    pub fn next_track(&mut self) -> Option<AudioFile> {
+        if let Some(last) = self.last_started.take() {
+            if self.repeat == RepeatMode::One {
+                self.last_started = Some(last.clone());
+                return Some(last);
+            }
+            self.played.push(last);
+        }
+
+        if self.queue.is_empty() && self.repeat == RepeatMode::All {
+            self.queue.extend(self.played.drain(..));
+        }
+        let next = if self.shuffle && !self.queue.is_empty() {
+            let index = rand::rng().random_range(0..self.queue.len());
+            self.queue.remove(index)
+        } else {
+            self.queue.pop_front()
+        };
+
+        if next.is_none() {
+            self.played.clear();
+        }
+        self.last_started = next.clone();
+        next
+    }
+
+    // Hands back a track that next_track picked ahead of time but that never
+    // started playing.
+    pub fn requeue(&mut self, file: AudioFile) {
+        if self.repeat == RepeatMode::One && self.last_started.as_ref() == Some(&file) {
+            return;
+        }
+        self.last_started = None;
+        self.queue.push_front(file);
+    }
+
+    // Plays through the queue until it runs out or playback is stopped.
+    // Consecutive tracks are spliced into one stream, so the pacing clock and
+    // the device buffer carry straight on from one track to the next.
+    // Returns how many tracks were played to the end.
+    pub fn play_queue(player: Arc<Mutex<AudioPlayer>>) -> usize {
+        let mut session = {
+            let mut p = player.lock().unwrap();
+            p.is_playing = true;
+            p.is_paused = false;
+            p.overs = 0;
+            p.last_over = None;
+            Session {
+                pacer: Pacer::new(p.flow_control, p.format.sample_rate),
+                finished: 0,
+                failures: 0,
+                resume: p.resume.take(),
+                start_at: None,
+            }
+        };
+
+        while let Some((track, decoder)) = Self::open_next(&player, &mut session) {
+            let Err((file, e)) = Self::stream_tracks(&player, track, decoder, &mut session) else {
+                break;
+            };
+            if e.is::<LinkLost>() {
+                let position = player.lock().unwrap().current_duration;
+                let reconnected = Self::reconnect(&player, &mut session);
+                let mut p = player.lock().unwrap();
+                if reconnected && p.resume_after_reconnect {
+                    session.resume = Some((file, position));
+                    continue;
+                }
+                p.requeue(file);
+                if !reconnected && p.is_playing {
+                    p.last_error = Some(e.to_string());
+                }
+                break;
+            }
+            let mut p = player.lock().unwrap();
+            p.last_error = Some(e.to_string());
+            p.emit(PlayerEvent::TrackFailed(file, e.to_string()));
+            if session.give_up(&p) {
+                break;
+            }
+        }
+
+        let mut p = player.lock().unwrap();
+        if let Some(ref mut sink) = p.sink
+            && let Err(e) = sink.flush()
+        {
+            eprintln!("Failed to flush {}: {}", sink.name(), e);
+        }
+        p.is_playing = false;
+        p.is_paused = false;
+        p.seek_to = None;
+        p.current_file = None;
+        p.progress = 0.0;
+        p.current_duration = 0.0;
+        p.total_duration = 0.0;
+        p.normalization = None;
+        p.emit(PlayerEvent::QueueFinished);
+        session.finished
+    }
+
+    // Waits for the device behind a lost link to come back and reopens it.
+    // False if it didn't return in time or playback was stopped meanwhile.
+    fn reconnect(player: &Arc<Mutex<AudioPlayer>>, session: &mut Session) -> bool {
+        let (link, format, timeout) = {
+            let p = player.lock().unwrap();
+            let Some(link) = p.link.clone() else {
+                return false;
+            };
+            (link, p.format, p.reconnect_timeout)
+        };
+        let deadline = Instant::now() + Duration::from_secs_f32(timeout.max(0.0));
+        let keep_waiting = || player.lock().unwrap().is_playing && Instant::now() < deadline;
+        loop {
+            let Some(port) = link::wait_for(
+                &link.device,
+                ports::available_ports,
+                link::RECONNECT_POLL,
+                keep_waiting,
+            ) else {
+                return false;
+            };
+            // A freshly enumerated port can refuse to open for a moment.
+            match link.reopen(&port, format) {
+                Ok(sink) => {
+                    let mut p = player.lock().unwrap();
+                    p.sink = Some(sink);
+                    p.link = Some(Link {
+                        device: port.clone(),
+                        ..link
+                    });
+                    p.link_down = None;
+                    p.emit(PlayerEvent::LinkRestored(port.name));
+                    // Whatever the device had buffered is gone.
+                    session.pacer = Pacer::new(p.flow_control, p.format.sample_rate);
+                    return true;
+                }
+                Err(_) if keep_waiting() => thread::sleep(link::RECONNECT_POLL),
+                Err(_) => return false,
+            }
+        }
+    }
+
+    // A failed write on a supervised link drops the dead sink and reports the
+    // link as lost; anywhere else it's an ordinary error.
+    fn write_failed(p: &mut AudioPlayer, e: std::io::Error) -> Box<dyn std::error::Error> {
+        let name = p.sink.as_ref().map(|sink| sink.name()).unwrap_or_default();
+        let message = format!("failed to write to {}: {}", name, e);
+        if p.link.is_none() {
+            return message.into();
+        }
+        p.sink = None;
+        p.link_down = Some(message.clone());
+        p.emit(PlayerEvent::LinkLost(message.clone()));
+        Box::new(LinkLost(message))
+    }
+
+    // Takes tracks off the queue until one opens, reporting the ones that
+    // don't. None means playback is over.
+    fn open_next(
+        player: &Arc<Mutex<AudioPlayer>>,
+        session: &mut Session,
+    ) -> Option<(Track, Box<dyn Decoder>)> {
+        loop {
+            let (file, backend, live_input) = {
+                let mut p = player.lock().unwrap();
+                if !p.is_playing {
+                    return None;
+                }
+                if p.sink.is_none() {
+                    p.last_error = Some("no output connected".to_string());
+                    return None;
+                }
+                // A track interrupted by a lost link picks up where it was,
+                // ahead of whatever the queue would have played next.
+                let file = match session.resume.take() {
+                    Some((file, position)) => {
+                        session.start_at = Some(position);
+                        p.last_started = Some(file.clone());
+                        file
+                    }
+                    None => p.next_track()?,
+                };
+                (file, p.decoder, p.live_input)
+            };
+
+            let live = live::is_live(&file.path);
+            let opened = if live {
+                live::open(&file.path, &live_input)
+            } else {
+                decode::open(&file.path, backend)
+            };
+            match opened {
+                Ok(decoder) => {
+                    // Live input can't be measured ahead, so it plays as is.
+                    let normalization = if live {
+                        None
+                    } else {
+                        Self::normalization(player, &file, decoder.as_ref())
+                    };
+                    player.lock().unwrap().measure_queue();
+                    let track = Track {
+                        duration: decoder.duration(),
+                        normalization,
+                        file,
+                        live,
+                    };
+                    return Some((track, decoder));
+                }
+                Err(e) => {
+                    let message = format!("failed to load {}: {}", file.path, e);
+                    let mut p = player.lock().unwrap();
+                    p.last_error = Some(message.clone());
+                    p.emit(PlayerEvent::TrackFailed(file, message));
+                    if session.give_up(&p) {
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+
+    // Gain that brings `file` to the reference loudness, preferring its tags.
+    // Untagged files are measured in the background; one that hasn't been yet
+    // plays as it is rather than holding up the stream.
+    fn normalization(
+        player: &Arc<Mutex<AudioPlayer>>,
+        file: &AudioFile,
+        decoder: &dyn Decoder,
+    ) -> Option<Normalization> {
+        let (mode, backend, cache) = {
+            let p = player.lock().unwrap();
+            (p.gain_mode, p.decoder, Arc::clone(&p.loudness))
+        };
+        if mode == GainMode::Off {
+            return None;
+        }
+        let tags = decoder.replay_gain();
+        let measured = cache.cached(&file.path);
+        if measured.is_none() && tags.select(mode).is_none() {
+            cache.measure_later(&file.path, backend);
+        }
+        loudness::normalization(mode, tags, measured)
+    }
+
+    // Starts measuring the queued files that normalization will need, so
+    // they're ready by the time they play.
+    pub fn measure_queue(&self) {
+        if self.gain_mode == GainMode::Off {
+            return;
+        }
+        for file in self.queue.iter().filter(|f| !live::is_live(&f.path)) {
+            self.loudness.measure_later(&file.path, self.decoder);
+        }
+    }
+
+    fn start_track(player: &Arc<Mutex<AudioPlayer>>, track: &Track) {
+        let mut p = player.lock().unwrap();
+        p.current_file = Some(track.file.clone());
+        p.normalization = track.normalization;
+        p.last_error = None;
+        p.seek_to = None;
+        p.progress = 0.0;
+        p.current_duration = 0.0;
+        p.total_duration = track.duration.unwrap_or(0.0);
+        p.emit(PlayerEvent::TrackStarted(track.file.clone()));
+    }
+
+    // Gives a live track its jitter buffer once it's the one playing.
+    fn buffer_live(player: &Arc<Mutex<AudioPlayer>>, track: &Track, stream: &mut DecodeStream) {
+        if track.live {
+            let p = player.lock().unwrap();
+            stream.set_jitter_buffer(p.live_input.jitter_samples(p.format.sample_rate));
+        }
+    }
+
+    // Streams `file` and whatever follows it in the queue. On failure, returns
+    // the track that was playing at the time.
+    fn stream_tracks(
+        player: &Arc<Mutex<AudioPlayer>>,
+        track: Track,
+        decoder: Box<dyn Decoder>,
+        session: &mut Session,
+    ) -> Result<(), (AudioFile, Box<dyn std::error::Error>)> {
+        let (sample_rate, resampler) = {
+            let p = player.lock().unwrap();
+            (p.format.sample_rate, p.resampler)
+        };
+        Self::start_track(player, &track);
+        if let Some(position) = session.start_at.take() {
+            player.lock().unwrap().seek_to = Some(position);
+        }
+        let mut stream = DecodeStream::spawn(decoder, sample_rate, resampler);
+        Self::buffer_live(player, &track, &mut stream);
+        let mut current = track.file;
+        let mut upcoming = VecDeque::new();
+        let mut crossfade = None;
+
+        let result = Self::stream(
+            player,
+            &mut current,
+            &mut stream,
+            &mut upcoming,
+            &mut crossfade,
+            session,
+        );
+
+        // Tracks that were decoded ahead but never reached go back in line,
+        // along with one that was only part way through fading in.
+        let mut p = player.lock().unwrap();
+        for track in upcoming.into_iter().rev() {
+            p.requeue(track.file);
+        }
+        if let Some(next) = crossfade {
+            p.requeue(next.track.file);
+        }
+        result.map_err(|e| (current, e))
+    }
+
+    fn stream(
+        player: &Arc<Mutex<AudioPlayer>>,
+        current: &mut AudioFile,
+        stream: &mut DecodeStream,
+        upcoming: &mut VecDeque<Track>,
+        crossfade: &mut Option<Crossfade>,
+        session: &mut Session,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (format, backend, resampler, fade_curve, fade_in, fade_out) = {
+            let p = player.lock().unwrap();
+            (
+                p.format,
+                p.decoder,
+                p.resampler,
+                p.fade_curve,
+                fade::seconds_to_frames(p.fade_in, p.format.sample_rate),
+                fade::seconds_to_frames(p.fade_out, p.format.sample_rate),
+            )
+        };
+        let sample_rate = format.sample_rate;
+        let mut samples = vec![0f32; 2048];
+        let mut incoming_samples = vec![0f32; samples.len()];
+        let mut chunk = Vec::with_capacity(samples.len() * 2);
+        let mut current_play_time = 0.0;
+        let mut paused = false;
+        let mut fade = Some(Fade::fade_in(fade_curve, fade_in));
+        let mut stopping: Option<Fade> = None;
+        let mut equalizer = Equalizer::new(sample_rate);
+        let mut limiter = Limiter::new(sample_rate);
+        let (mut volume, mut requantizer) = {
+            let p = player.lock().unwrap();
+            (
+                GainRamp::new(
+                    p.output_gain(),
+                    fade::seconds_to_frames(VOLUME_RAMP, sample_rate),
+                ),
+                Requantizer::new(format, p.dither, p.noise_shaping),
+            )
+        };
+        // Set once this track has had its chance to start a crossfade.
+        let mut crossfade_tried = false;
+
+        loop {
+            let (playing, pause_requested, seek_to, crossfade_seconds, crossfade_curve, total) = {
+                let mut p = player.lock().unwrap();
+                (
+                    p.is_playing,
+                    p.is_paused,
+                    p.seek_to.take(),
+                    p.crossfade,
+                    p.crossfade_curve,
+                    p.total_duration,
+                )
+            };
+            // A paused stream has nothing left to fade.
+            let stop_now = !playing && (paused || fade_out == 0);
+            // A device left paused would swallow the next track.
+            let pause_requested = pause_requested && playing;
+            if pause_requested != paused {
+                paused = pause_requested;
+                let now = Instant::now();
+                let command = if paused {
+                    session.pacer.pause(now);
+                    ControlCommand::Pause
+                } else {
+                    session.pacer.resume(now);
+                    ControlCommand::Resume
+                };
+                let mut p = player.lock().unwrap();
+                let sink = p.sink.as_mut().ok_or("output disconnected")?;
+                if let Err(e) = sink.control(command) {
+                    return Err(Self::write_failed(&mut p, e));
+                }
+            }
+            if stop_now {
+                return Ok(());
+            }
+            if !playing && stopping.is_none() {
+                stopping = Some(Fade::fade_out(fade_curve, fade_out));
+            }
+            // Live input only ever goes forward.
+            let seek_to = seek_to.filter(|_| stopping.is_none() && !live::is_live(&current.path));
+            if let Some(target) = seek_to {
+                // Restarting the decoder at the offset works the same for
+                // every backend; the old stream's buffered audio is dropped.
+                let reopened = decode::open(&current.path, backend).and_then(|mut decoder| {
+                    decoder.seek(target)?;
+                    Ok(decoder)
+                });
+                let mut p = player.lock().unwrap();
+                match reopened {
+                    Ok(decoder) => {
+                        *stream = DecodeStream::spawn(decoder, sample_rate, resampler);
+                        for track in upcoming.drain(..).rev() {
+                            p.requeue(track.file);
+                        }
+                        if let Some(abandoned) = crossfade.take() {
+                            p.requeue(abandoned.track.file);
+                        }
+                        crossfade_tried = false;
+                        fade = Some(Fade::fade_in(fade_curve, fade_in));
+                        current_play_time = target;
+                        p.current_duration = target;
+                        if p.total_duration > 0.0 {
+                            p.progress = target / p.total_duration;
+                        }
+                    }
+                    Err(e) => p.last_error = Some(format!("failed to seek: {}", e)),
+                }
+            }
+            if paused {
+                thread::sleep(PAUSE_POLL);
+                continue;
+            }
+
+            // A crossfade needs to know where the track ends, so without a
+            // duration tracks are spliced gaplessly instead.
+            let crossfade_planned = crossfade_seconds > 0.0 && total > 0.0 && !crossfade_tried;
+            if crossfade_planned
+                && stopping.is_none()
+                && total - current_play_time <= crossfade_seconds
+            {
+                crossfade_tried = true;
+                if let Some((track, decoder)) = Self::open_next(player, session) {
+                    let frames = fade::seconds_to_frames(
+                        crossfade_seconds.min(total - current_play_time),
+                        sample_rate,
+                    );
+                    *crossfade = Some(Crossfade {
+                        track,
+                        stream: DecodeStream::spawn(decoder, sample_rate, resampler),
+                        fade_in: Fade::fade_in(crossfade_curve, frames),
+                        fade_out: Fade::fade_out(crossfade_curve, frames),
+                        played_frames: 0,
+                    });
+                }
+            }
+
+            // Picking the next track while this one still has buffered audio
+            // left gives it time to open and start decoding. While a
+            // crossfade is still to come the decoder is left waiting.
+            if stream.wants_next() {
+                if stopping.is_some() || crossfade.is_some() {
+                    stream.append(None);
+                } else if !crossfade_planned {
+                    let next = Self::open_next(player, session).map(|(track, decoder)| {
+                        upcoming.push_back(track);
+                        decoder
+                    });
+                    stream.append(next);
+                }
+            }
+
+            let len = match stream.read(&mut samples) {
+                Chunk::Audio(0) => {
+                    // The track ran out before its reported duration; splice
+                    // the next one in instead.
+                    if crossfade_planned && stream.wants_next() {
+                        crossfade_tried = true;
+                    }
+                    continue;
+                }
+                Chunk::Audio(len) => len,
+                Chunk::TrackChange => {
+                    session.finished += 1;
+                    session.failures = 0;
+                    let track = upcoming.pop_front().ok_or("unexpected track change")?;
+                    Self::start_track(player, &track);
+                    Self::buffer_live(player, &track, stream);
+                    *current = track.file;
+                    current_play_time = 0.0;
+                    crossfade_tried = false;
+                    continue;
+                }
+                Chunk::End => match crossfade.take() {
+                    Some(next) => {
+                        current_play_time = format.seconds(next.played_frames);
+                        fade = Self::finish_crossfade(player, next, current, stream, session);
+                        crossfade_tried = false;
+                        continue;
+                    }
+                    None => break,
+                },
+            };
+            let frames = len / 2;
+            loop {
+                let now = Instant::now();
+                let status = {
+                    let mut p = player.lock().unwrap();
+                    p.sink.as_mut().and_then(|sink| sink.poll_status())
+                };
+                if let Some(status) = status {
+                    session.pacer.report(now, status);
+                }
+                match session.pacer.delay(now, frames) {
+                    Some(wait) => thread::sleep(wait),
+                    None => break,
+                }
+            }
+
+            let (limiter_mode, gain, incoming_gain) = {
+                let p = player.lock().unwrap();
+                equalizer.update(&p.eq);
+                let factor = |normalization: Option<Normalization>| {
+                    normalization.map_or(1.0, |n| n.factor(p.preamp, p.clip_prevention))
+                };
+                volume.set_target(p.output_gain());
+                (
+                    p.limiter,
+                    factor(p.normalization),
+                    crossfade
+                        .as_ref()
+                        .map_or(1.0, |next| factor(next.track.normalization)),
+                )
+            };
+
+            let samples = &mut samples[..len];
+            scale(samples, gain);
+            if let Some(ref mut ramp) = fade {
+                ramp.apply(samples);
+                if ramp.is_finished() {
+                    fade = None;
+                }
+            }
+            if let Some(next) = crossfade.as_mut() {
+                let incoming = &mut incoming_samples[..len];
+                fill(&mut next.stream, incoming);
+                scale(incoming, incoming_gain);
+                next.fade_out.apply(samples);
+                next.fade_in.apply(incoming);
+                for (sample, incoming) in samples.iter_mut().zip(incoming.iter()) {
+                    *sample += incoming;
+                }
+                next.played_frames += frames;
+            }
+            equalizer.process(samples);
+            if let Some(ref mut ramp) = stopping {
+                ramp.apply(samples);
+            }
+
+            volume.apply(samples);
+            let overs = limiter::count_overs(samples);
+            limiter.process(samples, limiter_mode == LimiterMode::Lookahead);
+            if limiter_mode == LimiterMode::SoftClip {
+                samples
+                    .iter_mut()
+                    .for_each(|sample| *sample = limiter::soft_clip(*sample));
+            }
+            Self::write_samples(player, &mut requantizer, &mut chunk, samples, overs)?;
+
+            session.pacer.sent(frames);
+            current_play_time += format.seconds(frames);
+
+            if stopping.as_ref().is_some_and(Fade::is_finished) {
+                return Ok(());
+            }
+            if crossfade
+                .as_ref()
+                .is_some_and(|next| next.fade_out.is_finished())
+            {
+                let next = crossfade.take().unwrap();
+                current_play_time = format.seconds(next.played_frames);
+                fade = Self::finish_crossfade(player, next, current, stream, session);
+                crossfade_tried = false;
+            }
+
+            {
+                let mut p = player.lock().unwrap();
+                p.current_duration = current_play_time;
+                p.progress = if p.total_duration > 0.0 {
+                    p.current_duration / p.total_duration
+                } else {
+                    0.0
+                };
+            }
+        }
+
+        // Whatever the limiter still holds back is the very end of the queue.
+        let engaged = player.lock().unwrap().limiter == LimiterMode::Lookahead;
+        let tail = limiter.drain(engaged);
+        Self::write_samples(player, &mut requantizer, &mut chunk, &tail, 0)?;
+        session.pacer.sent(tail.len() / 2);
+
+        stream.finish()?;
+        session.finished += 1;
+        Ok(())
+    }
+
+    // Converts to the device format and sends it off, counting `overs`
+    // towards the clip indicator.
+    fn write_samples(
+        player: &Arc<Mutex<AudioPlayer>>,
+        requantizer: &mut Requantizer,
+        chunk: &mut Vec<u8>,
+        samples: &[f32],
+        overs: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut p = player.lock().unwrap();
+        requantizer.configure(p.dither, p.noise_shaping);
+        chunk.clear();
+        requantizer.process(samples, chunk);
+
+        if overs > 0 {
+            p.overs += overs as u64;
+            p.last_over = Some(Instant::now());
+        }
+        if let Some(ref meter) = p.meter
+            && let Err(TrySendError::Disconnected(_)) = meter.try_send(Block::new(&p.format, chunk))
+        {
+            p.meter = None;
+        }
+        let sink = p.sink.as_mut().ok_or("output disconnected")?;
+        if let Err(e) = sink.write_all(chunk) {
+            return Err(Self::write_failed(&mut p, e));
+        }
+        Ok(())
+    }
+
+    // Makes the incoming track of a crossfade the current one. The outgoing
+    // stream is dropped rather than drained, since its tail is inaudible by
+    // now. Returns what is left of the incoming track's fade-in.
+    fn finish_crossfade(
+        player: &Arc<Mutex<AudioPlayer>>,
+        next: Crossfade,
+        current: &mut AudioFile,
+        stream: &mut DecodeStream,
+        session: &mut Session,
+    ) -> Option<Fade> {
+        session.finished += 1;
+        session.failures = 0;
+        Self::start_track(player, &next.track);
+        *stream = next.stream;
+        Self::buffer_live(player, &next.track, stream);
+        *current = next.track.file;
+        Some(next.fade_in).filter(|fade| !fade.is_finished())
+    }
+}
+
+// A track that opened, with what's known about it up front.
+struct Track {
+    file: AudioFile,
+    duration: Option<f32>,
+    normalization: Option<Normalization>,
+    // Read from stdin or a pipe as it arrives.
+    live: bool,
+}
+
+// The next track fading in over the end of the current one.
+struct Crossfade {
+    track: Track,
+    stream: DecodeStream,
+    fade_in: Fade,
+    fade_out: Fade,
+    played_frames: usize,
+}
+
+// Fills `buf` from a stream that is never spliced, padding with silence once
+// it runs dry.
+fn fill(stream: &mut DecodeStream, buf: &mut [f32]) {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match stream.read(&mut buf[filled..]) {
+            Chunk::Audio(0) | Chunk::TrackChange | Chunk::End => break,
+            Chunk::Audio(len) => filled += len,
+        }
+    }
+    buf[filled..].fill(0.0);
+}
+
+fn scale(samples: &mut [f32], gain: f32) {
+    if gain != 1.0 {
+        samples.iter_mut().for_each(|sample| *sample *= gain);
+    }
+}
+
+// State that lasts for one run through the queue.
+struct Session {
+    pacer: Pacer,
+    finished: usize,
+    failures: usize,
+    // The track to pick up again after a reconnect, and where.
+    resume: Option<(AudioFile, f32)>,
+    // Seconds into the next track to start from.
+    start_at: Option<f32>,
+}
+
+impl Session {
+    // Counts a failed track and tells whether to stop: repeat-one would only
+    // fail again, and otherwise every track in the cycle has failed in a row.
+    fn give_up(&mut self, player: &AudioPlayer) -> bool {
+        self.failures += 1;
+        player.repeat == RepeatMode::One || self.failures > player.queue.len() + player.played.len()
+    }
+}
+
+pub fn format_duration(seconds: f32) -> String {
+    let total_seconds = seconds as u32;
+    let hours = total_seconds / 3600;
+    let minutes = (total_seconds % 3600) / 60;
+    let secs = total_seconds % 60;
+    if hours > 0 {
+        format!("{:02}:{:02}:{:02}", hours, minutes, secs)
+    } else {
+        format!("{:02}:{:02}", minutes, secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ports::PortInfo;
+    use crate::sink::{MemorySink, Protocol};
+    use crate::test_util;
+    use std::io;
+
+    fn player_with(names: &[&str]) -> AudioPlayer {
+        let mut player = AudioPlayer::default();
+        for name in names {
+            player
+                .queue
+                .push_back(AudioFile::from_path(Path::new(name)));
+        }
+        player
+    }
+
+    fn next_names(player: &mut AudioPlayer, count: usize) -> Vec<String> {
+        (0..count)
+            .map_while(|_| player.next_track())
+            .map(|file| file.name)
+            .collect()
+    }
+
+    #[test]
+    fn plays_the_queue_in_order_and_stops() {
+        let mut player = player_with(&["a", "b", "c"]);
+        assert_eq!(next_names(&mut player, 5), ["a", "b", "c"]);
+        assert!(player.played.is_empty());
+    }
+
+    #[test]
+    fn repeat_one_stays_on_the_current_track() {
+        let mut player = player_with(&["a", "b"]);
+        player.repeat = RepeatMode::One;
+        assert_eq!(next_names(&mut player, 3), ["a", "a", "a"]);
+
+        player.repeat = RepeatMode::Off;
+        assert_eq!(next_names(&mut player, 3), ["b"]);
+    }
+
+    #[test]
+    fn repeat_all_starts_over() {
+        let mut player = player_with(&["a", "b"]);
+        player.repeat = RepeatMode::All;
+        assert_eq!(next_names(&mut player, 5), ["a", "b", "a", "b", "a"]);
+    }
+
+    #[test]
+    fn shuffle_plays_every_track_once_per_cycle() {
+        let names = ["a", "b", "c", "d", "e", "f"];
+        let mut player = player_with(&names);
+        player.repeat = RepeatMode::All;
+        player.shuffle = true;
+
+        for _ in 0..3 {
+            let mut cycle = next_names(&mut player, names.len());
+            cycle.sort();
+            assert_eq!(cycle, names);
+        }
+    }
+
+    #[test]
+    fn subscribers_see_events_until_they_hang_up() {
+        let mut player = AudioPlayer::default();
+        let kept = player.subscribe();
+        drop(player.subscribe());
+
+        player.emit(PlayerEvent::QueueFinished);
+        assert_eq!(kept.try_recv(), Ok(PlayerEvent::QueueFinished));
+        assert_eq!(player.subscribers.len(), 1);
+    }
+
+    #[test]
+    fn volume_in_db_respects_the_range_and_mute() {
+        let mut player = AudioPlayer::default();
+        player.set_volume_db(-6.0);
+        assert!((player.volume - 0.501).abs() < 1e-3);
+        assert!((player.volume_db() - -6.0).abs() < 1e-4);
+
+        player.set_volume_db(MIN_VOLUME_DB);
+        assert_eq!(player.volume, 0.0);
+        assert_eq!(player.volume_db(), MIN_VOLUME_DB);
+
+        player.max_boost = 1.5;
+        player.set_volume_db(12.0);
+        assert_eq!(player.volume, 1.5);
+        player.volume = 1.8;
+        assert_eq!(player.output_gain(), 1.5);
+
+        player.muted = true;
+        assert_eq!(player.output_gain(), 0.0);
+    }
+
+    // Takes a few chunks and then fails like an unplugged serial port.
+    struct FlakySink {
+        writes_left: usize,
+    }
+
+    impl AudioSink for FlakySink {
+        fn name(&self) -> String {
+            "flaky".to_string()
+        }
+
+        fn write_all(&mut self, _data: &[u8]) -> io::Result<()> {
+            if self.writes_left == 0 {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "device gone"));
+            }
+            self.writes_left -= 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn plays_a_file_end_to_end_into_a_memory_sink() {
+        let format = DeviceFormat::default();
+        let frames = format.sample_rate as usize / 5;
+        let data: Vec<u8> = (0..frames as i16)
+            .map(|n| n % 4096 * 7)
+            .flat_map(|n| [n, -n])
+            .flat_map(i16::to_le_bytes)
+            .collect();
+        let path = test_util::write_wav("memory", &format, &data);
+
+        // Everything that would change the samples is off, so they come
+        // through bit for bit behind the limiter's delay.
+        let memory = MemorySink::new();
+        let mut player = player_with(&[&path]);
+        player.decoder = DecoderBackend::Native;
+        player.sink = Some(Box::new(memory.clone()));
+        player.fade_in = 0.0;
+        player.dither = DitherMode::Off;
+        player.limiter = LimiterMode::Off;
+        let player = Arc::new(Mutex::new(player));
+
+        assert_eq!(AudioPlayer::play_queue(Arc::clone(&player)), 1);
+        let delay = Limiter::new(format.sample_rate).latency() * format.bytes_per_frame();
+        let written = memory.contents();
+        assert_eq!(written.len(), delay + data.len());
+        assert!(written[..delay].iter().all(|&b| b == 0));
+        assert_eq!(written[delay..], data[..]);
+        assert!(player.lock().unwrap().queue.is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn unmeasured_tracks_play_at_unity_gain_instead_of_waiting() {
+        let format = DeviceFormat::default();
+        let data = test_util::s16(&vec![0.25; format.sample_rate as usize / 5 * 2]);
+        let path = test_util::write_wav("unmeasured", &format, &data);
+
+        let memory = MemorySink::new();
+        let cache = Arc::new(LoudnessCache::at(None));
+        let mut player = player_with(&[&path]);
+        player.decoder = DecoderBackend::Native;
+        player.sink = Some(Box::new(memory.clone()));
+        player.gain_mode = GainMode::Track;
+        player.loudness = Arc::clone(&cache);
+        player.fade_in = 0.0;
+        player.dither = DitherMode::Off;
+        player.limiter = LimiterMode::Off;
+        let player = Arc::new(Mutex::new(player));
+
+        assert_eq!(AudioPlayer::play_queue(Arc::clone(&player)), 1);
+        let delay = Limiter::new(format.sample_rate).latency() * format.bytes_per_frame();
+        assert_eq!(memory.contents()[delay..], data[..]);
+
+        // The measurement carries on and is there for the next time.
+        let started = Instant::now();
+        while cache.cached(&path).is_none() {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(5));
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn stopping_during_a_crossfade_keeps_the_incoming_track() {
+        let format = DeviceFormat::default();
+        let second = vec![0; format.sample_rate as usize * format.bytes_per_frame()];
+        let first = test_util::write_wav("crossfade-out", &format, &second);
+        let next = test_util::write_wav("crossfade-in", &format, &second);
+
+        let mut player = player_with(&[&first, &next]);
+        player.decoder = DecoderBackend::Native;
+        player.sink = Some(Box::new(MemorySink::new()));
+        player.crossfade = 0.5;
+        let player = Arc::new(Mutex::new(player));
+        let handle = {
+            let player = Arc::clone(&player);
+            thread::spawn(move || AudioPlayer::play_queue(player))
+        };
+
+        // Stop once the second track has started fading in.
+        while player.lock().unwrap().current_duration < 0.7 {
+            assert!(!handle.is_finished());
+            thread::sleep(Duration::from_millis(5));
+        }
+        player.lock().unwrap().is_playing = false;
+        assert_eq!(handle.join().unwrap(), 0);
+
+        let p = player.lock().unwrap();
+        let queued: Vec<&str> = p.queue.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(queued, [next.as_str()]);
+        let _ = std::fs::remove_file(&first);
+        let _ = std::fs::remove_file(&next);
+    }
+
+    #[test]
+    fn lost_link_drops_the_sink_and_keeps_the_track() {
+        let format = DeviceFormat::default();
+        let silence = vec![0; format.sample_rate as usize * format.bytes_per_frame()];
+        let path = test_util::write_wav("link", &format, &silence);
+
+        let mut player = player_with(&[&path]);
+        player.decoder = DecoderBackend::Native;
+        player.sink = Some(Box::new(FlakySink { writes_left: 3 }));
+        player.link = Some(Link {
+            device: PortInfo::named("/dev/feed-test-missing"),
+            protocol: Protocol::Raw,
+        });
+        player.reconnect_timeout = 0.3;
+        let events = player.subscribe();
+        let player = Arc::new(Mutex::new(player));
+
+        let started = Instant::now();
+        assert_eq!(AudioPlayer::play_queue(Arc::clone(&player)), 0);
+        assert!(started.elapsed() >= Duration::from_millis(300));
+
+        let p = player.lock().unwrap();
+        assert!(p.sink.is_none());
+        assert!(p.link_down.as_deref().unwrap().contains("device gone"));
+        assert_eq!(
+            p.queue.front().map(|f| f.path.as_str()),
+            Some(path.as_str())
+        );
+        let events: Vec<PlayerEvent> = events.try_iter().collect();
+        assert!(events.iter().any(|e| matches!(e, PlayerEvent::LinkLost(_))));
+        assert!(
+            !events
+                .iter()
+                .any(|e| matches!(e, PlayerEvent::TrackFailed(..)))
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,375 @@
+use serde::{Deserialize, Serialize};
+use serialport::{SerialPortInfo, SerialPortType};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// How often the watcher looks for ports coming and going.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(1000);
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UsbInfo {
+    pub vid: u16,
+    pub pid: u16,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PortInfo {
+    pub name: String,
+    pub usb: Option<UsbInfo>,
+}
+
+impl From<SerialPortInfo> for PortInfo {
+    fn from(info: SerialPortInfo) -> Self {
+        let usb = match info.port_type {
+            SerialPortType::UsbPort(usb) => Some(UsbInfo {
+                vid: usb.vid,
+                pid: usb.pid,
+                serial_number: usb.serial_number,
+                manufacturer: usb.manufacturer,
+                product: usb.product,
+            }),
+            _ => None,
+        };
+        Self {
+            name: info.port_name,
+            usb,
+        }
+    }
+}
+
+impl PortInfo {
+    pub fn named(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            usb: None,
+        }
+    }
+
+    // One line for lists: the port, then what's behind it.
+    pub fn label(&self) -> String {
+        match &self.usb {
+            Some(usb) => format!("{} - {}", self.name, usb.describe()),
+            None => self.name.clone(),
+        }
+    }
+
+    pub fn serial_number(&self) -> Option<&str> {
+        self.usb.as_ref()?.serial_number.as_deref()
+    }
+
+    // A USB device keeps its serial number when it re-enumerates under
+    // another name; anything else can only be told apart by name.
+    pub fn same_device(&self, other: &PortInfo) -> bool {
+        match (self.serial_number(), other.serial_number()) {
+            (Some(a), Some(b)) => a == b && self.usb_ids() == other.usb_ids(),
+            _ => self.name == other.name,
+        }
+    }
+
+    fn usb_ids(&self) -> Option<(u16, u16)> {
+        self.usb.as_ref().map(|usb| (usb.vid, usb.pid))
+    }
+}
+
+impl UsbInfo {
+    pub fn describe(&self) -> String {
+        let mut text = format!("USB {:04x}:{:04x}", self.vid, self.pid);
+        if let Some(product) = &self.product {
+            text += &format!(" {}", product);
+        }
+        if let Some(serial) = &self.serial_number {
+            text += &format!(" (serial {})", serial);
+        }
+        text
+    }
+}
+
+pub fn available_ports() -> Vec<PortInfo> {
+    let mut ports: Vec<PortInfo> = serialport::available_ports()
+        .unwrap_or_default()
+        .into_iter()
+        .map(PortInfo::from)
+        .collect();
+    ports.sort_by(|a, b| a.name.cmp(&b.name));
+    ports
+}
+
+// What the DAC reports over USB. The defaults are the CDC descriptors the
+// firmware ships with (usbd_desc.c).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DacMatch {
+    pub vid: u16,
+    pub pid: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+}
+
+impl Default for DacMatch {
+    fn default() -> Self {
+        Self {
+            vid: 0x0483,
+            pid: 0x5740,
+            manufacturer: Some("STMicroelectronics".to_string()),
+            product: Some("STM32 Virtual ComPort".to_string()),
+        }
+    }
+}
+
+impl DacMatch {
+    // IDs must match. The strings are checked only where the OS reports
+    // them, since some platforms leave them out or substitute their own.
+    pub fn matches(&self, port: &PortInfo) -> bool {
+        let Some(usb) = &port.usb else {
+            return false;
+        };
+        let same = |wanted: &Option<String>, reported: &Option<String>| match (wanted, reported) {
+            (Some(wanted), Some(reported)) => wanted.eq_ignore_ascii_case(reported.trim()),
+            _ => true,
+        };
+        usb.vid == self.vid
+            && usb.pid == self.pid
+            && same(&self.manufacturer, &usb.manufacturer)
+            && same(&self.product, &usb.product)
+    }
+
+    pub fn describe(&self) -> String {
+        let mut text = format!("USB {:04x}:{:04x}", self.vid, self.pid);
+        if let Some(product) = &self.product {
+            text += &format!(" \"{}\"", product);
+        }
+        text
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Detection {
+    Found(PortInfo),
+    // More than one board is plugged in; the user has to pick.
+    Ambiguous(Vec<PortInfo>),
+    NotFound,
+}
+
+pub fn detect_dac(ports: &[PortInfo], dac: &DacMatch) -> Detection {
+    let mut found: Vec<PortInfo> = ports.iter().filter(|p| dac.matches(p)).cloned().collect();
+    match found.len() {
+        0 => Detection::NotFound,
+        1 => Detection::Found(found.remove(0)),
+        _ => Detection::Ambiguous(found),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PortEvent {
+    // The list of ports is different from last time.
+    Changed(Vec<PortInfo>),
+    // The watched device went away.
+    Disappeared(PortInfo),
+    // The watched device is back, possibly under a new name.
+    Reappeared(PortInfo),
+}
+
+// What changed between two scans, as seen by someone watching `watched`.
+fn changes(
+    previous: &[PortInfo],
+    current: &[PortInfo],
+    watched: Option<&PortInfo>,
+) -> Vec<PortEvent> {
+    if previous == current {
+        return Vec::new();
+    }
+    let mut events = vec![PortEvent::Changed(current.to_vec())];
+    if let Some(watched) = watched {
+        let find = |ports: &[PortInfo]| ports.iter().find(|p| p.same_device(watched)).cloned();
+        match (find(previous), find(current)) {
+            (Some(gone), None) => events.push(PortEvent::Disappeared(gone)),
+            (None, Some(back)) => events.push(PortEvent::Reappeared(back)),
+            _ => {}
+        }
+    }
+    events
+}
+
+// Rescans the serial ports on a background thread and reports what changed.
+// The thread ends once the watcher is dropped.
+pub struct PortWatcher {
+    events: Receiver<PortEvent>,
+    watched: Arc<Mutex<Option<PortInfo>>>,
+}
+
+impl PortWatcher {
+    pub fn spawn(interval: Duration) -> Self {
+        Self::spawn_with(interval, available_ports)
+    }
+
+    fn spawn_with(
+        interval: Duration,
+        mut scan: impl FnMut() -> Vec<PortInfo> + Send + 'static,
+    ) -> Self {
+        let (sender, events) = mpsc::channel();
+        let watched = Arc::new(Mutex::new(None));
+        {
+            let watched = Arc::clone(&watched);
+            thread::spawn(move || Self::run(sender, watched, interval, &mut scan));
+        }
+        Self { events, watched }
+    }
+
+    fn run(
+        sender: Sender<PortEvent>,
+        watched: Arc<Mutex<Option<PortInfo>>>,
+        interval: Duration,
+        scan: &mut dyn FnMut() -> Vec<PortInfo>,
+    ) {
+        let mut previous = Vec::new();
+        loop {
+            let current = scan();
+            let watched = watched.lock().unwrap().clone();
+            for event in changes(&previous, &current, watched.as_ref()) {
+                if sender.send(event).is_err() {
+                    return;
+                }
+            }
+            previous = current;
+            thread::sleep(interval);
+        }
+    }
+
+    // The device to report disappearing and coming back.
+    pub fn watch(&self, port: Option<PortInfo>) {
+        *self.watched.lock().unwrap() = port;
+    }
+
+    pub fn events(&self) -> mpsc::TryIter<'_, PortEvent> {
+        self.events.try_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usb(name: &str, serial: &str) -> PortInfo {
+        PortInfo {
+            name: name.to_string(),
+            usb: Some(UsbInfo {
+                vid: 0x0483,
+                pid: 0x5740,
+                serial_number: Some(serial.to_string()),
+                manufacturer: Some("STMicroelectronics".to_string()),
+                product: Some("STM32 Virtual ComPort".to_string()),
+            }),
+        }
+    }
+
+    #[test]
+    fn usb_devices_are_matched_by_serial_number() {
+        let dac = usb("/dev/ttyACM0", "2061377B5548");
+        assert!(dac.same_device(&usb("/dev/ttyACM1", "2061377B5548")));
+        assert!(!dac.same_device(&usb("/dev/ttyACM0", "0000")));
+        assert!(PortInfo::named("/dev/ttyS0").same_device(&PortInfo::named("/dev/ttyS0")));
+        assert_eq!(
+            dac.label(),
+            "/dev/ttyACM0 - USB 0483:5740 STM32 Virtual ComPort (serial 2061377B5548)"
+        );
+    }
+
+    #[test]
+    fn reports_the_watched_device_leaving_and_returning() {
+        let dac = usb("/dev/ttyACM0", "A1");
+        let other = PortInfo::named("/dev/ttyS0");
+        let with = vec![dac.clone(), other.clone()];
+        let without = vec![other.clone()];
+        let moved = vec![usb("/dev/ttyACM1", "A1"), other.clone()];
+
+        assert!(changes(&with, &with, Some(&dac)).is_empty());
+        assert_eq!(
+            changes(&with, &without, Some(&dac)),
+            [
+                PortEvent::Changed(without.clone()),
+                PortEvent::Disappeared(dac.clone())
+            ]
+        );
+        assert_eq!(
+            changes(&without, &moved, Some(&dac)),
+            [
+                PortEvent::Changed(moved.clone()),
+                PortEvent::Reappeared(moved[0].clone())
+            ]
+        );
+        assert_eq!(
+            changes(&without, &with, Some(&other)),
+            [PortEvent::Changed(with.clone())]
+        );
+        assert_eq!(
+            changes(&with, &without, None),
+            [PortEvent::Changed(without)]
+        );
+    }
+
+    #[test]
+    fn watcher_thread_reports_scans() {
+        // A few scans with the board, giving `watch` time to land first.
+        let mut sequence = vec![vec![usb("/dev/ttyACM0", "A1")]; 10];
+        sequence.push(Vec::new());
+        let scans = Arc::new(Mutex::new(sequence));
+        let watcher = {
+            let scans = Arc::clone(&scans);
+            PortWatcher::spawn_with(Duration::from_millis(5), move || {
+                let mut scans = scans.lock().unwrap();
+                if scans.len() > 1 {
+                    scans.remove(0)
+                } else {
+                    scans[0].clone()
+                }
+            })
+        };
+        watcher.watch(Some(usb("/dev/ttyACM0", "A1")));
+
+        let mut events = Vec::new();
+        for _ in 0..200 {
+            events.extend(watcher.events());
+            if events.len() >= 3 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(matches!(events[0], PortEvent::Changed(_)));
+        assert!(events.contains(&PortEvent::Disappeared(usb("/dev/ttyACM0", "A1"))));
+    }
+
+    #[test]
+    fn detects_the_dac_by_its_descriptors() {
+        let dac = DacMatch::default();
+        let board = usb("/dev/ttyACM0", "A1");
+        let mut stranger = usb("/dev/ttyACM1", "B2");
+        stranger.usb.as_mut().unwrap().pid = 0x1234;
+        let mut renamed = usb("COM3", "C3");
+        renamed.usb.as_mut().unwrap().product = Some("USB Serial Device".to_string());
+        let mut unnamed = usb("COM4", "D4");
+        unnamed.usb.as_mut().unwrap().product = None;
+
+        assert!(dac.matches(&board));
+        assert!(!dac.matches(&stranger));
+        assert!(!dac.matches(&renamed));
+        assert!(dac.matches(&unnamed));
+        assert!(!dac.matches(&PortInfo::named("/dev/ttyS0")));
+
+        let ports = [
+            PortInfo::named("/dev/ttyS0"),
+            stranger.clone(),
+            board.clone(),
+        ];
+        assert_eq!(detect_dac(&ports, &dac), Detection::Found(board.clone()));
+        assert_eq!(detect_dac(&ports[..2], &dac), Detection::NotFound);
+        assert_eq!(
+            detect_dac(&[board.clone(), unnamed.clone()], &dac),
+            Detection::Ambiguous(vec![board, unnamed])
+        );
+    }
+}
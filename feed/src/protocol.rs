@@ -0,0 +1,415 @@
+// Wire format shared with the firmware. Every frame is laid out as
+//
+//   magic[2] | version u8 | type u8 | seq u16 | len u16 | payload[len] | crc32
+//
+// with all integers little-endian. The CRC is CRC-32/ISO-HDLC over everything
+// from the magic up to the end of the payload.
+
+pub const MAGIC: [u8; 2] = [0xA5, 0x5A];
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 8;
+pub const CRC_LEN: usize = 4;
+pub const MAX_PAYLOAD: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameType {
+    Audio = 0x01,
+    Control = 0x02,
+    Telemetry = 0x03,
+}
+
+impl FrameType {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(FrameType::Audio),
+            0x02 => Some(FrameType::Control),
+            0x03 => Some(FrameType::Telemetry),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub frame_type: FrameType,
+    pub seq: u16,
+    pub payload: Vec<u8>,
+}
+
+// Payload of a telemetry frame: how many stereo frames are waiting in the
+// device's incoming buffer, and how many it can hold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceStatus {
+    pub buffered_frames: u32,
+    pub capacity_frames: u32,
+}
+
+impl DeviceStatus {
+    pub const LEN: usize = 8;
+
+    pub fn to_bytes(self) -> [u8; Self::LEN] {
+        let mut out = [0u8; Self::LEN];
+        out[..4].copy_from_slice(&self.buffered_frames.to_le_bytes());
+        out[4..].copy_from_slice(&self.capacity_frames.to_le_bytes());
+        out
+    }
+
+    pub fn from_bytes(payload: &[u8]) -> Option<Self> {
+        if payload.len() < Self::LEN {
+            return None;
+        }
+        Some(Self {
+            buffered_frames: u32::from_le_bytes(payload[..4].try_into().unwrap()),
+            capacity_frames: u32::from_le_bytes(payload[4..8].try_into().unwrap()),
+        })
+    }
+}
+
+// First payload byte of a control frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ControlCommand {
+    Pause = 0x01,
+    Resume = 0x02,
+}
+
+impl ControlCommand {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(ControlCommand::Pause),
+            0x02 => Some(ControlCommand::Resume),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    UnsupportedVersion(u8),
+    UnknownType(u8),
+    PayloadTooLong(usize),
+    BadCrc { expected: u32, actual: u32 },
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::UnsupportedVersion(v) => write!(f, "unsupported protocol version {}", v),
+            DecodeError::UnknownType(t) => write!(f, "unknown frame type {:#04x}", t),
+            DecodeError::PayloadTooLong(len) => write!(f, "payload of {} bytes is too long", len),
+            DecodeError::BadCrc { expected, actual } => {
+                write!(
+                    f,
+                    "crc mismatch: expected {:08x}, got {:08x}",
+                    expected, actual
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc = CRC_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[derive(Default)]
+pub struct Encoder {
+    next_seq: u16,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn encode(&mut self, frame_type: FrameType, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + payload.len() + CRC_LEN);
+        self.encode_into(&mut out, frame_type, payload);
+        out
+    }
+
+    // Appends one frame per MAX_PAYLOAD-sized piece of `payload`.
+    pub fn encode_into(&mut self, out: &mut Vec<u8>, frame_type: FrameType, payload: &[u8]) {
+        if payload.is_empty() {
+            self.encode_one(out, frame_type, payload);
+        }
+        for piece in payload.chunks(MAX_PAYLOAD) {
+            self.encode_one(out, frame_type, piece);
+        }
+    }
+
+    fn encode_one(&mut self, out: &mut Vec<u8>, frame_type: FrameType, payload: &[u8]) {
+        let start = out.len();
+        out.extend_from_slice(&MAGIC);
+        out.push(VERSION);
+        out.push(frame_type as u8);
+        out.extend_from_slice(&self.next_seq.to_le_bytes());
+        out.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        out.extend_from_slice(payload);
+        let crc = crc32(&out[start..]);
+        out.extend_from_slice(&crc.to_le_bytes());
+        self.next_seq = self.next_seq.wrapping_add(1);
+    }
+}
+
+// Reference decoder. Bytes can arrive split at arbitrary points; anything that
+// doesn't parse is skipped one byte at a time until the next magic is found.
+#[derive(Default)]
+pub struct Decoder {
+    buffer: Vec<u8>,
+    expected_seq: Option<u16>,
+    pub skipped_bytes: u64,
+    pub lost_frames: u64,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    pub fn next_frame(&mut self) -> Option<Result<Frame, DecodeError>> {
+        self.resync();
+        if self.buffer.len() < HEADER_LEN {
+            return None;
+        }
+
+        let version = self.buffer[2];
+        if version != VERSION {
+            self.skip(1);
+            return Some(Err(DecodeError::UnsupportedVersion(version)));
+        }
+        let Some(frame_type) = FrameType::from_u8(self.buffer[3]) else {
+            let raw = self.buffer[3];
+            self.skip(1);
+            return Some(Err(DecodeError::UnknownType(raw)));
+        };
+        let len = u16::from_le_bytes([self.buffer[6], self.buffer[7]]) as usize;
+        if len > MAX_PAYLOAD {
+            self.skip(1);
+            return Some(Err(DecodeError::PayloadTooLong(len)));
+        }
+
+        let total = HEADER_LEN + len + CRC_LEN;
+        if self.buffer.len() < total {
+            return None;
+        }
+
+        let body_end = HEADER_LEN + len;
+        let expected = crc32(&self.buffer[..body_end]);
+        let actual = u32::from_le_bytes(self.buffer[body_end..total].try_into().unwrap());
+        if expected != actual {
+            self.skip(1);
+            return Some(Err(DecodeError::BadCrc { expected, actual }));
+        }
+
+        let seq = u16::from_le_bytes([self.buffer[4], self.buffer[5]]);
+        if let Some(expected_seq) = self.expected_seq {
+            self.lost_frames += seq.wrapping_sub(expected_seq) as u64;
+        }
+        self.expected_seq = Some(seq.wrapping_add(1));
+
+        let payload = self.buffer[HEADER_LEN..body_end].to_vec();
+        self.buffer.drain(..total);
+        Some(Ok(Frame {
+            frame_type,
+            seq,
+            payload,
+        }))
+    }
+
+    fn resync(&mut self) {
+        let start = self
+            .buffer
+            .windows(MAGIC.len())
+            .position(|w| w == MAGIC)
+            .unwrap_or(self.buffer.len().saturating_sub(MAGIC.len() - 1));
+        if start > 0 {
+            self.skip(start);
+        }
+    }
+
+    fn skip(&mut self, count: usize) {
+        self.buffer.drain(..count);
+        self.skipped_bytes += count as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(decoder: &mut Decoder) -> Vec<Result<Frame, DecodeError>> {
+        std::iter::from_fn(|| decoder.next_frame()).collect()
+    }
+
+    #[test]
+    fn crc_matches_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn round_trip_all_frame_types() {
+        let mut encoder = Encoder::new();
+        let mut stream = Vec::new();
+        stream.extend(encoder.encode(FrameType::Audio, &[1, 2, 3, 4]));
+        stream.extend(encoder.encode(FrameType::Control, &[0x10]));
+        stream.extend(encoder.encode(FrameType::Telemetry, &[]));
+
+        let mut decoder = Decoder::new();
+        decoder.push(&stream);
+        let frames: Vec<Frame> = decode_all(&mut decoder)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].frame_type, FrameType::Audio);
+        assert_eq!(frames[0].payload, vec![1, 2, 3, 4]);
+        assert_eq!(frames[1].frame_type, FrameType::Control);
+        assert_eq!(frames[1].seq, 1);
+        assert_eq!(frames[2].frame_type, FrameType::Telemetry);
+        assert!(frames[2].payload.is_empty());
+        assert_eq!(decoder.skipped_bytes, 0);
+        assert_eq!(decoder.lost_frames, 0);
+    }
+
+    #[test]
+    fn byte_at_a_time_delivery() {
+        let mut encoder = Encoder::new();
+        let payload: Vec<u8> = (0..=255).collect();
+        let stream = encoder.encode(FrameType::Audio, &payload);
+
+        let mut decoder = Decoder::new();
+        let mut frames = Vec::new();
+        for byte in stream {
+            decoder.push(&[byte]);
+            frames.extend(decode_all(&mut decoder));
+        }
+
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].as_ref().unwrap().payload, payload);
+    }
+
+    #[test]
+    fn long_payload_is_split() {
+        let mut encoder = Encoder::new();
+        let payload = vec![0x42; MAX_PAYLOAD * 2 + 10];
+        let mut stream = Vec::new();
+        encoder.encode_into(&mut stream, FrameType::Audio, &payload);
+
+        let mut decoder = Decoder::new();
+        decoder.push(&stream);
+        let lens: Vec<usize> = decode_all(&mut decoder)
+            .into_iter()
+            .map(|f| f.unwrap().payload.len())
+            .collect();
+        assert_eq!(lens, vec![MAX_PAYLOAD, MAX_PAYLOAD, 10]);
+    }
+
+    #[test]
+    fn corrupted_frame_is_rejected_and_stream_recovers() {
+        let mut encoder = Encoder::new();
+        let mut first = encoder.encode(FrameType::Audio, &[0; 16]);
+        let second = encoder.encode(FrameType::Audio, &[7; 16]);
+        first[HEADER_LEN + 3] ^= 0xFF;
+
+        let mut decoder = Decoder::new();
+        decoder.push(&first);
+        decoder.push(&second);
+        let results = decode_all(&mut decoder);
+
+        assert!(matches!(results[0], Err(DecodeError::BadCrc { .. })));
+        let good = results.last().unwrap().as_ref().unwrap();
+        assert_eq!(good.seq, 1);
+        assert_eq!(good.payload, vec![7; 16]);
+    }
+
+    #[test]
+    fn garbage_between_frames_is_skipped() {
+        let mut encoder = Encoder::new();
+        let mut stream = vec![0x00, 0xA5, 0x13, 0x37];
+        stream.extend(encoder.encode(FrameType::Control, &[1]));
+        stream.push(0xFF);
+        stream.extend(encoder.encode(FrameType::Control, &[2]));
+
+        let mut decoder = Decoder::new();
+        decoder.push(&stream);
+        let frames: Vec<Frame> = decode_all(&mut decoder)
+            .into_iter()
+            .filter_map(Result::ok)
+            .collect();
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].payload, vec![2]);
+        assert_eq!(decoder.skipped_bytes, 5);
+    }
+
+    #[test]
+    fn missing_frames_are_counted() {
+        let mut encoder = Encoder::new();
+        let first = encoder.encode(FrameType::Audio, &[1]);
+        let _lost = encoder.encode(FrameType::Audio, &[2]);
+        let _lost = encoder.encode(FrameType::Audio, &[3]);
+        let last = encoder.encode(FrameType::Audio, &[4]);
+
+        let mut decoder = Decoder::new();
+        decoder.push(&first);
+        decoder.push(&last);
+        assert_eq!(decode_all(&mut decoder).len(), 2);
+        assert_eq!(decoder.lost_frames, 2);
+    }
+
+    #[test]
+    fn device_status_round_trip() {
+        let status = DeviceStatus {
+            buffered_frames: 1234,
+            capacity_frames: 16384,
+        };
+        assert_eq!(DeviceStatus::from_bytes(&status.to_bytes()), Some(status));
+        assert_eq!(DeviceStatus::from_bytes(&[0; 4]), None);
+    }
+
+    #[test]
+    fn unsupported_version_is_reported() {
+        let mut encoder = Encoder::new();
+        let mut stream = encoder.encode(FrameType::Audio, &[1]);
+        stream[2] = VERSION + 1;
+
+        let mut decoder = Decoder::new();
+        decoder.push(&stream);
+        assert_eq!(
+            decoder.next_frame(),
+            Some(Err(DecodeError::UnsupportedVersion(VERSION + 1)))
+        );
+    }
+}
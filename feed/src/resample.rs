@@ -0,0 +1,415 @@
+use serde::{Deserialize, Serialize};
+
+// Sample-rate converters for interleaved stereo f32. All of them are
+// stateful, so a stream can be fed in blocks of any size without
+// discontinuities at the boundaries.
+
+pub trait Resampler: Send {
+    fn process(&mut self, input: &[f32], out: &mut Vec<f32>);
+
+    // Pushes out whatever is still held back for the filter's look-ahead.
+    fn flush(&mut self, _out: &mut Vec<f32>) {}
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResampleQuality {
+    /// Straight-line interpolation between neighbouring samples; cheapest,
+    /// but rolls off the top octave and lets images through
+    Linear,
+    /// Kaiser-windowed sinc evaluated for every output sample, 32 taps
+    Sinc,
+    /// Precomputed bank of windowed-sinc phases, 64 taps
+    #[default]
+    Polyphase,
+}
+
+impl ResampleQuality {
+    pub const ALL: [ResampleQuality; 3] = [
+        ResampleQuality::Linear,
+        ResampleQuality::Sinc,
+        ResampleQuality::Polyphase,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ResampleQuality::Linear => "Linear",
+            ResampleQuality::Sinc => "Windowed sinc",
+            ResampleQuality::Polyphase => "Polyphase",
+        }
+    }
+}
+
+pub fn new(quality: ResampleQuality, input_rate: u32, output_rate: u32) -> Box<dyn Resampler> {
+    match quality {
+        ResampleQuality::Linear => Box::new(LinearResampler::new(input_rate, output_rate)),
+        ResampleQuality::Sinc => Box::new(SincResampler::direct(input_rate, output_rate)),
+        ResampleQuality::Polyphase => Box::new(SincResampler::polyphase(input_rate, output_rate)),
+    }
+}
+
+pub struct LinearResampler {
+    step: f64,
+    pos: f64,
+    prev: [f32; 2],
+}
+
+impl LinearResampler {
+    pub fn new(input_rate: u32, output_rate: u32) -> Self {
+        Self {
+            step: input_rate as f64 / output_rate as f64,
+            pos: 1.0,
+            prev: [0.0; 2],
+        }
+    }
+}
+
+impl Resampler for LinearResampler {
+    fn process(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        let frames = input.len() / 2;
+        if frames == 0 {
+            return;
+        }
+
+        // Frame 0 is the last frame of the previous block, so positions are
+        // offset by one against `input`.
+        let frame = |i: usize| -> [f32; 2] {
+            if i == 0 {
+                self.prev
+            } else {
+                [input[(i - 1) * 2], input[(i - 1) * 2 + 1]]
+            }
+        };
+
+        while self.pos < frames as f64 {
+            let i = self.pos as usize;
+            let t = (self.pos - i as f64) as f32;
+            let (a, b) = (frame(i), frame(i + 1));
+            out.push(a[0] + (b[0] - a[0]) * t);
+            out.push(a[1] + (b[1] - a[1]) * t);
+            self.pos += self.step;
+        }
+
+        self.pos -= frames as f64;
+        self.prev = [input[(frames - 1) * 2], input[(frames - 1) * 2 + 1]];
+    }
+}
+
+enum Filter {
+    // Kernel evaluated from scratch for every output sample.
+    Direct { cutoff: f64, beta: f64 },
+    // `phases + 1` rows of `2 * half` taps; fractional positions between two
+    // rows are interpolated.
+    Table { phases: usize, coeffs: Vec<f32> },
+}
+
+pub struct SincResampler {
+    step: f64,
+    half: usize,
+    pos: f64,
+    history: Vec<f32>,
+    weights: Vec<f32>,
+    filter: Filter,
+}
+
+impl SincResampler {
+    pub fn direct(input_rate: u32, output_rate: u32) -> Self {
+        let cutoff = cutoff(input_rate, output_rate, 0.92);
+        Self::with_filter(
+            input_rate,
+            output_rate,
+            16,
+            Filter::Direct { cutoff, beta: 8.0 },
+        )
+    }
+
+    pub fn polyphase(input_rate: u32, output_rate: u32) -> Self {
+        const HALF: usize = 32;
+        const PHASES: usize = 512;
+        const BETA: f64 = 9.0;
+
+        let cutoff = cutoff(input_rate, output_rate, 0.95);
+        let mut coeffs = Vec::with_capacity((PHASES + 1) * 2 * HALF);
+        for phase in 0..=PHASES {
+            let frac = phase as f64 / PHASES as f64;
+            let row: Vec<f64> = (0..2 * HALF)
+                .map(|k| kernel(tap_offset(k, HALF, frac), HALF, cutoff, BETA))
+                .collect();
+            let sum: f64 = row.iter().sum();
+            coeffs.extend(row.iter().map(|&c| (c / sum) as f32));
+        }
+
+        Self::with_filter(
+            input_rate,
+            output_rate,
+            HALF,
+            Filter::Table {
+                phases: PHASES,
+                coeffs,
+            },
+        )
+    }
+
+    fn with_filter(input_rate: u32, output_rate: u32, half: usize, filter: Filter) -> Self {
+        Self {
+            step: input_rate as f64 / output_rate as f64,
+            half,
+            // Start with `half` frames of silence so the first output sample
+            // is centred on the first input frame.
+            pos: half as f64,
+            history: vec![0.0; half * 2],
+            weights: vec![0.0; half * 2],
+            filter,
+        }
+    }
+
+    fn compute_weights(&mut self, frac: f64) {
+        match &self.filter {
+            Filter::Direct { cutoff, beta } => {
+                let mut sum = 0.0;
+                for (k, w) in self.weights.iter_mut().enumerate() {
+                    let value = kernel(tap_offset(k, self.half, frac), self.half, *cutoff, *beta);
+                    *w = value as f32;
+                    sum += value;
+                }
+                for w in &mut self.weights {
+                    *w /= sum as f32;
+                }
+            }
+            Filter::Table { phases, coeffs } => {
+                let taps = self.half * 2;
+                let position = frac * *phases as f64;
+                let row = (position as usize).min(*phases - 1);
+                let t = (position - row as f64) as f32;
+                let a = &coeffs[row * taps..(row + 1) * taps];
+                let b = &coeffs[(row + 1) * taps..(row + 2) * taps];
+                for (k, w) in self.weights.iter_mut().enumerate() {
+                    *w = a[k] + (b[k] - a[k]) * t;
+                }
+            }
+        }
+    }
+}
+
+impl Resampler for SincResampler {
+    fn process(&mut self, input: &[f32], out: &mut Vec<f32>) {
+        self.history.extend_from_slice(input);
+        let frames = self.history.len() / 2;
+
+        while (self.pos as usize) + self.half < frames {
+            let i = self.pos as usize;
+            self.compute_weights(self.pos - i as f64);
+
+            let start = i + 1 - self.half;
+            let window = &self.history[start * 2..(start + self.half * 2) * 2];
+            let (mut left, mut right) = (0.0f32, 0.0f32);
+            for (w, frame) in self.weights.iter().zip(window.chunks_exact(2)) {
+                left += w * frame[0];
+                right += w * frame[1];
+            }
+            out.push(left);
+            out.push(right);
+            self.pos += self.step;
+        }
+
+        let consumed = (self.pos as usize + 1).saturating_sub(self.half);
+        self.history.drain(..consumed * 2);
+        self.pos -= consumed as f64;
+    }
+
+    fn flush(&mut self, out: &mut Vec<f32>) {
+        let silence = vec![0.0; self.half * 2];
+        self.process(&silence, out);
+    }
+}
+
+// Cutoff in cycles per input sample, below both Nyquist frequencies.
+fn cutoff(input_rate: u32, output_rate: u32, rolloff: f64) -> f64 {
+    0.5 * rolloff * (output_rate as f64 / input_rate as f64).min(1.0)
+}
+
+// Distance in input samples between tap `k` and the output position.
+fn tap_offset(k: usize, half: usize, frac: f64) -> f64 {
+    k as f64 + 1.0 - half as f64 - frac
+}
+
+fn kernel(x: f64, half: usize, cutoff: f64, beta: f64) -> f64 {
+    let r = x / half as f64;
+    if r.abs() >= 1.0 {
+        return 0.0;
+    }
+    let arg = 2.0 * cutoff * x;
+    let sinc = if arg.abs() < 1e-12 {
+        1.0
+    } else {
+        (std::f64::consts::PI * arg).sin() / (std::f64::consts::PI * arg)
+    };
+    // Kaiser window shifted to reach exactly zero at the edges, so the kernel
+    // stays continuous as taps slide in and out of the window.
+    let window = (bessel_i0(beta * (1.0 - r * r).sqrt()) - 1.0) / (bessel_i0(beta) - 1.0);
+    2.0 * cutoff * sinc * window
+}
+
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+    for k in 1..50 {
+        term *= half / k as f64;
+        sum += term * term;
+        if term * term < sum * 1e-17 {
+            break;
+        }
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f64, rate: u32, seconds: f64) -> Vec<f32> {
+        let frames = (rate as f64 * seconds) as usize;
+        (0..frames)
+            .flat_map(|n| {
+                let s = (0.5 * (2.0 * std::f64::consts::PI * freq * n as f64 / rate as f64).sin())
+                    as f32;
+                [s, s]
+            })
+            .collect()
+    }
+
+    fn convert(quality: ResampleQuality, input: &[f32], from: u32, to: u32) -> Vec<f32> {
+        let mut resampler = new(quality, from, to);
+        let mut out = Vec::new();
+        // Odd block size so block boundaries land everywhere.
+        for block in input.chunks(2 * 997) {
+            resampler.process(block, &mut out);
+        }
+        resampler.flush(&mut out);
+        out
+    }
+
+    // Amplitude of `freq` in the left channel, measured with a Hann-windowed
+    // single-bin DFT over the middle of the signal.
+    fn amplitude(signal: &[f32], freq: f64, rate: u32) -> f64 {
+        let left: Vec<f64> = signal.iter().step_by(2).map(|&s| s as f64).collect();
+        let skip = left.len() / 8;
+        let part = &left[skip..left.len() - skip];
+        let n = part.len() as f64;
+        let (mut re, mut im, mut norm) = (0.0, 0.0, 0.0);
+        for (i, &x) in part.iter().enumerate() {
+            let w = 0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / n).cos();
+            let phase = 2.0 * std::f64::consts::PI * freq * i as f64 / rate as f64;
+            re += x * w * phase.cos();
+            im -= x * w * phase.sin();
+            norm += w;
+        }
+        2.0 * (re * re + im * im).sqrt() / norm
+    }
+
+    fn db(ratio: f64) -> f64 {
+        20.0 * ratio.log10()
+    }
+
+    #[test]
+    fn output_length_follows_the_rate_ratio() {
+        let input = sine(1000.0, 44100, 1.0);
+        for quality in ResampleQuality::ALL {
+            let out = convert(quality, &input, 44100, 46875);
+            let frames = out.len() / 2;
+            assert!(
+                frames.abs_diff(46875) <= 40,
+                "{:?}: {} frames",
+                quality,
+                frames
+            );
+        }
+    }
+
+    #[test]
+    fn passband_is_flat() {
+        for (quality, freq, tolerance) in [
+            (ResampleQuality::Linear, 1000.0, 0.1),
+            (ResampleQuality::Sinc, 1000.0, 0.01),
+            (ResampleQuality::Sinc, 15000.0, 0.1),
+            (ResampleQuality::Polyphase, 1000.0, 0.01),
+            (ResampleQuality::Polyphase, 18000.0, 0.1),
+        ] {
+            let out = convert(quality, &sine(freq, 44100, 0.5), 44100, 46875);
+            let gain = db(amplitude(&out, freq, 46875) / 0.5);
+            assert!(
+                gain.abs() < tolerance,
+                "{:?} at {} Hz: {:.3} dB",
+                quality,
+                freq,
+                gain
+            );
+        }
+    }
+
+    #[test]
+    fn linear_rolls_off_the_top_octave() {
+        let out = convert(
+            ResampleQuality::Linear,
+            &sine(18000.0, 44100, 0.5),
+            44100,
+            46875,
+        );
+        let gain = db(amplitude(&out, 18000.0, 46875) / 0.5);
+        assert!(gain < -1.0, "{:.3} dB", gain);
+    }
+
+    #[test]
+    fn downsampling_rejects_aliases() {
+        // 30 kHz is above the output Nyquist frequency and would fold to 16875 Hz.
+        let input = sine(30000.0, 96000, 0.5);
+        let alias = 46875.0 - 30000.0;
+
+        let level =
+            |quality| db(amplitude(&convert(quality, &input, 96000, 46875), alias, 46875) / 0.5);
+
+        assert!(level(ResampleQuality::Linear) > -20.0);
+        assert!(
+            level(ResampleQuality::Sinc) < -60.0,
+            "{:.1} dB",
+            level(ResampleQuality::Sinc)
+        );
+        assert!(
+            level(ResampleQuality::Polyphase) < -80.0,
+            "{:.1} dB",
+            level(ResampleQuality::Polyphase)
+        );
+    }
+
+    #[test]
+    fn upsampling_rejects_images() {
+        // The first image of a 20 kHz tone lands at 24.1 kHz, which folds to
+        // 22775 Hz at the device rate.
+        let input = sine(20000.0, 44100, 0.5);
+        let image = 46875.0 - (44100.0 - 20000.0);
+
+        let out = convert(ResampleQuality::Polyphase, &input, 44100, 46875);
+        let level = db(amplitude(&out, image, 46875) / 0.5);
+        assert!(level < -70.0, "{:.1} dB", level);
+    }
+
+    #[test]
+    fn block_size_does_not_change_the_output() {
+        let input = sine(440.0, 44100, 0.2);
+        for quality in ResampleQuality::ALL {
+            let mut whole = Vec::new();
+            let mut resampler = new(quality, 44100, 46875);
+            resampler.process(&input, &mut whole);
+            resampler.flush(&mut whole);
+
+            let pieces = convert(quality, &input, 44100, 46875);
+            // Rounding of the running position may shift the very last
+            // frame of the flushed tail.
+            assert!(whole.len().abs_diff(pieces.len()) <= 2, "{:?}", quality);
+            for (a, b) in whole.iter().zip(&pieces) {
+                assert!((a - b).abs() < 1e-5, "{:?}", quality);
+            }
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+
+// Bounded single-producer/single-consumer queue between the decoder thread and
+// the writer. Writers block while it's full, readers block while it's empty,
+// and either side can close it to wake the other up.
+pub struct RingBuffer<T> {
+    state: Mutex<State<T>>,
+    readable: Condvar,
+    writable: Condvar,
+    capacity: usize,
+}
+
+struct State<T> {
+    data: VecDeque<T>,
+    finished: bool,
+    cancelled: bool,
+    stalled: bool,
+}
+
+impl<T: Copy> RingBuffer<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                data: VecDeque::with_capacity(capacity),
+                finished: false,
+                cancelled: false,
+                stalled: false,
+            }),
+            readable: Condvar::new(),
+            writable: Condvar::new(),
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Blocks until all of `items` is queued. Returns false if the reader has
+    // cancelled, in which case the producer should stop.
+    pub fn push(&self, mut items: &[T]) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while !items.is_empty() {
+            while state.data.len() == self.capacity && !state.cancelled {
+                state = self.writable.wait(state).unwrap();
+            }
+            if state.cancelled {
+                return false;
+            }
+            let room = (self.capacity - state.data.len()).min(items.len());
+            state.data.extend(&items[..room]);
+            items = &items[room..];
+            self.readable.notify_one();
+        }
+        true
+    }
+
+    // Blocks until `out` is full or the producer has finished or stalled.
+    // Returns how many items were read; 0 means the stream is over unless the
+    // producer is stalled.
+    pub fn pop(&self, out: &mut [T]) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let mut filled = 0;
+        while filled < out.len() {
+            while state.data.is_empty() && !state.finished && !state.cancelled && !state.stalled {
+                state = self.readable.wait(state).unwrap();
+            }
+            if state.data.is_empty() {
+                break;
+            }
+            let count = state.data.len().min(out.len() - filled);
+            for (slot, item) in out[filled..filled + count]
+                .iter_mut()
+                .zip(state.data.drain(..count))
+            {
+                *slot = item;
+            }
+            filled += count;
+            self.writable.notify_one();
+        }
+        filled
+    }
+
+    // Called by the producer once it has pushed everything.
+    pub fn finish(&self) {
+        self.state.lock().unwrap().finished = true;
+        self.readable.notify_all();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().unwrap().finished
+    }
+
+    // Called by the producer while it waits on something other than the
+    // reader, so a blocked reader returns with what's there instead of
+    // waiting for a full buffer.
+    pub fn set_stalled(&self, stalled: bool) {
+        self.state.lock().unwrap().stalled = stalled;
+        if stalled {
+            self.readable.notify_all();
+        }
+    }
+
+    // Called by the reader to make the producer give up.
+    pub fn cancel(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.cancelled = true;
+        state.data.clear();
+        self.writable.notify_all();
+        self.readable.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn producer_blocks_and_everything_arrives_in_order() {
+        let ring = Arc::new(RingBuffer::new(64));
+        let producer = {
+            let ring = Arc::clone(&ring);
+            thread::spawn(move || {
+                let items: Vec<u32> = (0..10_000).collect();
+                for piece in items.chunks(100) {
+                    assert!(ring.push(piece));
+                    assert!(ring.len() <= ring.capacity());
+                }
+                ring.finish();
+            })
+        };
+
+        let mut received = Vec::new();
+        let mut buf = [0u32; 37];
+        loop {
+            let n = ring.pop(&mut buf);
+            if n == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..n]);
+        }
+        producer.join().unwrap();
+
+        assert_eq!(received, (0..10_000).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn cancel_releases_a_blocked_producer() {
+        let ring = Arc::new(RingBuffer::new(8));
+        let producer = {
+            let ring = Arc::clone(&ring);
+            thread::spawn(move || ring.push(&[0u8; 64]))
+        };
+
+        let mut buf = [0u8; 4];
+        assert_eq!(ring.pop(&mut buf), 4);
+        ring.cancel();
+
+        assert!(!producer.join().unwrap());
+    }
+
+    #[test]
+    fn stalled_producer_releases_a_partial_read() {
+        let ring = Arc::new(RingBuffer::new(64));
+        assert!(ring.push(&[1u8, 2, 3]));
+        let reader = {
+            let ring = Arc::clone(&ring);
+            thread::spawn(move || {
+                let mut buf = [0u8; 16];
+                let n = ring.pop(&mut buf);
+                buf[..n].to_vec()
+            })
+        };
+
+        ring.set_stalled(true);
+        assert_eq!(reader.join().unwrap(), [1, 2, 3]);
+        assert!(!ring.is_finished());
+    }
+}
@@ -0,0 +1,581 @@
+use crate::flow::SimulatedDevice;
+use crate::format::DeviceFormat;
+use crate::protocol::{self, ControlCommand, DeviceStatus, FrameType};
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub trait AudioSink: Send {
+    fn name(&self) -> String;
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Returns whatever the device has sent back without blocking.
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+
+    fn poll_status(&mut self) -> Option<DeviceStatus> {
+        None
+    }
+
+    // Sinks without a control channel ignore commands.
+    fn control(&mut self, _command: ControlCommand) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AudioSink for Box<dyn AudioSink> {
+    fn name(&self) -> String {
+        (**self).name()
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        (**self).write_all(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read(buf)
+    }
+
+    fn poll_status(&mut self) -> Option<DeviceStatus> {
+        (**self).poll_status()
+    }
+
+    fn control(&mut self, command: ControlCommand) -> io::Result<()> {
+        (**self).control(command)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SinkKind {
+    Serial,
+    Raw,
+    Wav,
+    Stdout,
+    Pty,
+    Memory,
+    Simulated,
+}
+
+impl SinkKind {
+    pub const ALL: [SinkKind; 7] = [
+        SinkKind::Serial,
+        SinkKind::Raw,
+        SinkKind::Wav,
+        SinkKind::Stdout,
+        SinkKind::Pty,
+        SinkKind::Memory,
+        SinkKind::Simulated,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SinkKind::Serial => "Serial port",
+            SinkKind::Raw => "Raw file",
+            SinkKind::Wav => "WAV file",
+            SinkKind::Stdout => "Stdout",
+            SinkKind::Pty => "Pseudo-terminal",
+            SinkKind::Memory => "Memory",
+            SinkKind::Simulated => "Simulated device",
+        }
+    }
+
+    pub fn needs_target(self) -> bool {
+        matches!(self, SinkKind::Serial | SinkKind::Raw | SinkKind::Wav)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Protocol {
+    /// Bare s16le samples, as understood by the current firmware
+    #[default]
+    Raw,
+    /// Audio wrapped in sequenced, CRC-checked frames
+    Framed,
+}
+
+pub fn open_sink(
+    kind: SinkKind,
+    target: &str,
+    format: DeviceFormat,
+    protocol: Protocol,
+) -> Result<Box<dyn AudioSink>, Box<dyn std::error::Error>> {
+    if kind.needs_target() && target.is_empty() {
+        return Err(format!("{} sink needs a target", kind.label()).into());
+    }
+
+    let sink: Box<dyn AudioSink> = match kind {
+        SinkKind::Serial => Box::new(SerialSink::open(target)?),
+        SinkKind::Raw => Box::new(RawFileSink::create(target)?),
+        SinkKind::Wav => Box::new(WavFileSink::create(target, &format)?),
+        SinkKind::Stdout => Box::new(StdoutSink::new()),
+        SinkKind::Pty => Box::new(PtySink::open()?),
+        SinkKind::Memory => Box::new(MemorySink::new()),
+        SinkKind::Simulated => Box::new(SimulatedDevice::new(format, protocol == Protocol::Framed)),
+    };
+
+    Ok(match protocol {
+        Protocol::Raw => sink,
+        Protocol::Framed => Box::new(FramedSink::new(sink)),
+    })
+}
+
+pub struct FramedSink<S: AudioSink = Box<dyn AudioSink>> {
+    inner: S,
+    encoder: protocol::Encoder,
+    decoder: protocol::Decoder,
+    buffer: Vec<u8>,
+}
+
+impl<S: AudioSink> FramedSink<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            encoder: protocol::Encoder::new(),
+            decoder: protocol::Decoder::new(),
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn send(&mut self, frame_type: FrameType, payload: &[u8]) -> io::Result<()> {
+        self.buffer.clear();
+        self.encoder
+            .encode_into(&mut self.buffer, frame_type, payload);
+        self.inner.write_all(&self.buffer)
+    }
+}
+
+impl<S: AudioSink> AudioSink for FramedSink<S> {
+    fn name(&self) -> String {
+        format!("{} (framed)", self.inner.name())
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.send(FrameType::Audio, data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn poll_status(&mut self) -> Option<DeviceStatus> {
+        let mut buf = [0u8; 256];
+        loop {
+            match self.inner.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => self.decoder.push(&buf[..n]),
+                Err(e) => {
+                    eprintln!("Failed to read from {}: {}", self.inner.name(), e);
+                    break;
+                }
+            }
+        }
+
+        let mut status = None;
+        while let Some(frame) = self.decoder.next_frame() {
+            if let Ok(frame) = frame
+                && frame.frame_type == FrameType::Telemetry
+            {
+                status = DeviceStatus::from_bytes(&frame.payload).or(status);
+            }
+        }
+        status
+    }
+
+    fn control(&mut self, command: ControlCommand) -> io::Result<()> {
+        self.send(FrameType::Control, &[command as u8])
+    }
+}
+
+pub struct SerialSink {
+    name: String,
+    port: Box<dyn SerialPort>,
+}
+
+impl SerialSink {
+    pub fn open(port_name: &str) -> serialport::Result<Self> {
+        let port = serialport::new(port_name, 115200)
+            .timeout(Duration::from_millis(1000))
+            .open()?;
+        Ok(Self {
+            name: port_name.to_string(),
+            port,
+        })
+    }
+}
+
+impl AudioSink for SerialSink {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.port.write_all(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.port.bytes_to_read()? as usize;
+        if available == 0 {
+            return Ok(0);
+        }
+        let len = available.min(buf.len());
+        io::Read::read(&mut self.port, &mut buf[..len])
+    }
+}
+
+pub struct RawFileSink {
+    path: String,
+    file: BufWriter<File>,
+}
+
+impl RawFileSink {
+    pub fn create(path: &str) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_string(),
+            file: BufWriter::new(File::create(path)?),
+        })
+    }
+}
+
+impl AudioSink for RawFileSink {
+    fn name(&self) -> String {
+        self.path.clone()
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+const WAV_HEADER_LEN: u32 = 44;
+
+pub struct WavFileSink {
+    path: String,
+    file: BufWriter<File>,
+    data_len: u32,
+}
+
+impl WavFileSink {
+    pub fn create(path: &str, format: &DeviceFormat) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let DeviceFormat {
+            sample_rate,
+            channels,
+            ..
+        } = *format;
+        let block_align = format.bytes_per_frame() as u16;
+        // 24-bit samples sit at the top of their 32-bit words, so the file
+        // reads as plain 32-bit PCM.
+        let bits = format.encoding.bytes() as u16 * 8;
+
+        file.write_all(b"RIFF")?;
+        file.write_all(&(WAV_HEADER_LEN - 8).to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&bits.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        Ok(Self {
+            path: path.to_string(),
+            file,
+            data_len: 0,
+        })
+    }
+}
+
+impl AudioSink for WavFileSink {
+    fn name(&self) -> String {
+        self.path.clone()
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)?;
+        self.data_len = self.data_len.saturating_add(data.len() as u32);
+        Ok(())
+    }
+
+    // Patches the RIFF and data chunk sizes so the file is valid after every flush,
+    // then moves back to the end so further writes keep appending.
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let file = self.file.get_mut();
+        file.seek(SeekFrom::Start(4))?;
+        file.write_all(&(WAV_HEADER_LEN - 8 + self.data_len).to_le_bytes())?;
+        file.seek(SeekFrom::Start(WAV_HEADER_LEN as u64 - 4))?;
+        file.write_all(&self.data_len.to_le_bytes())?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
+
+impl Drop for WavFileSink {
+    fn drop(&mut self) {
+        if let Err(e) = AudioSink::flush(self) {
+            eprintln!("Failed to finalize {}: {}", self.path, e);
+        }
+    }
+}
+
+pub struct StdoutSink {
+    out: io::Stdout,
+}
+
+impl StdoutSink {
+    pub fn new() -> Self {
+        Self { out: io::stdout() }
+    }
+}
+
+impl Default for StdoutSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AudioSink for StdoutSink {
+    fn name(&self) -> String {
+        "stdout".to_string()
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.lock().write_all(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.lock().flush()
+    }
+}
+
+// Writes into the master side of a fresh pty pair. The slave stays open for the
+// lifetime of the sink so the pty isn't torn down before a reader attaches.
+#[cfg(unix)]
+pub struct PtySink {
+    master: serialport::TTYPort,
+    slave: serialport::TTYPort,
+}
+
+#[cfg(unix)]
+impl PtySink {
+    pub fn open() -> serialport::Result<Self> {
+        let (master, slave) = serialport::TTYPort::pair()?;
+        if let Some(name) = slave.name() {
+            println!("Pseudo-terminal available at {}", name);
+        }
+        Ok(Self { master, slave })
+    }
+}
+
+#[cfg(unix)]
+impl AudioSink for PtySink {
+    fn name(&self) -> String {
+        self.slave.name().unwrap_or_else(|| "pty".to_string())
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.master.write_all(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.master.flush()
+    }
+}
+
+#[cfg(not(unix))]
+pub struct PtySink;
+
+#[cfg(not(unix))]
+impl PtySink {
+    pub fn open() -> Result<Self, Box<dyn std::error::Error>> {
+        Err("pseudo-terminals are only supported on unix".into())
+    }
+}
+
+#[cfg(not(unix))]
+impl AudioSink for PtySink {
+    fn name(&self) -> String {
+        "pty".to_string()
+    }
+
+    fn write_all(&mut self, _data: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "pty sink unavailable",
+        ))
+    }
+}
+
+// What the memory sink keeps before it only counts; enough for about six
+// minutes of the stock format.
+pub const MEMORY_LIMIT: usize = 64 << 20;
+
+// Keeps what is written in memory, for tests to read back. Clones share the
+// same buffer, so a test can hold one while the player owns another.
+#[derive(Clone)]
+pub struct MemorySink {
+    captured: Arc<Mutex<Captured>>,
+    limit: usize,
+}
+
+#[derive(Default)]
+struct Captured {
+    data: Vec<u8>,
+    written: u64,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::with_limit(MEMORY_LIMIT)
+    }
+
+    // Keeps the first `limit` bytes; anything after that is dropped.
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            captured: Arc::default(),
+            limit,
+        }
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.captured.lock().unwrap().data.clone()
+    }
+
+    // Hands over what was kept so far and starts again from empty.
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut self.captured.lock().unwrap().data)
+    }
+
+    // Every byte written, including any past the limit.
+    pub fn written(&self) -> u64 {
+        self.captured.lock().unwrap().written
+    }
+}
+
+impl Default for MemorySink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AudioSink for MemorySink {
+    fn name(&self) -> String {
+        format!("memory ({} bytes)", self.written())
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut captured = self.captured.lock().unwrap();
+        let room = self.limit.saturating_sub(captured.data.len());
+        captured
+            .data
+            .extend_from_slice(&data[..room.min(data.len())]);
+        captured.written += data.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::SampleEncoding;
+    use crate::test_util;
+
+    #[test]
+    fn wav_header_sizes_are_patched_on_every_flush() {
+        let path = test_util::temp_path("sink-header", "wav");
+        let format = DeviceFormat {
+            sample_rate: 48000,
+            channels: 1,
+            encoding: SampleEncoding::S24leIn32,
+        };
+        let mut sink = WavFileSink::create(path.to_str().unwrap(), &format).unwrap();
+        let u32_at =
+            |bytes: &[u8], at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+
+        sink.write_all(&[1; 12]).unwrap();
+        sink.flush().unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 44 + 12);
+        assert_eq!(u32_at(&bytes, 4), 36 + 12);
+        assert_eq!(u32_at(&bytes, 24), 48000);
+        assert_eq!(u32_at(&bytes, 28), 48000 * 4);
+        assert_eq!(&bytes[32..36], [4, 0, 32, 0]);
+        assert_eq!(u32_at(&bytes, 40), 12);
+
+        // Writes after a flush still land at the end.
+        sink.write_all(&[2; 8]).unwrap();
+        drop(sink);
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(u32_at(&bytes, 4), 36 + 20);
+        assert_eq!(u32_at(&bytes, 40), 20);
+        assert_eq!(&bytes[44 + 12..], [2; 8]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn framed_sink_wraps_audio_and_control_in_sequenced_frames() {
+        let memory = MemorySink::new();
+        let mut sink = FramedSink::new(memory.clone());
+        sink.write_all(&[1, 2, 3, 4]).unwrap();
+        sink.control(ControlCommand::Pause).unwrap();
+        sink.write_all(&[5, 6]).unwrap();
+
+        let mut decoder = protocol::Decoder::new();
+        decoder.push(&memory.contents());
+        let frames: Vec<_> = std::iter::from_fn(|| decoder.next_frame())
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].frame_type, FrameType::Audio);
+        assert_eq!(frames[0].payload, [1, 2, 3, 4]);
+        assert_eq!(frames[1].frame_type, FrameType::Control);
+        assert_eq!(frames[1].payload, [ControlCommand::Pause as u8]);
+        assert_eq!(frames[2].payload, [5, 6]);
+        assert_eq!(frames[1].seq, frames[0].seq.wrapping_add(1));
+        assert_eq!(frames[2].seq, frames[1].seq.wrapping_add(1));
+        assert_eq!(decoder.lost_frames, 0);
+    }
+
+    #[test]
+    fn memory_sink_stops_keeping_at_its_limit() {
+        let mut sink = MemorySink::with_limit(5);
+        sink.write_all(&[1, 2, 3]).unwrap();
+        sink.write_all(&[4, 5, 6, 7]).unwrap();
+        assert_eq!(sink.contents(), [1, 2, 3, 4, 5]);
+        assert_eq!(sink.written(), 7);
+        assert_eq!(sink.name(), "memory (7 bytes)");
+
+        assert_eq!(sink.take(), [1, 2, 3, 4, 5]);
+        sink.write_all(&[8]).unwrap();
+        assert_eq!(sink.contents(), [8]);
+    }
+}
@@ -0,0 +1,32 @@
+use crate::format::DeviceFormat;
+use crate::sink::{AudioSink, WavFileSink};
+use std::path::PathBuf;
+
+// A file in the temp directory named after the test and this process, so
+// parallel test runs don't share it.
+pub fn temp_path(name: &str, extension: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "feed-{}-{}.{}",
+        name,
+        std::process::id(),
+        extension
+    ))
+}
+
+// Writes `data`, already in `format`'s encoding, as a WAV file and returns
+// its path.
+pub fn write_wav(name: &str, format: &DeviceFormat, data: &[u8]) -> String {
+    let path = temp_path(name, "wav").to_string_lossy().to_string();
+    let mut sink = WavFileSink::create(&path, format).unwrap();
+    sink.write_all(data).unwrap();
+    sink.flush().unwrap();
+    path
+}
+
+// 16-bit samples for the default encoding, from fractions of full scale.
+pub fn s16(samples: &[f32]) -> Vec<u8> {
+    samples
+        .iter()
+        .flat_map(|&s| ((s * 32767.0) as i16).to_le_bytes())
+        .collect()
+}